@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, Crc};
+
+// Размер блока при чтении файлов и отправке частей ответа
+const CHUNK_SIZE: usize = 64 * 1024;
+// Без ZIP64: размеры, смещения и число записей ограничены полями заголовков
+const ZIP_MAX_SIZE: u64 = u32::MAX as u64;
+const ZIP_MAX_ENTRIES: usize = u16::MAX as usize;
+const TAR_BLOCK: usize = 512;
+
+// Формат архива: ?format=zip|tgz
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn from_param(value: &str) -> Option<ArchiveFormat> {
+        match value {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+// Элемент архива: имя внутри архива и файл с содержимым; без файла — каталог
+pub struct ArchiveEntry {
+    pub name: String,
+    pub source: Option<PathBuf>,
+    pub modified: u64,
+}
+
+// Тело ответа с Transfer-Encoding: chunked — каждая запись уходит отдельной частью
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    // Завершающая часть нулевой длины
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Пустая часть означала бы конец тела
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Пишет архив в out по мере чтения файлов, не собирая его в памяти или на диске
+pub fn write_archive<W: Write>(format: ArchiveFormat, entries: &[ArchiveEntry], out: W) -> io::Result<W> {
+    match format {
+        ArchiveFormat::Zip => write_zip(entries, out),
+        ArchiveFormat::TarGz => {
+            let mut gz = GzEncoder::new(out, Compression::default());
+            for entry in entries {
+                write_tar_entry(&mut gz, entry)?;
+            }
+            // Конец архива — два пустых блока
+            gz.write_all(&[0u8; TAR_BLOCK * 2])?;
+            gz.finish()
+        }
+    }
+}
+
+// Считает записанные байты: в ZIP нужны смещения заголовков и сжатые размеры
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Что нужно центральному каталогу о каждой записи
+struct ZipRecord {
+    name: Vec<u8>,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+    directory: bool,
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "archive is too large for ZIP without ZIP64")
+}
+
+fn to_u32(value: u64) -> io::Result<u32> {
+    if value > ZIP_MAX_SIZE {
+        return Err(too_large());
+    }
+    Ok(value as u32)
+}
+
+// ZIP для потока: CRC и размеры заранее неизвестны, поэтому они идут
+// в дескрипторе данных после содержимого (бит 3 флагов)
+fn write_zip<W: Write>(entries: &[ArchiveEntry], out: W) -> io::Result<W> {
+    if entries.len() > ZIP_MAX_ENTRIES {
+        return Err(too_large());
+    }
+    // Бит 3 — дескриптор данных, бит 11 — имена в UTF-8
+    const FLAGS: u16 = 0x0808;
+    let mut out = CountingWriter { inner: out, written: 0 };
+    let mut records = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let directory = entry.source.is_none();
+        let mut name = entry.name.clone().into_bytes();
+        if directory {
+            name.push(b'/');
+        }
+        let (time, date) = dos_datetime(entry.modified);
+        let method: u16 = if directory { 0 } else { 8 };
+        let offset = to_u32(out.written)?;
+
+        out.write_all(&0x04034b50u32.to_le_bytes())?;
+        out.write_all(&20u16.to_le_bytes())?;
+        out.write_all(&FLAGS.to_le_bytes())?;
+        out.write_all(&method.to_le_bytes())?;
+        out.write_all(&time.to_le_bytes())?;
+        out.write_all(&date.to_le_bytes())?;
+        // CRC и размеры — в дескрипторе
+        out.write_all(&[0u8; 12])?;
+        out.write_all(&(name.len() as u16).to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(&name)?;
+
+        let mut crc = Crc::new();
+        let start = out.written;
+        if let Some(source) = &entry.source {
+            let mut file = File::open(source)?;
+            let mut encoder = DeflateEncoder::new(&mut out, Compression::default());
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                crc.update(&buf[..n]);
+                encoder.write_all(&buf[..n])?;
+            }
+            encoder.finish()?;
+        }
+        let compressed = to_u32(out.written - start)?;
+        let size = to_u32(crc.amount() as u64)?;
+
+        out.write_all(&0x08074b50u32.to_le_bytes())?;
+        out.write_all(&crc.sum().to_le_bytes())?;
+        out.write_all(&compressed.to_le_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+
+        records.push(ZipRecord { name, method, time, date, crc: crc.sum(), compressed, size, offset, directory });
+    }
+
+    let directory_offset = to_u32(out.written)?;
+    for record in &records {
+        // Права unix: 0755 для каталогов, 0644 для файлов
+        let (mode, dos_attr) = if record.directory { (0o040755u32, 0x10u32) } else { (0o100644u32, 0) };
+        out.write_all(&0x02014b50u32.to_le_bytes())?;
+        // Создан в unix (3), версия 2.0
+        out.write_all(&((3u16 << 8) | 20).to_le_bytes())?;
+        out.write_all(&20u16.to_le_bytes())?;
+        out.write_all(&FLAGS.to_le_bytes())?;
+        out.write_all(&record.method.to_le_bytes())?;
+        out.write_all(&record.time.to_le_bytes())?;
+        out.write_all(&record.date.to_le_bytes())?;
+        out.write_all(&record.crc.to_le_bytes())?;
+        out.write_all(&record.compressed.to_le_bytes())?;
+        out.write_all(&record.size.to_le_bytes())?;
+        out.write_all(&(record.name.len() as u16).to_le_bytes())?;
+        // Длины extra и комментария, номер диска, внутренние атрибуты
+        out.write_all(&[0u8; 8])?;
+        out.write_all(&((mode << 16) | dos_attr).to_le_bytes())?;
+        out.write_all(&record.offset.to_le_bytes())?;
+        out.write_all(&record.name)?;
+    }
+    let directory_size = to_u32(out.written - directory_offset as u64)?;
+
+    out.write_all(&0x06054b50u32.to_le_bytes())?;
+    out.write_all(&[0u8; 4])?;
+    out.write_all(&(records.len() as u16).to_le_bytes())?;
+    out.write_all(&(records.len() as u16).to_le_bytes())?;
+    out.write_all(&directory_size.to_le_bytes())?;
+    out.write_all(&directory_offset.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    Ok(out.inner)
+}
+
+// Время и дата в формате MS-DOS (локальное время, точность 2 секунды)
+fn dos_datetime(secs: u64) -> (u16, u16) {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() || tm.tm_year < 80 {
+        // Раньше 1980 года DOS-время не бывает
+        return (0, (1 << 5) | 1);
+    }
+    let time = (tm.tm_hour << 11) | (tm.tm_min << 5) | (tm.tm_sec / 2);
+    let date = ((tm.tm_year - 80) << 9) | ((tm.tm_mon + 1) << 5) | tm.tm_mday;
+    (time as u16, date as u16)
+}
+
+// Восьмеричное число в поле tar-заголовка, с завершающим нулем
+fn tar_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn tar_header(name: &[u8], size: u64, modified: u64, kind: u8) -> [u8; TAR_BLOCK] {
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    tar_octal(&mut header[100..108], if kind == b'5' { 0o755 } else { 0o644 });
+    tar_octal(&mut header[108..116], 0);
+    tar_octal(&mut header[116..124], 0);
+    tar_octal(&mut header[124..136], size);
+    tar_octal(&mut header[136..148], modified);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // Контрольная сумма считается при поле суммы, заполненном пробелами
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u64 = header.iter().map(|&b| b as u64).sum();
+    tar_octal(&mut header[148..155], sum);
+    header[155] = b' ';
+    header
+}
+
+fn tar_padding<W: Write>(out: &mut W, size: u64) -> io::Result<()> {
+    let rest = (size % TAR_BLOCK as u64) as usize;
+    if rest != 0 {
+        out.write_all(&[0u8; TAR_BLOCK][..TAR_BLOCK - rest])?;
+    }
+    Ok(())
+}
+
+fn write_tar_entry<W: Write>(out: &mut W, entry: &ArchiveEntry) -> io::Result<()> {
+    let mut name = entry.name.clone().into_bytes();
+    let kind = if entry.source.is_some() { b'0' } else { b'5' };
+    if kind == b'5' {
+        name.push(b'/');
+    }
+    // Длинное имя передается отдельной записью GNU ././@LongLink
+    if name.len() > 99 {
+        let size = name.len() as u64 + 1;
+        out.write_all(&tar_header(b"././@LongLink", size, 0, b'L'))?;
+        out.write_all(&name)?;
+        out.write_all(&[0])?;
+        tar_padding(out, size)?;
+        name.truncate(99);
+    }
+
+    let Some(source) = &entry.source else {
+        return out.write_all(&tar_header(&name, 0, entry.modified, kind));
+    };
+    let file = File::open(source)?;
+    // Размер в заголовке идет до содержимого, поэтому отдаем ровно столько байт, сколько было при открытии
+    let size = file.metadata()?.len();
+    out.write_all(&tar_header(&name, size, entry.modified, kind))?;
+    let copied = io::copy(&mut file.take(size), out)?;
+    if copied != size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while archiving"));
+    }
+    tar_padding(out, size)
+}
@@ -0,0 +1,246 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::context::Context;
+use crate::db::{get_totp, use_api_token};
+use crate::handlers::HttpError;
+use crate::session::current_user;
+use crate::store::{StoreError, User};
+use crate::utils::{get_header, get_timestamp, hash_password};
+
+// Области доступа персональных токенов
+pub const SCOPE_FILES_READ: &str = "files:read";
+pub const SCOPE_FILES_WRITE: &str = "files:write";
+pub const ALL_SCOPES: &[&str] = &[SCOPE_FILES_READ, SCOPE_FILES_WRITE];
+
+// Префикс помогает узнать токен в логах и конфигурации скриптов
+pub const TOKEN_PREFIX: &str = "ows_";
+
+pub enum AuthOutcome {
+    Authorized(String),
+    // Учетные данные отсутствуют или неверны
+    Unauthorized,
+    // Пользователь известен, но у токена нет нужной области доступа
+    Forbidden,
+    // Слишком много неудачных попыток Basic-входа
+    Throttled(u64),
+}
+
+// Проверка пароля, введенного пользователем. Раньше формы не превращали '+' в пробел,
+// и пароль "a b" хешировался как "a+b". Для учетных записей, созданных до исправления
+// (legacy_form_password), пароль с пробелами проверяется и в этой старой форме.
+// После первого успешного входа флаг снимается, а старый хеш заменяется новым
+pub fn check_password(ctx: &Context, username: &str, password: &str) -> Result<Option<User>, StoreError> {
+    let mut user = match ctx.users.authenticate(username, &hash_password(password))? {
+        Some(user) => user,
+        None if password.contains(' ') => {
+            let legacy = hash_password(&password.replace(' ', "+"));
+            match ctx.users.authenticate(username, &legacy)?.filter(|user| user.legacy_form_password) {
+                Some(user) => user,
+                None => return Ok(None),
+            }
+        }
+        None => return Ok(None),
+    };
+    if user.legacy_form_password {
+        user.set_password(password);
+        ctx.users.update(&user)?;
+    }
+    Ok(Some(user))
+}
+
+// Аутентификация запроса: заголовок Authorization (Bearer или Basic), иначе cookie сессии.
+// Вход через браузер дает все области доступа, токен — только выданные при создании
+pub fn authenticate(ctx: &Context, request: &str, client_ip: &str, scope: &str) -> Result<AuthOutcome, HttpError> {
+    let conn = ctx.pool.get()?;
+    let limiter = &ctx.limiter;
+    let authorization = match get_header(request, "Authorization") {
+        Some(value) => value,
+        None => {
+            return Ok(match current_user(&conn, request)? {
+                Some(username) => AuthOutcome::Authorized(username),
+                None => AuthOutcome::Unauthorized,
+            });
+        }
+    };
+
+    let (kind, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+    if kind.eq_ignore_ascii_case("Bearer") {
+        let token = credentials.trim();
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Ok(AuthOutcome::Unauthorized);
+        }
+        let (username, scopes) = match use_api_token(&conn, &hash_password(token), get_timestamp())? {
+            Some(found) => found,
+            None => return Ok(AuthOutcome::Unauthorized),
+        };
+        // Токены отключенного пользователя перестают действовать вместе с ним
+        if ctx.users.find(&username)?.is_none_or(|user| user.disabled) {
+            return Ok(AuthOutcome::Unauthorized);
+        }
+        return Ok(if scopes.split(',').any(|s| s == scope) {
+            AuthOutcome::Authorized(username)
+        } else {
+            AuthOutcome::Forbidden
+        });
+    }
+
+    if kind.eq_ignore_ascii_case("Basic") {
+        let decoded = match STANDARD.decode(credentials.trim()) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            Err(_) => return Ok(AuthOutcome::Unauthorized),
+        };
+        let (username, password) = match decoded.split_once(':') {
+            Some(pair) => pair,
+            None => return Ok(AuthOutcome::Unauthorized),
+        };
+        // Basic-вход подчиняется тем же ограничениям, что и форма входа
+        if let Err(retry_after) = limiter.check(client_ip, username) {
+            return Ok(AuthOutcome::Throttled(retry_after));
+        }
+        let user = match check_password(ctx, username, password)? {
+            Some(user) => user,
+            None => {
+                limiter.record_failure(client_ip, username);
+                return Ok(AuthOutcome::Unauthorized);
+            }
+        };
+        if user.disabled {
+            return Ok(AuthOutcome::Unauthorized);
+        }
+        // Пароль без второго фактора не должен обходить 2FA — таким пользователям нужны токены
+        if matches!(get_totp(&conn, username)?, Some((_, true, _))) {
+            return Ok(AuthOutcome::Unauthorized);
+        }
+        limiter.record_success(username);
+        return Ok(AuthOutcome::Authorized(username.to_string()));
+    }
+
+    Ok(AuthOutcome::Unauthorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::TestContext;
+    use crate::db::{create_api_token, enable_totp, list_api_tokens, revoke_api_token, set_totp_secret};
+
+    const IP: &str = "192.0.2.1";
+    const OTHER_IP: &str = "192.0.2.2";
+
+    fn request(authorization: &str) -> String {
+        format!("GET /files HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n\r\n", authorization)
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        request(&format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password))))
+    }
+
+    // Пользователь bg с паролем "secret 12" и токеном с правом только на чтение
+    fn setup(name: &str) -> TestContext {
+        let test = TestContext::new(name);
+        test.ctx.users.create("bg", &hash_password("secret 12")).unwrap();
+        let conn = test.ctx.pool.get().unwrap();
+        create_api_token(&conn, "bg", "script", &hash_password("ows_read"), SCOPE_FILES_READ, get_timestamp(), None).unwrap();
+        drop(conn);
+        test
+    }
+
+    fn outcome(ctx: &Context, request: &str, scope: &str) -> AuthOutcome {
+        authenticate(ctx, request, IP, scope).ok().unwrap()
+    }
+
+    #[test]
+    fn bearer_token_needs_scope() {
+        let test = setup("auth-scope");
+        let ctx = &test.ctx;
+        assert!(matches!(outcome(ctx, &request("Bearer ows_read"), SCOPE_FILES_READ), AuthOutcome::Authorized(user) if user == "bg"));
+        assert!(matches!(outcome(ctx, &request("Bearer ows_read"), SCOPE_FILES_WRITE), AuthOutcome::Forbidden));
+        assert!(matches!(outcome(ctx, &request("Bearer ows_other"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+        assert!(matches!(outcome(ctx, &request("Bearer read"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+    }
+
+    #[test]
+    fn revoked_and_expired_tokens_are_rejected() {
+        let test = setup("auth-revoked");
+        let ctx = &test.ctx;
+        let conn = ctx.pool.get().unwrap();
+        let id = list_api_tokens(&conn, "bg").unwrap()[0].id;
+        assert!(revoke_api_token(&conn, "bg", id).unwrap());
+        create_api_token(&conn, "bg", "old", &hash_password("ows_old"), SCOPE_FILES_READ, 0, Some(1)).unwrap();
+        drop(conn);
+        assert!(matches!(outcome(ctx, &request("Bearer ows_read"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+        assert!(matches!(outcome(ctx, &request("Bearer ows_old"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+    }
+
+    #[test]
+    fn disabled_user_is_rejected() {
+        let test = setup("auth-disabled");
+        let ctx = &test.ctx;
+        let mut user = ctx.users.find("bg").unwrap().unwrap();
+        user.disabled = true;
+        ctx.users.update(&user).unwrap();
+        assert!(matches!(outcome(ctx, &request("Bearer ows_read"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+        assert!(matches!(outcome(ctx, &basic("bg", "secret 12"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+    }
+
+    #[test]
+    fn basic_auth_checks_password() {
+        let test = setup("auth-basic");
+        let ctx = &test.ctx;
+        assert!(matches!(outcome(ctx, &basic("bg", "secret 12"), SCOPE_FILES_WRITE), AuthOutcome::Authorized(user) if user == "bg"));
+        assert!(matches!(outcome(ctx, &basic("bg", "secret+12"), SCOPE_FILES_WRITE), AuthOutcome::Unauthorized));
+        // Вторая ошибка с того же адреса упирается в задержку ограничителя
+        let nobody = authenticate(ctx, &basic("nobody", "secret 12"), OTHER_IP, SCOPE_FILES_WRITE).ok().unwrap();
+        assert!(matches!(nobody, AuthOutcome::Unauthorized));
+        assert!(matches!(outcome(ctx, &request("Basic not-base64!"), SCOPE_FILES_WRITE), AuthOutcome::Unauthorized));
+    }
+
+    #[test]
+    fn basic_auth_is_refused_with_2fa() {
+        let test = setup("auth-2fa");
+        let ctx = &test.ctx;
+        // С включенной 2FA пароля недостаточно, а токен продолжает работать
+        let conn = ctx.pool.get().unwrap();
+        set_totp_secret(&conn, "bg", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        enable_totp(&conn, "bg", 0).unwrap();
+        drop(conn);
+        assert!(matches!(outcome(ctx, &basic("bg", "secret 12"), SCOPE_FILES_READ), AuthOutcome::Unauthorized));
+        assert!(matches!(outcome(ctx, &request("Bearer ows_read"), SCOPE_FILES_READ), AuthOutcome::Authorized(_)));
+    }
+
+    #[test]
+    fn session_cookie_without_header() {
+        let test = setup("auth-cookie");
+        let ctx = &test.ctx;
+        let request = "GET /files HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(matches!(outcome(ctx, request, SCOPE_FILES_WRITE), AuthOutcome::Unauthorized));
+        let cookie = crate::session::start_session(&ctx.pool.get().unwrap(), "bg", false).ok().unwrap();
+        let token = cookie.split(';').next().unwrap();
+        let request = format!("GET /files HTTP/1.1\r\nCookie: {}\r\n\r\n", token);
+        assert!(matches!(outcome(ctx, &request, SCOPE_FILES_WRITE), AuthOutcome::Authorized(user) if user == "bg"));
+    }
+
+    #[test]
+    fn legacy_form_password_is_upgraded_on_login() {
+        let test = TestContext::new("auth-legacy");
+        let ctx = &test.ctx;
+        // Пароль "a b 123" сохранен до исправления разбора форм как "a+b+123"
+        let mut user = ctx.users.create("old", &hash_password("a+b+123")).unwrap();
+        user.legacy_form_password = true;
+        ctx.users.update(&user).unwrap();
+        ctx.users.create("new", &hash_password("a+b+123")).unwrap();
+
+        assert!(check_password(ctx, "old", "a b 123").unwrap().is_some());
+        let user = ctx.users.find("old").unwrap().unwrap();
+        assert!(!user.legacy_form_password);
+        assert_eq!(user.password_hash, hash_password("a b 123"));
+        // После перехеширования старая форма больше не подходит
+        assert!(check_password(ctx, "old", "a+b+123").unwrap().is_none());
+        assert!(check_password(ctx, "old", "a b 123").unwrap().is_some());
+
+        // Без флага '+' и пробел — разные пароли
+        assert!(check_password(ctx, "new", "a b 123").unwrap().is_none());
+        assert!(check_password(ctx, "new", "a+b+123").unwrap().is_some());
+    }
+}
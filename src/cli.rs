@@ -0,0 +1,327 @@
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+use rusqlite::{Connection, DatabaseName};
+
+use crate::db::{delete_user_data, delete_user_sessions, init_db, DB_PATH};
+use crate::files::{delete_user_files, reconcile, StorageConfig};
+use crate::migrations::{current_version, latest_version, run};
+use crate::policy::RegistrationPolicy;
+use crate::search::rebuild_search_index;
+use crate::store::{SqliteUserStore, UserSort, UserStore, ROLES, ROLE_USER};
+use crate::utils::{format_size, format_timestamp, get_formatted_time, hash_password, log_to_file};
+
+pub const USAGE: &str = "Использование:
+    web_server_v2 [serve]                       запустить сервер
+    web_server_v2 user add <имя> [роль]         создать пользователя (пароль спрашивается)
+    web_server_v2 user passwd <имя>             сменить пароль
+    web_server_v2 user list                     список пользователей
+    web_server_v2 user delete <имя>             удалить пользователя
+    web_server_v2 user set-role <имя> <роль>    назначить роль (user, admin)
+    web_server_v2 user disable <имя>            отключить учетную запись
+    web_server_v2 user enable <имя>             снова включить учетную запись
+    web_server_v2 user set-quota <имя> <байт>   индивидуальная квота на файлы (default — по умолчанию)
+    web_server_v2 db migrate [--dry-run]        применить (или показать) миграции схемы
+    web_server_v2 db backup <путь>              резервная копия базы на лету
+    web_server_v2 db check                      проверка целостности базы
+    web_server_v2 files reconcile               сверить таблицу файлов с каталогом загрузок
+    web_server_v2 search reindex                пересобрать поисковый индекс по файлам и заметке";
+
+// Подкоманды работают с той же базой и тем же кодом, что и сервер
+fn open_store() -> Result<SqliteUserStore, Box<dyn Error>> {
+    init_db()?;
+    Ok(SqliteUserStore::open(DB_PATH, 1)?)
+}
+
+// Действия администратора из консоли тоже попадают в журнал
+fn log_action(message: &str) {
+    let log_entry = format!("CLI: {} at {}", message, get_formatted_time());
+    let _ = log_to_file(&log_entry).map_err(|e| eprintln!("Log error: {}", e));
+}
+
+// Читает пароль с терминала без эха; при вводе из канала просто читает строку
+fn read_password(prompt: &str) -> Result<String, Box<dyn Error>> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::isatty(fd) == 1 && libc::tcgetattr(fd, &mut term) == 0 };
+    if is_tty {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+
+    let mut line = String::new();
+    let result = std::io::stdin().lock().read_line(&mut line);
+
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        println!();
+    }
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Запрашивает новый пароль дважды и проверяет его по правилам регистрации
+fn prompt_new_password(username: &str) -> Result<String, Box<dyn Error>> {
+    let password = read_password("Пароль: ")?;
+    let confirm = read_password("Повторите пароль: ")?;
+    if password != confirm {
+        return Err("пароли не совпадают".into());
+    }
+    let errors = RegistrationPolicy::from_env().validate_password(username, &password);
+    if !errors.is_empty() {
+        return Err(errors.join(" ").into());
+    }
+    Ok(password)
+}
+
+fn check_role(role: &str) -> Result<(), Box<dyn Error>> {
+    if ROLES.contains(&role) {
+        Ok(())
+    } else {
+        Err(format!("неизвестная роль '{}', допустимые: {}", role, ROLES.join(", ")).into())
+    }
+}
+
+// user add <имя> [роль]
+pub fn user_add(username: &str, role: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let role = role.unwrap_or(ROLE_USER);
+    check_role(role)?;
+    if username.is_empty() {
+        return Err("имя пользователя не может быть пустым".into());
+    }
+    let store = open_store()?;
+    if store.find(username)?.is_some() {
+        return Err(format!("пользователь {} уже существует", username).into());
+    }
+
+    let password = prompt_new_password(username)?;
+    let mut user = store.create(username, &hash_password(&password))?;
+    if user.role != role {
+        user.role = role.to_string();
+        store.update(&user)?;
+    }
+    log_action(&format!("user {} added with role {}", username, role));
+    println!("Пользователь {} создан (id {}, роль {})", username, user.id, role);
+    Ok(())
+}
+
+// user passwd <имя>
+pub fn user_passwd(username: &str) -> Result<(), Box<dyn Error>> {
+    let store = open_store()?;
+    let mut user = store
+        .find(username)?
+        .ok_or_else(|| format!("пользователь {} не найден", username))?;
+    let password = prompt_new_password(username)?;
+    user.set_password(&password);
+    store.update(&user)?;
+    log_action(&format!("password changed for {}", username));
+    println!("Пароль пользователя {} изменен", username);
+    Ok(())
+}
+
+// user list
+pub fn user_list() -> Result<(), Box<dyn Error>> {
+    const PAGE: usize = 500;
+    let store = open_store()?;
+    println!("{:>5}  {:<32} {:<6} {:<19}  СТАТУС", "ID", "ИМЯ", "РОЛЬ", "ПОСЛЕДНИЙ ВХОД");
+    let mut offset = 0;
+    loop {
+        let users = store.list(offset, PAGE, UserSort::Id, false)?;
+        for user in &users {
+            let last_login = user.last_login_at.map(format_timestamp).unwrap_or_else(|| "-".to_string());
+            let status = if user.disabled { "отключен" } else { "активен" };
+            println!("{:>5}  {:<32} {:<6} {:<19}  {}", user.id, user.username, user.role, last_login, status);
+        }
+        if users.len() < PAGE {
+            break;
+        }
+        offset += PAGE;
+    }
+    Ok(())
+}
+
+// user delete <имя>
+pub fn user_delete(username: &str) -> Result<(), Box<dyn Error>> {
+    let store = open_store()?;
+    if !store.delete(username)? {
+        return Err(format!("пользователь {} не найден", username).into());
+    }
+    let conn = Connection::open(DB_PATH)?;
+    delete_user_data(&conn, username)?;
+    let files = delete_user_files(&conn, &StorageConfig::from_env(), username)?;
+    log_action(&format!("user {} deleted with {} files", username, files));
+    println!("Пользователь {} удален (файлов: {})", username, files);
+    Ok(())
+}
+
+// user set-role <имя> <роль>
+pub fn user_set_role(username: &str, role: &str) -> Result<(), Box<dyn Error>> {
+    check_role(role)?;
+    let store = open_store()?;
+    let mut user = store
+        .find(username)?
+        .ok_or_else(|| format!("пользователь {} не найден", username))?;
+    user.role = role.to_string();
+    store.update(&user)?;
+    log_action(&format!("role of {} set to {}", username, role));
+    println!("Пользователю {} назначена роль {}", username, role);
+    Ok(())
+}
+
+// user disable <имя> / user enable <имя>
+pub fn user_set_disabled(username: &str, disabled: bool) -> Result<(), Box<dyn Error>> {
+    let store = open_store()?;
+    let mut user = store
+        .find(username)?
+        .ok_or_else(|| format!("пользователь {} не найден", username))?;
+    user.disabled = disabled;
+    store.update(&user)?;
+    if disabled {
+        delete_user_sessions(&Connection::open(DB_PATH)?, username)?;
+        log_action(&format!("user {} disabled", username));
+        println!("Пользователь {} отключен", username);
+    } else {
+        log_action(&format!("user {} enabled", username));
+        println!("Пользователь {} включен", username);
+    }
+    Ok(())
+}
+
+// user set-quota <имя> <байт|default>
+pub fn user_set_quota(username: &str, quota: &str) -> Result<(), Box<dyn Error>> {
+    let quota_bytes = match quota {
+        "default" => None,
+        value => Some(value.parse::<u64>().map_err(|_| format!("неверная квота '{}'", value))?),
+    };
+    let store = open_store()?;
+    let mut user = store
+        .find(username)?
+        .ok_or_else(|| format!("пользователь {} не найден", username))?;
+    user.quota_bytes = quota_bytes;
+    store.update(&user)?;
+    let quota_text = quota_bytes.map(format_size).unwrap_or_else(|| "по умолчанию".to_string());
+    log_action(&format!("quota of {} set to {}", username, quota));
+    println!("Квота пользователя {}: {}", username, quota_text);
+    Ok(())
+}
+
+// db migrate [--dry-run]
+pub fn db_migrate(dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let mut conn = Connection::open(DB_PATH)?;
+    let version = current_version(&conn)?;
+    println!("Версия схемы: {} (последняя {})", version, latest_version());
+    if version > latest_version() {
+        return Err(format!("база новее программы (версия {})", version).into());
+    }
+
+    let migrations = run(&mut conn, dry_run)?;
+    if migrations.is_empty() {
+        println!("{}", if dry_run { "Ожидающих миграций нет" } else { "Схема уже актуальна" });
+    }
+    for migration in migrations {
+        if dry_run {
+            println!("  {:>3} {}", migration.version, migration.name);
+        } else {
+            println!("Применена миграция {} ({})", migration.version, migration.name);
+        }
+    }
+    Ok(())
+}
+
+// db backup <путь> — онлайн-копия через SQLite backup API, сервер можно не останавливать
+pub fn db_backup(path: &str) -> Result<(), Box<dyn Error>> {
+    if std::path::Path::new(path).exists() {
+        return Err(format!("файл {} уже существует", path).into());
+    }
+    let conn = Connection::open(DB_PATH)?;
+    conn.backup(DatabaseName::Main, path, None)?;
+    log_action(&format!("database backed up to {}", path));
+    println!("Резервная копия сохранена в {}", path);
+    Ok(())
+}
+
+// db check — целостность файла, внешние ключи и версия схемы
+pub fn db_check() -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open(DB_PATH)?;
+    let mut problems = 0;
+
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let integrity: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    if integrity != ["ok"] {
+        problems += integrity.len();
+        for line in &integrity {
+            println!("integrity_check: {}", line);
+        }
+    }
+
+    let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+    let violations: Vec<String> = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    problems += violations.len();
+    for table in &violations {
+        println!("foreign_key_check: нарушение в таблице {}", table);
+    }
+
+    let version = current_version(&conn)?;
+    if version != latest_version() {
+        problems += 1;
+        println!(
+            "Версия схемы {} не совпадает с ожидаемой {} — выполните db migrate",
+            version,
+            latest_version()
+        );
+    }
+
+    if problems > 0 {
+        return Err(format!("обнаружено проблем: {}", problems).into());
+    }
+    println!("База в порядке (версия схемы {})", version);
+    Ok(())
+}
+
+// files reconcile — находит файлы, загруженные в обход сервера, и записи об удаленных файлах
+pub fn files_reconcile() -> Result<(), Box<dyn Error>> {
+    init_db()?;
+    let conn = Connection::open(DB_PATH)?;
+    let report = reconcile(&conn, &StorageConfig::from_env())?;
+    for path in &report.added {
+        println!("+ {}", path);
+    }
+    for path in &report.updated {
+        println!("~ {}", path);
+    }
+    for path in &report.removed {
+        println!("- {}", path);
+    }
+    let summary = format!(
+        "files reconciled: {} added, {} updated, {} removed, {} orphaned blobs deleted",
+        report.added.len(),
+        report.updated.len(),
+        report.removed.len(),
+        report.orphaned_blobs
+    );
+    log_action(&summary);
+    println!(
+        "Добавлено: {}, обновлено: {}, удалено: {}, лишних блобов удалено: {}",
+        report.added.len(),
+        report.updated.len(),
+        report.removed.len(),
+        report.orphaned_blobs
+    );
+    Ok(())
+}
+
+// search reindex — заново извлекает текст всех файлов, например после смены правил извлечения
+pub fn search_reindex() -> Result<(), Box<dyn Error>> {
+    init_db()?;
+    let conn = Connection::open(DB_PATH)?;
+    let indexed = rebuild_search_index(&conn)?;
+    log_action(&format!("search index rebuilt, {} files with text", indexed));
+    println!("Индекс пересобран, файлов с текстом: {}", indexed);
+    Ok(())
+}
@@ -0,0 +1,446 @@
+// Правила для содержимого загружаемых файлов: какие типы разрешены, какого размера они могут быть
+// и под каким типом файл хранится и отдается. Тип определяется сервером по расширению и сверяется
+// с сигнатурой содержимого — Content-Type, присланному клиентом, не доверяем
+
+use std::fs::File;
+use std::io::Read;
+
+// Сколько первых байт файла читается для распознавания формата
+pub const SNIFF_BYTES: usize = 1024;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Форматы, которые узнаются по магическим байтам в начале файла
+#[derive(Clone, Copy, PartialEq)]
+enum Signature {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Pdf,
+    Zip,
+    Gzip,
+    SevenZip,
+    Rar,
+    // Контейнер ISO BMFF (MP4, MOV, M4A): "ftyp" после длины первого блока
+    Ftyp,
+    Ogg,
+    Wav,
+    Flac,
+    // Контейнер Matroska (MKV, WebM)
+    Ebml,
+    Elf,
+    WindowsExe,
+}
+
+// Порядок распознавания; у форматов без надежной сигнатуры (текст, MP3, BMP) ее нет вовсе
+const SIGNATURES: &[Signature] = &[
+    Signature::Png,
+    Signature::Jpeg,
+    Signature::Gif,
+    Signature::Webp,
+    Signature::Pdf,
+    Signature::Zip,
+    Signature::Gzip,
+    Signature::SevenZip,
+    Signature::Rar,
+    Signature::Ftyp,
+    Signature::Ogg,
+    Signature::Wav,
+    Signature::Flac,
+    Signature::Ebml,
+    Signature::Elf,
+    Signature::WindowsExe,
+];
+
+impl Signature {
+    fn matches(self, head: &[u8]) -> bool {
+        match self {
+            Signature::Png => head.starts_with(b"\x89PNG\r\n\x1a\n"),
+            Signature::Jpeg => head.starts_with(&[0xFF, 0xD8, 0xFF]),
+            Signature::Gif => head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a"),
+            Signature::Webp => head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP",
+            Signature::Pdf => head.starts_with(b"%PDF-"),
+            // Пустой ZIP-архив состоит из одной записи конца каталога
+            Signature::Zip => head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06"),
+            Signature::Gzip => head.starts_with(&[0x1F, 0x8B, 0x08]),
+            Signature::SevenZip => head.starts_with(b"7z\xBC\xAF\x27\x1C"),
+            Signature::Rar => head.starts_with(b"Rar!\x1A\x07"),
+            Signature::Ftyp => head.len() >= 8 && &head[4..8] == b"ftyp",
+            Signature::Ogg => head.starts_with(b"OggS"),
+            Signature::Wav => head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WAVE",
+            Signature::Flac => head.starts_with(b"fLaC"),
+            Signature::Ebml => head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]),
+            Signature::Elf => head.starts_with(b"\x7FELF"),
+            // "MZ" слишком короткая сигнатура, поэтому проверяется и заголовок PE, на который она ссылается
+            Signature::WindowsExe => {
+                head.starts_with(b"MZ")
+                    && head.len() >= 0x40
+                    && {
+                        let offset = u32::from_le_bytes([head[0x3C], head[0x3D], head[0x3E], head[0x3F]]) as usize;
+                        head.get(offset..offset + 4) == Some(b"PE\0\0")
+                    }
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Signature::Png => "PNG",
+            Signature::Jpeg => "JPEG",
+            Signature::Gif => "GIF",
+            Signature::Webp => "WebP",
+            Signature::Pdf => "PDF",
+            Signature::Zip => "ZIP",
+            Signature::Gzip => "gzip",
+            Signature::SevenZip => "7z",
+            Signature::Rar => "RAR",
+            Signature::Ftyp => "MP4/QuickTime",
+            Signature::Ogg => "Ogg",
+            Signature::Wav => "WAV",
+            Signature::Flac => "FLAC",
+            Signature::Ebml => "Matroska/WebM",
+            Signature::Elf => "исполняемый файл ELF",
+            Signature::WindowsExe => "исполняемый файл Windows",
+        }
+    }
+}
+
+// Известный тип файла: расширения, MIME-тип и сигнатура, если у формата она есть
+struct FileType {
+    extensions: &'static [&'static str],
+    mime: &'static str,
+    signature: Option<Signature>,
+}
+
+const FILE_TYPES: &[FileType] = &[
+    FileType { extensions: &["png"], mime: "image/png", signature: Some(Signature::Png) },
+    FileType { extensions: &["jpg", "jpeg", "jpe"], mime: "image/jpeg", signature: Some(Signature::Jpeg) },
+    FileType { extensions: &["gif"], mime: "image/gif", signature: Some(Signature::Gif) },
+    FileType { extensions: &["webp"], mime: "image/webp", signature: Some(Signature::Webp) },
+    FileType { extensions: &["bmp"], mime: "image/bmp", signature: None },
+    FileType { extensions: &["svg"], mime: "image/svg+xml", signature: None },
+    FileType { extensions: &["pdf"], mime: "application/pdf", signature: Some(Signature::Pdf) },
+    FileType { extensions: &["zip"], mime: "application/zip", signature: Some(Signature::Zip) },
+    FileType {
+        extensions: &["docx"],
+        mime: "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        signature: Some(Signature::Zip),
+    },
+    FileType {
+        extensions: &["xlsx"],
+        mime: "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        signature: Some(Signature::Zip),
+    },
+    FileType {
+        extensions: &["pptx"],
+        mime: "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        signature: Some(Signature::Zip),
+    },
+    FileType { extensions: &["odt"], mime: "application/vnd.oasis.opendocument.text", signature: Some(Signature::Zip) },
+    FileType {
+        extensions: &["ods"],
+        mime: "application/vnd.oasis.opendocument.spreadsheet",
+        signature: Some(Signature::Zip),
+    },
+    FileType {
+        extensions: &["odp"],
+        mime: "application/vnd.oasis.opendocument.presentation",
+        signature: Some(Signature::Zip),
+    },
+    FileType { extensions: &["epub"], mime: "application/epub+zip", signature: Some(Signature::Zip) },
+    FileType { extensions: &["jar"], mime: "application/java-archive", signature: Some(Signature::Zip) },
+    FileType { extensions: &["gz", "tgz"], mime: "application/gzip", signature: Some(Signature::Gzip) },
+    FileType { extensions: &["7z"], mime: "application/x-7z-compressed", signature: Some(Signature::SevenZip) },
+    FileType { extensions: &["rar"], mime: "application/vnd.rar", signature: Some(Signature::Rar) },
+    FileType { extensions: &["mp4", "m4v"], mime: "video/mp4", signature: Some(Signature::Ftyp) },
+    FileType { extensions: &["m4a"], mime: "audio/mp4", signature: Some(Signature::Ftyp) },
+    FileType { extensions: &["mov"], mime: "video/quicktime", signature: Some(Signature::Ftyp) },
+    FileType { extensions: &["webm"], mime: "video/webm", signature: Some(Signature::Ebml) },
+    FileType { extensions: &["mkv"], mime: "video/x-matroska", signature: Some(Signature::Ebml) },
+    FileType { extensions: &["ogg", "oga", "opus"], mime: "audio/ogg", signature: Some(Signature::Ogg) },
+    FileType { extensions: &["ogv"], mime: "video/ogg", signature: Some(Signature::Ogg) },
+    FileType { extensions: &["wav"], mime: "audio/wav", signature: Some(Signature::Wav) },
+    FileType { extensions: &["flac"], mime: "audio/flac", signature: Some(Signature::Flac) },
+    FileType { extensions: &["mp3"], mime: "audio/mpeg", signature: None },
+    FileType {
+        extensions: &["exe", "dll"],
+        mime: "application/vnd.microsoft.portable-executable",
+        signature: Some(Signature::WindowsExe),
+    },
+    FileType { extensions: &["txt", "log"], mime: "text/plain", signature: None },
+    FileType { extensions: &["csv"], mime: "text/csv", signature: None },
+    FileType { extensions: &["md"], mime: "text/markdown", signature: None },
+    FileType { extensions: &["json"], mime: "application/json", signature: None },
+    FileType { extensions: &["xml"], mime: "application/xml", signature: None },
+    FileType { extensions: &["html", "htm"], mime: "text/html", signature: None },
+    FileType { extensions: &["xhtml"], mime: "application/xhtml+xml", signature: None },
+    FileType { extensions: &["css"], mime: "text/css", signature: None },
+    FileType { extensions: &["js", "mjs"], mime: "text/javascript", signature: None },
+    // Исходники и конфигурация: показываются и ищутся как текст
+    FileType { extensions: &["rs"], mime: "text/x-rust", signature: None },
+    FileType { extensions: &["py"], mime: "text/x-python", signature: None },
+    FileType { extensions: &["c", "h"], mime: "text/x-c", signature: None },
+    FileType { extensions: &["cpp", "cc", "hpp"], mime: "text/x-c++", signature: None },
+    FileType { extensions: &["go"], mime: "text/x-go", signature: None },
+    FileType { extensions: &["java"], mime: "text/x-java", signature: None },
+    FileType { extensions: &["sh"], mime: "text/x-shellscript", signature: None },
+    FileType { extensions: &["sql"], mime: "text/x-sql", signature: None },
+    FileType { extensions: &["toml"], mime: "text/x-toml", signature: None },
+    FileType { extensions: &["yaml", "yml"], mime: "text/x-yaml", signature: None },
+    FileType { extensions: &["ini", "conf"], mime: "text/x-ini", signature: None },
+];
+
+// Типы, которые браузер исполняет: открытые с нашего адреса, они получили бы доступ к сессии пользователя
+const RISKY_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/javascript",
+    "application/javascript",
+    "application/ecmascript",
+    "text/xml",
+    "application/xml",
+];
+
+// Расширение имени файла в нижнем регистре; "" — расширения нет
+fn extension(name: &str) -> String {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    match base.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_ascii_lowercase(),
+        _ => String::new(),
+    }
+}
+
+fn file_type(name: &str) -> Option<&'static FileType> {
+    let ext = extension(name);
+    FILE_TYPES.iter().find(|file_type| file_type.extensions.contains(&ext.as_str()))
+}
+
+// Тип, под которым файл хранится и отдается, — по расширению
+pub fn content_type_for(name: &str) -> &'static str {
+    file_type(name).map(|file_type| file_type.mime).unwrap_or(DEFAULT_CONTENT_TYPE)
+}
+
+// Отдавать ли файл только как вложение: такие типы браузер может выполнить
+pub fn is_risky_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    RISKY_TYPES.contains(&mime.as_str()) || mime.ends_with("+xml")
+}
+
+// Текстовое содержимое: его можно показать как текст и проиндексировать для поиска
+pub fn is_text_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/") || mime == "application/json" || mime == "application/xml" || mime.ends_with("+xml")
+}
+
+// "image/*" подходит к любому image/..., остальные шаблоны сравниваются точно
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(major) => mime.split('/').next() == Some(major),
+        None => pattern == mime,
+    }
+}
+
+// Начало файла для распознавания формата
+pub fn read_head(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(SNIFF_BYTES);
+    File::open(path)?.take(SNIFF_BYTES as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+// Почему файл не принят
+pub enum ContentError {
+    // Тип не разрешен или содержимое не соответствует расширению
+    Rejected(String),
+    // Файл больше, чем разрешено для его типа
+    TooLarge(String),
+}
+
+impl ContentError {
+    pub fn message(&self) -> &str {
+        match self {
+            ContentError::Rejected(message) | ContentError::TooLarge(message) => message,
+        }
+    }
+}
+
+pub struct ContentPolicy {
+    // Разрешенные расширения без точки, в нижнем регистре; пустой список — любые
+    pub allowed_extensions: Vec<String>,
+    // Разрешенные MIME-типы: точные ("application/pdf") или шаблоны ("image/*"); пустой список — любые
+    pub allowed_types: Vec<String>,
+    // Наибольший размер одного файла любого типа
+    pub max_file_bytes: Option<u64>,
+    // Ограничения размера для отдельных типов: (расширение, MIME-тип или шаблон; размер в байтах)
+    pub type_limits: Vec<(String, u64)>,
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl ContentPolicy {
+    // Настройки из переменных окружения: UPLOAD_ALLOWED_EXTENSIONS ("jpg,png,pdf"),
+    // UPLOAD_ALLOWED_TYPES ("image/*,application/pdf"), UPLOAD_MAX_FILE_BYTES
+    // и UPLOAD_TYPE_LIMITS ("image/*=10485760,mp4=524288000"). По умолчанию ограничений нет,
+    // но сверка содержимого с расширением выполняется всегда
+    pub fn from_env() -> Self {
+        ContentPolicy {
+            allowed_extensions: env_list("UPLOAD_ALLOWED_EXTENSIONS"),
+            allowed_types: env_list("UPLOAD_ALLOWED_TYPES"),
+            max_file_bytes: std::env::var("UPLOAD_MAX_FILE_BYTES").ok().and_then(|value| value.parse().ok()),
+            type_limits: env_list("UPLOAD_TYPE_LIMITS")
+                .iter()
+                .filter_map(|item| item.split_once('='))
+                .filter_map(|(key, limit)| Some((key.trim().to_string(), limit.trim().parse().ok()?)))
+                .collect(),
+        }
+    }
+
+    // Разрешено ли хранить файл с таким именем: расширение и его тип должны быть в списках
+    pub fn check_type(&self, name: &str) -> Result<(), ContentError> {
+        let ext = extension(name);
+        if !self.allowed_extensions.is_empty() && !self.allowed_extensions.contains(&ext) {
+            return Err(ContentError::Rejected(if ext.is_empty() {
+                "Файлы без расширения загружать нельзя.".to_string()
+            } else {
+                format!("Файлы .{} загружать нельзя.", ext)
+            }));
+        }
+        let mime = content_type_for(name);
+        if !self.allowed_types.is_empty() && !self.allowed_types.iter().any(|pattern| mime_matches(pattern, mime)) {
+            return Err(ContentError::Rejected(format!("Файлы типа {} загружать нельзя.", mime)));
+        }
+        Ok(())
+    }
+
+    // Наибольший допустимый размер файла с таким именем; None — без ограничения
+    pub fn size_limit(&self, name: &str) -> Option<u64> {
+        let ext = extension(name);
+        let mime = content_type_for(name);
+        self.type_limits
+            .iter()
+            .filter(|(key, _)| if key.contains('/') { mime_matches(key, mime) } else { *key == ext })
+            .map(|(_, limit)| *limit)
+            .chain(self.max_file_bytes)
+            .min()
+    }
+
+    // Проверка до приема содержимого: тип и заявленный размер
+    pub fn check_upload(&self, name: &str, size: u64) -> Result<(), ContentError> {
+        self.check_type(name)?;
+        match self.size_limit(name) {
+            Some(limit) if size > limit => Err(ContentError::TooLarge(format!(
+                "Файл слишком большой: для этого типа разрешено не больше {} байт.",
+                limit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Сверка начала содержимого с расширением: формат с сигнатурой должен ее иметь,
+    // а распознанный формат не может прятаться под расширением другого известного типа
+    pub fn check_content(&self, name: &str, head: &[u8]) -> Result<(), ContentError> {
+        // Пустой файл создают клиенты WebDAV перед записью содержимого
+        if head.is_empty() {
+            return Ok(());
+        }
+        let declared = file_type(name);
+        let sniffed = SIGNATURES.iter().copied().find(|signature| signature.matches(head));
+        match (declared.and_then(|file_type| file_type.signature), sniffed) {
+            (Some(expected), Some(found)) if expected == found => Ok(()),
+            (Some(expected), _) => Err(ContentError::Rejected(format!(
+                "Содержимое файла не похоже на {}, как обещает расширение .{}.",
+                expected.name(),
+                extension(name)
+            ))),
+            (None, Some(found)) if declared.is_some() => Err(ContentError::Rejected(format!(
+                "Файл с расширением .{} на самом деле {}.",
+                extension(name),
+                found.name()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn policy() -> ContentPolicy {
+        ContentPolicy {
+            allowed_extensions: Vec::new(),
+            allowed_types: vec!["image/*".to_string(), "application/pdf".to_string(), "text/plain".to_string()],
+            max_file_bytes: Some(1000),
+            type_limits: vec![("image/*".to_string(), 500), ("gif".to_string(), 100)],
+        }
+    }
+
+    #[test]
+    fn allowed_and_disallowed_types() {
+        let policy = policy();
+        assert!(policy.check_type("photo.PNG").is_ok());
+        assert!(policy.check_type("docs/report.pdf").is_ok());
+        assert!(policy.check_type("notes.txt").is_ok());
+        assert!(matches!(policy.check_type("page.html"), Err(ContentError::Rejected(_))));
+        assert!(matches!(policy.check_type("setup.exe"), Err(ContentError::Rejected(_))));
+        // Без расширения тип — application/octet-stream, его нет в списке
+        assert!(policy.check_type("README").is_err());
+        assert!(policy.check_type(".png").is_err());
+
+        let by_extension = ContentPolicy { allowed_extensions: vec!["jpg".to_string()], ..policy };
+        assert!(by_extension.check_type("a.jpg").is_ok());
+        assert!(by_extension.check_type("a.jpeg").is_err());
+    }
+
+    #[test]
+    fn size_limits_by_type() {
+        let policy = policy();
+        assert_eq!(policy.size_limit("a.png"), Some(500));
+        assert_eq!(policy.size_limit("a.gif"), Some(100));
+        assert_eq!(policy.size_limit("a.pdf"), Some(1000));
+        assert!(policy.check_upload("a.png", 500).is_ok());
+        assert!(matches!(policy.check_upload("a.png", 501), Err(ContentError::TooLarge(_))));
+        assert!(matches!(policy.check_upload("a.html", 1), Err(ContentError::Rejected(_))));
+    }
+
+    #[test]
+    fn content_must_match_extension() {
+        let policy = policy();
+        assert!(policy.check_content("photo.png", PNG).is_ok());
+        assert!(policy.check_content("notes.txt", b"hello").is_ok());
+        assert!(policy.check_content("empty.png", b"").is_ok());
+        // HTML под видом картинки
+        assert!(matches!(
+            policy.check_content("photo.png", b"<html><script>alert(1)</script></html>"),
+            Err(ContentError::Rejected(_))
+        ));
+        // Картинка под видом другого формата с сигнатурой
+        assert!(policy.check_content("photo.jpg", PNG).is_err());
+        // Распознанный формат под расширением известного типа без сигнатуры
+        assert!(policy.check_content("notes.txt", b"\x7FELF\x02\x01\x01").is_err());
+        // Неизвестное расширение не сверяется
+        assert!(policy.check_content("data.bin", b"\x7FELF\x02\x01\x01").is_ok());
+    }
+
+    #[test]
+    fn served_types() {
+        assert_eq!(content_type_for("a.PNG"), "image/png");
+        assert_eq!(content_type_for("archive.tar.gz"), "application/gzip");
+        assert_eq!(content_type_for("noext"), DEFAULT_CONTENT_TYPE);
+        assert!(is_risky_type("text/html; charset=utf-8"));
+        assert!(is_risky_type("image/svg+xml"));
+        assert!(!is_risky_type("image/png"));
+        assert!(is_text_type("text/x-rust"));
+        assert!(!is_text_type("application/pdf"));
+    }
+}
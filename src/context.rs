@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::content_policy::ContentPolicy;
+use crate::files::StorageConfig;
+use crate::limiter::LoginLimiter;
+use crate::notifier::Notifier;
+use crate::policy::RegistrationPolicy;
+use crate::pool::Pool;
+use crate::scanner::UploadScanner;
+use crate::store::UserStore;
+
+// Общее состояние сервера, доступное обработчикам всех соединений
+pub struct Context {
+    // Соединения с users.db; обработчики берут их отсюда, а не открывают базу сами
+    pub pool: Arc<Pool>,
+    // Внешний адрес сервера для ссылок, которые уходят пользователю (BASE_URL).
+    // Заголовок Host для этого не годится: его присылает клиент
+    pub base_url: String,
+    pub users: Box<dyn UserStore>,
+    pub limiter: LoginLimiter,
+    pub notifier: Box<dyn Notifier>,
+    pub registration: RegistrationPolicy,
+    pub storage: StorageConfig,
+    // Какие файлы можно загружать и как их отдавать
+    pub content_policy: ContentPolicy,
+    // Проверка загруженных файлов перед тем, как они станут видны
+    pub scanner: Box<dyn UploadScanner>,
+}
+
+// Контекст для тестов: временные база и хранилище, политики по умолчанию, без сканера
+#[cfg(test)]
+pub struct TestContext {
+    pub ctx: Context,
+    // Удаляются после ctx, когда соединения с базой уже закрыты
+    _db: crate::db::TempDb,
+    _storage: crate::files::TempStorage,
+}
+
+#[cfg(test)]
+impl TestContext {
+    pub fn new(name: &str) -> TestContext {
+        use crate::limiter::{LimiterConfig, SystemClock};
+        use crate::notifier::OutboxNotifier;
+        use crate::scanner::NoopScanner;
+        use crate::store::SqliteUserStore;
+
+        let db = crate::db::TempDb::new(name);
+        let storage = crate::files::TempStorage::new(name);
+        let ctx = Context {
+            pool: Arc::new(Pool::new(&db.0, 2).unwrap()),
+            base_url: "http://localhost".to_string(),
+            users: Box::new(SqliteUserStore::open(&db.0, 2).unwrap()),
+            limiter: LoginLimiter::new(LimiterConfig::default(), SystemClock),
+            notifier: Box::new(OutboxNotifier::new(format!("{}/outbox.txt", storage.0.root))),
+            registration: RegistrationPolicy::default(),
+            storage: storage.0.clone(),
+            content_policy: ContentPolicy {
+                allowed_extensions: Vec::new(),
+                allowed_types: Vec::new(),
+                max_file_bytes: None,
+                type_limits: Vec::new(),
+            },
+            scanner: Box::new(NoopScanner),
+        };
+        TestContext { ctx, _db: db, _storage: storage }
+    }
+}
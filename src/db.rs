@@ -1,36 +1,343 @@
 use rusqlite::{params, Connection, Result};
 
+use crate::migrations::migrate;
+use crate::store::ROLE_ADMIN;
+use crate::utils::{constant_time_eq, get_formatted_time, hash_password, log_to_file, verify_password_salted};
+
+pub const DB_PATH: &str = "users.db";
+
+// Персональный токен доступа (сам токен не хранится, только его хеш)
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+    pub revoked: bool,
+}
+
+// Открывает базу и доводит схему до последней версии
 pub fn init_db() -> Result<()> {
-    let conn = Connection::open("users.db")?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY,
-            username TEXT NOT NULL UNIQUE,
-            password_hash TEXT NOT NULL
-        )",
-        [],
+    let mut conn = Connection::open(DB_PATH)?;
+    for migration in migrate(&mut conn)? {
+        let log_entry = format!(
+            "Applied migration {} ({}) at {}",
+            migration.version, migration.name, get_formatted_time()
+        );
+        println!("{}", log_entry);
+        let _ = log_to_file(&log_entry).map_err(|e| eprintln!("Log error: {}", e));
+    }
+    Ok(())
+}
+
+// Сколько действующих администраторов; роль назначается только командой user set-role
+pub fn count_admins(conn: &Connection) -> Result<usize> {
+    conn.query_row("SELECT COUNT(*) FROM users WHERE role = ?1 AND disabled = 0", params![ROLE_ADMIN], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|count| count as usize)
+}
+
+pub fn create_session(
+    conn: &Connection,
+    token_hash: &str,
+    username: &str,
+    pending_2fa: bool,
+    expires_at: u64,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO sessions (token_hash, username, pending_2fa, expires_at) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![token_hash, username, pending_2fa, expires_at as i64])?;
+    Ok(())
+}
+
+// Возвращает (имя пользователя, ожидает ли сессия второго фактора) для неистекшей сессии
+pub fn find_session(conn: &Connection, token_hash: &str, now: u64) -> Result<Option<(String, bool)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT username, pending_2fa FROM sessions WHERE token_hash = ?1 AND expires_at > ?2",
     )?;
+    let mut rows = stmt.query(params![token_hash, now as i64])?;
+    match rows.next()? {
+        Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+        None => Ok(None),
+    }
+}
+
+pub fn activate_session(conn: &Connection, token_hash: &str, expires_at: u64) -> Result<()> {
+    conn.prepare_cached(
+        "UPDATE sessions SET pending_2fa = 0, expires_at = ?2 WHERE token_hash = ?1",
+    )?
+    .execute(params![token_hash, expires_at as i64])?;
     Ok(())
 }
 
-pub fn user_exists(conn: &Connection, username: &str) -> Result<bool> {
-    let mut stmt = conn.prepare("SELECT COUNT(*) FROM users WHERE username = ?1")?;
-    let count: i64 = stmt.query_row(params![username], |row| row.get(0))?;
-    Ok(count > 0)
+pub fn delete_session(conn: &Connection, token_hash: &str) -> Result<()> {
+    conn.prepare_cached("DELETE FROM sessions WHERE token_hash = ?1")?.execute(params![token_hash])?;
+    Ok(())
 }
 
-pub fn register_user(conn: &Connection, username: &str, password_hash: &str) -> Result<()> {
-    conn.execute(
-        "INSERT INTO users (username, password_hash) VALUES (?1, ?2)",
-        params![username, password_hash],
+// Завершает все сессии пользователя (например, после смены пароля)
+pub fn delete_user_sessions(conn: &Connection, username: &str) -> Result<()> {
+    conn.prepare_cached("DELETE FROM sessions WHERE username = ?1")?.execute(params![username])?;
+    Ok(())
+}
+
+// Завершает сессии пользователя, кроме сессии с хешем keep_token_hash
+pub fn delete_other_sessions(conn: &Connection, username: &str, keep_token_hash: &str) -> Result<()> {
+    conn.prepare_cached("DELETE FROM sessions WHERE username = ?1 AND token_hash != ?2")?
+        .execute(params![username, keep_token_hash])?;
+    Ok(())
+}
+
+// Удаляет все, что связано с пользователем, кроме самой записи в users (ее удаляет UserStore)
+pub fn delete_user_data(conn: &Connection, username: &str) -> Result<()> {
+    delete_user_sessions(conn, username)?;
+    disable_totp(conn, username)?;
+    conn.prepare_cached("DELETE FROM password_resets WHERE username = ?1")?.execute(params![username])?;
+    conn.prepare_cached("DELETE FROM api_tokens WHERE username = ?1")?.execute(params![username])?;
+    Ok(())
+}
+
+pub fn create_reset_token(conn: &Connection, token_hash: &str, username: &str, expires_at: u64) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO password_resets (token_hash, username, expires_at) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![token_hash, username, expires_at as i64])?;
+    Ok(())
+}
+
+// Владелец действующего токена сброса: не использованного и не истекшего
+pub fn find_reset_token(conn: &Connection, token_hash: &str, now: u64) -> Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT username FROM password_resets WHERE token_hash = ?1 AND used = 0 AND expires_at > ?2",
     )?;
+    let mut rows = stmt.query(params![token_hash, now as i64])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+// Гасит токен сброса и возвращает владельца. Повторное использование и истекшие токены дают None
+pub fn consume_reset_token(conn: &Connection, token_hash: &str, now: u64) -> Result<Option<String>> {
+    let username = match find_reset_token(conn, token_hash, now)? {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let updated = conn.prepare_cached(
+        "UPDATE password_resets SET used = 1 WHERE token_hash = ?1 AND used = 0",
+    )?
+    .execute(params![token_hash])?;
+    Ok(if updated > 0 { Some(username) } else { None })
+}
+
+// Возвращает (секрет, включена ли 2FA, последний использованный шаг)
+pub fn get_totp(conn: &Connection, username: &str) -> Result<Option<(String, bool, u64)>> {
+    let mut stmt = conn.prepare_cached("SELECT secret, enabled, last_step FROM totp WHERE username = ?1")?;
+    let mut rows = stmt.query(params![username])?;
+    match rows.next()? {
+        Some(row) => Ok(Some((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64))),
+        None => Ok(None),
+    }
+}
+
+// Сохраняет новый (еще не подтвержденный) секрет
+pub fn set_totp_secret(conn: &Connection, username: &str, secret: &str) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO totp (username, secret, enabled, last_step) VALUES (?1, ?2, 0, 0)",
+    )?
+    .execute(params![username, secret])?;
     Ok(())
 }
 
-pub fn authenticate_user(conn: &Connection, username: &str, password_hash: &str) -> Result<bool> {
-    let mut stmt = conn.prepare(
-        "SELECT COUNT(*) FROM users WHERE username = ?1 AND password_hash = ?2",
+pub fn enable_totp(conn: &Connection, username: &str, step: u64) -> Result<()> {
+    conn.prepare_cached(
+        "UPDATE totp SET enabled = 1, last_step = ?2 WHERE username = ?1",
+    )?
+    .execute(params![username, step as i64])?;
+    Ok(())
+}
+
+pub fn update_totp_step(conn: &Connection, username: &str, step: u64) -> Result<()> {
+    conn.prepare_cached(
+        "UPDATE totp SET last_step = ?2 WHERE username = ?1",
+    )?
+    .execute(params![username, step as i64])?;
+    Ok(())
+}
+
+// Полное отключение 2FA вместе с кодами восстановления
+pub fn disable_totp(conn: &Connection, username: &str) -> Result<()> {
+    conn.prepare_cached("DELETE FROM totp WHERE username = ?1")?.execute(params![username])?;
+    conn.prepare_cached("DELETE FROM recovery_codes WHERE username = ?1")?.execute(params![username])?;
+    Ok(())
+}
+
+// Заменяет коды восстановления пользователя новым набором (хранятся только хеши)
+pub fn store_recovery_codes(conn: &Connection, username: &str, code_hashes: &[String]) -> Result<()> {
+    conn.prepare_cached("DELETE FROM recovery_codes WHERE username = ?1")?.execute(params![username])?;
+    for hash in code_hashes {
+        conn.prepare_cached(
+            "INSERT INTO recovery_codes (username, code_hash) VALUES (?1, ?2)",
+        )?
+        .execute(params![username, hash])?;
+    }
+    Ok(())
+}
+
+// Погашает неиспользованный код восстановления, подходящий к code; false, если такого нет.
+// Коды хешируются с солью, поэтому сравниваются по одному. Коды, выданные до этого,
+// хранятся как одиночный SHA-256 и проверяются так же, пока их не заменят новым набором
+pub fn use_recovery_code(conn: &Connection, username: &str, code: &str) -> Result<bool> {
+    let codes: Vec<(i64, String)> = conn
+        .prepare_cached("SELECT id, code_hash FROM recovery_codes WHERE username = ?1 AND used = 0")?
+        .query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    let legacy = hash_password(code);
+    let found = codes.iter().find(|(_, hash)| {
+        verify_password_salted(code, hash) || constant_time_eq(legacy.as_bytes(), hash.as_bytes())
+    });
+    let Some((id, _)) = found else {
+        return Ok(false);
+    };
+    let updated = conn
+        .prepare_cached("UPDATE recovery_codes SET used = 1 WHERE id = ?1 AND used = 0")?
+        .execute(params![id])?;
+    Ok(updated > 0)
+}
+
+pub fn create_api_token(
+    conn: &Connection,
+    username: &str,
+    name: &str,
+    token_hash: &str,
+    scopes: &str,
+    created_at: u64,
+    expires_at: Option<u64>,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO api_tokens (username, name, token_hash, scopes, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![username, name, token_hash, scopes, created_at as i64, expires_at.map(|t| t as i64)])?;
+    Ok(())
+}
+
+pub fn list_api_tokens(conn: &Connection, username: &str) -> Result<Vec<ApiToken>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, name, scopes, created_at, expires_at, last_used_at, revoked
+         FROM api_tokens WHERE username = ?1 ORDER BY id DESC",
     )?;
-    let count: i64 = stmt.query_row(params![username, password_hash], |row| row.get(0))?;
-    Ok(count > 0)
-}
\ No newline at end of file
+    let tokens = stmt.query_map(params![username], |row| {
+        Ok(ApiToken {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            scopes: row.get(2)?,
+            created_at: row.get::<_, i64>(3)? as u64,
+            expires_at: row.get::<_, Option<i64>>(4)?.map(|t| t as u64),
+            last_used_at: row.get::<_, Option<i64>>(5)?.map(|t| t as u64),
+            revoked: row.get(6)?,
+        })
+    })?;
+    tokens.collect()
+}
+
+// Отзыв токена; владелец проверяется, чтобы нельзя было отозвать чужой
+pub fn revoke_api_token(conn: &Connection, username: &str, id: i64) -> Result<bool> {
+    let updated = conn.prepare_cached(
+        "UPDATE api_tokens SET revoked = 1 WHERE id = ?1 AND username = ?2",
+    )?
+    .execute(params![id, username])?;
+    Ok(updated > 0)
+}
+
+// Возвращает (владелец, области доступа) для действующего токена и отмечает время использования
+pub fn use_api_token(conn: &Connection, token_hash: &str, now: u64) -> Result<Option<(String, String)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, username, scopes FROM api_tokens
+         WHERE token_hash = ?1 AND revoked = 0 AND (expires_at IS NULL OR expires_at > ?2)",
+    )?;
+    let mut rows = stmt.query(params![token_hash, now as i64])?;
+    let (id, username, scopes): (i64, String, String) = match rows.next()? {
+        Some(row) => (row.get(0)?, row.get(1)?, row.get(2)?),
+        None => return Ok(None),
+    };
+    conn.prepare_cached(
+        "UPDATE api_tokens SET last_used_at = ?2 WHERE id = ?1",
+    )?
+    .execute(params![id, now as i64])?;
+    Ok(Some((username, scopes)))
+}
+
+// Временная база для тестов: файл со всеми миграциями, удаляется вместе со значением
+#[cfg(test)]
+pub struct TempDb(pub String);
+
+#[cfg(test)]
+impl TempDb {
+    pub fn new(name: &str) -> TempDb {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("web_server_v2-{}-{}-{}.db", name, std::process::id(), n));
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        let mut conn = Connection::open(&path).unwrap();
+        migrate(&mut conn).unwrap();
+        TempDb(path)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_token_works_once() {
+        let db = TempDb::new("reset-once");
+        let conn = Connection::open(&db.0).unwrap();
+        create_reset_token(&conn, "hash", "bg", 1000).unwrap();
+        assert_eq!(find_reset_token(&conn, "hash", 500).unwrap().as_deref(), Some("bg"));
+        // Поиск токен не гасит
+        assert_eq!(consume_reset_token(&conn, "hash", 500).unwrap().as_deref(), Some("bg"));
+        assert_eq!(consume_reset_token(&conn, "hash", 500).unwrap(), None);
+        assert_eq!(find_reset_token(&conn, "hash", 500).unwrap(), None);
+        assert_eq!(consume_reset_token(&conn, "other", 500).unwrap(), None);
+    }
+
+    #[test]
+    fn reset_token_expires() {
+        let db = TempDb::new("reset-expiry");
+        let conn = Connection::open(&db.0).unwrap();
+        create_reset_token(&conn, "hash", "bg", 1000).unwrap();
+        assert_eq!(find_reset_token(&conn, "hash", 1000).unwrap(), None);
+        assert_eq!(consume_reset_token(&conn, "hash", 1001).unwrap(), None);
+        assert_eq!(consume_reset_token(&conn, "hash", 999).unwrap().as_deref(), Some("bg"));
+    }
+
+    #[test]
+    fn other_sessions_are_deleted() {
+        let db = TempDb::new("sessions");
+        let conn = Connection::open(&db.0).unwrap();
+        for (token, username) in [("a", "bg"), ("b", "bg"), ("c", "bg2")] {
+            create_session(&conn, token, username, false, 1000).unwrap();
+        }
+        delete_other_sessions(&conn, "bg", "a").unwrap();
+        assert!(find_session(&conn, "a", 0).unwrap().is_some());
+        assert!(find_session(&conn, "b", 0).unwrap().is_none());
+        assert!(find_session(&conn, "c", 0).unwrap().is_some());
+        // Без текущей сессии завершаются все
+        delete_other_sessions(&conn, "bg", "").unwrap();
+        assert!(find_session(&conn, "a", 0).unwrap().is_none());
+    }
+}
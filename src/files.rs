@@ -0,0 +1,1297 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, Result, Row};
+use sha2::{Digest, Sha256};
+
+use crate::search::{extract_file_text, extract_text, index_text};
+use crate::shares::delete_file_shares;
+use crate::store::User;
+use crate::uploads::delete_user_uploads;
+use crate::utils::{get_content_type, get_timestamp, random_token, sha256_hex};
+
+// Общий публичный каталог загрузок (раздается через /static/); новые файлы сюда больше не попадают
+pub const UPLOAD_DIR: &str = "static/uploads";
+// Личные пространства пользователей хранятся в <STORAGE_DIR>. Содержимое лежит один раз
+// в <STORAGE_DIR>/.blobs/<2 символа хеша>/<sha256>, а таблица files связывает с ним имена пользователей
+const DEFAULT_STORAGE_DIR: &str = "storage";
+const BLOB_DIR: &str = ".blobs";
+// Кэш миниатюр изображений: <STORAGE_DIR>/.thumbs/<sha256>.png
+const THUMB_DIR: &str = ".thumbs";
+// Квота по умолчанию — 100 МБ на пользователя
+const DEFAULT_QUOTA_BYTES: u64 = 100 * 1024 * 1024;
+// Сколько дней удаленные файлы хранятся в корзине
+const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+// Ограничение большинства файловых систем на длину имени
+const MAX_NAME_LEN: usize = 255;
+
+// Изменения счетчиков ссылок и удаление блобов выполняются под этой блокировкой,
+// иначе параллельные загрузка и удаление одинакового содержимого могут потерять файл
+static BLOB_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub root: String,
+    // Квота для пользователей без индивидуальной (users.quota_bytes)
+    pub default_quota_bytes: u64,
+    // Через сколько секунд файлы из корзины удаляются окончательно
+    pub trash_retention_secs: u64,
+}
+
+impl StorageConfig {
+    // Каталог задается переменной STORAGE_DIR, квота в байтах — USER_QUOTA_BYTES,
+    // срок хранения в корзине в днях — TRASH_RETENTION_DAYS
+    pub fn from_env() -> Self {
+        StorageConfig {
+            root: std::env::var("STORAGE_DIR").unwrap_or_else(|_| DEFAULT_STORAGE_DIR.to_string()),
+            default_quota_bytes: std::env::var("USER_QUOTA_BYTES")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_QUOTA_BYTES),
+            trash_retention_secs: std::env::var("TRASH_RETENTION_DAYS")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS)
+                * 24
+                * 60
+                * 60,
+        }
+    }
+
+    // Личный каталог пользователя; None для имен, которые нельзя использовать как имя каталога.
+    // Имена с точкой в начале заняты служебными каталогами (.blobs)
+    pub fn user_dir(&self, username: &str) -> Option<String> {
+        if username.is_empty() || username.starts_with('.') || username.contains(['/', '\\']) {
+            return None;
+        }
+        Some(format!("{}/{}", self.root, username))
+    }
+
+    pub fn quota_for(&self, user: &User) -> u64 {
+        user.quota_bytes.unwrap_or(self.default_quota_bytes)
+    }
+
+    fn blob_dir(&self) -> String {
+        format!("{}/{}", self.root, BLOB_DIR)
+    }
+
+    fn blob_path(&self, sha256: &str) -> String {
+        format!("{}/{}/{}", self.blob_dir(), &sha256[..2], sha256)
+    }
+
+    fn is_blob(&self, stored_path: &str) -> bool {
+        stored_path.starts_with(&format!("{}/", self.blob_dir()))
+    }
+
+    // Миниатюра зависит только от содержимого, поэтому общая для всех его копий
+    pub fn thumbnail_path(&self, sha256: &str) -> String {
+        format!("{}/{}/{}.png", self.root, THUMB_DIR, sha256)
+    }
+}
+
+// Хранилище во временном каталоге для тестов; удаляется вместе со всем содержимым
+#[cfg(test)]
+pub struct TempStorage(pub StorageConfig);
+
+#[cfg(test)]
+impl TempStorage {
+    pub fn new(name: &str) -> TempStorage {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let root = std::env::temp_dir().join(format!("web_server_v2-{}-{}-{}", name, std::process::id(), n));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        TempStorage(StorageConfig {
+            root: root.to_string_lossy().to_string(),
+            default_quota_bytes: DEFAULT_QUOTA_BYTES,
+            trash_retention_secs: DEFAULT_TRASH_RETENTION_DAYS * 24 * 60 * 60,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempStorage {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0.root);
+    }
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    // Операция невозможна (неверное имя, имя занято, нет папки); текст показывается пользователю
+    Invalid(String),
+}
+
+impl From<std::io::Error> for FileError {
+    fn from(err: std::io::Error) -> Self {
+        FileError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for FileError {
+    fn from(err: rusqlite::Error) -> Self {
+        FileError::Sqlite(err)
+    }
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileError::Io(err) => write!(f, "IO error: {}", err),
+            FileError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+            FileError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+// Запись о файле. path — имя в пространстве владельца, stored_path — где лежит содержимое на диске
+pub struct FileRecord {
+    pub id: i64,
+    // None для общих файлов из UPLOAD_DIR
+    pub owner: Option<String>,
+    pub path: String,
+    pub stored_path: String,
+    pub original_name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+    pub uploaded_at: u64,
+}
+
+// Файл в корзине. file.path — откуда он был удален, file.id не используется
+pub struct TrashItem {
+    pub id: i64,
+    pub file: FileRecord,
+    pub deleted_at: u64,
+}
+
+// Содержимое одной папки: вложенные папки (полные пути) и файлы
+pub struct FolderListing {
+    pub folders: Vec<String>,
+    pub files: Vec<FileRecord>,
+}
+
+// Порядок файлов в файловом менеджере
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileSort {
+    Name,
+    Size,
+    Date,
+}
+
+impl FileSort {
+    pub const ALL: &'static [FileSort] = &[FileSort::Name, FileSort::Size, FileSort::Date];
+
+    // Значение параметра ?sort= в файловом менеджере
+    pub fn param(self) -> &'static str {
+        match self {
+            FileSort::Name => "name",
+            FileSort::Size => "size",
+            FileSort::Date => "date",
+        }
+    }
+
+    pub fn from_param(value: &str) -> Option<FileSort> {
+        FileSort::ALL.iter().copied().find(|sort| sort.param() == value)
+    }
+
+    // Папки всегда идут первыми и упорядочены по имени; файлы с равным ключом — тоже по имени
+    pub fn apply(self, listing: &mut FolderListing, descending: bool) {
+        listing.folders.sort_by_key(|path| path.to_lowercase());
+        listing.files.sort_by(|a, b| {
+            let by_name = a.path.to_lowercase().cmp(&b.path.to_lowercase());
+            match self {
+                FileSort::Name => by_name,
+                FileSort::Size => a.size.cmp(&b.size).then(by_name),
+                FileSort::Date => a.uploaded_at.cmp(&b.uploaded_at).then(by_name),
+            }
+        });
+        if descending {
+            listing.files.reverse();
+            if self == FileSort::Name {
+                listing.folders.reverse();
+            }
+        }
+    }
+}
+
+// Итог сверки таблицы files с диском
+#[derive(Default)]
+pub struct ReconcileReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    // Блобы, на которые не ссылается ни один файл
+    pub orphaned_blobs: usize,
+}
+
+const FILE_COLUMNS: &str = "id, owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at";
+
+fn file_from_row(row: &Row) -> Result<FileRecord> {
+    Ok(FileRecord {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        path: row.get(2)?,
+        stored_path: row.get(3)?,
+        original_name: row.get(4)?,
+        size: row.get::<_, i64>(5)? as u64,
+        sha256: row.get(6)?,
+        content_type: row.get(7)?,
+        uploaded_at: row.get::<_, i64>(8)? as u64,
+    })
+}
+
+// Сохраняет запись: id == 0 — новая, иначе обновление существующей. Возвращает id
+pub fn save_file_record(conn: &Connection, file: &FileRecord) -> Result<i64> {
+    if file.id != 0 {
+        conn.prepare_cached(
+            "UPDATE files SET owner = ?2, path = ?3, stored_path = ?4, original_name = ?5, size = ?6,
+                 sha256 = ?7, content_type = ?8, uploaded_at = ?9 WHERE id = ?1",
+        )?
+        .execute(params![
+            file.id,
+            file.owner,
+            file.path,
+            file.stored_path,
+            file.original_name,
+            file.size as i64,
+            file.sha256,
+            file.content_type,
+            file.uploaded_at as i64
+        ])?;
+        return Ok(file.id);
+    }
+    conn.prepare_cached(
+        "INSERT INTO files (owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        file.owner,
+        file.path,
+        file.stored_path,
+        file.original_name,
+        file.size as i64,
+        file.sha256,
+        file.content_type,
+        file.uploaded_at as i64
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Все известные файлы, новые сверху
+pub fn list_file_records(conn: &Connection) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM files ORDER BY uploaded_at DESC, id DESC",
+        FILE_COLUMNS
+    ))?;
+    let files = stmt.query_map([], file_from_row)?;
+    files.collect()
+}
+
+pub fn find_file_record(conn: &Connection, id: i64) -> Result<Option<FileRecord>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM files WHERE id = ?1", FILE_COLUMNS))?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(file_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+// Файл пользователя по имени в его пространстве
+pub fn find_user_file(conn: &Connection, owner: &str, path: &str) -> Result<Option<FileRecord>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM files WHERE owner = ?1 AND path = ?2", FILE_COLUMNS))?;
+    let mut rows = stmt.query(params![owner, path])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(file_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+// Файлы, видимые пользователю: его собственные и общие публичные (без владельца)
+pub fn list_visible_files(conn: &Connection, username: &str) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM files WHERE owner = ?1 OR owner IS NULL ORDER BY uploaded_at DESC, id DESC",
+        FILE_COLUMNS
+    ))?;
+    let files = stmt.query_map(params![username], file_from_row)?;
+    files.collect()
+}
+
+// Общие файлы из UPLOAD_DIR
+pub fn list_public_files(conn: &Connection) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM files WHERE owner IS NULL ORDER BY path", FILE_COLUMNS))?;
+    let files = stmt.query_map([], file_from_row)?;
+    files.collect()
+}
+
+// Сколько байт занимают файлы пользователя, включая корзину: ее содержимое еще хранится на диске. Одинаковые файлы считаются каждый раз:
+// экономия от дедупликации квоту не увеличивает. Незавершенные загрузки (tus) резервируют свою полную длину,
+// иначе несколько параллельных загрузок вместе превысили бы квоту
+pub fn used_bytes(conn: &Connection, owner: &str) -> Result<u64> {
+    let used: i64 = conn
+        .prepare_cached(
+            "SELECT (SELECT COALESCE(SUM(size), 0) FROM files WHERE owner = ?1)
+                  + (SELECT COALESCE(SUM(size), 0) FROM trash WHERE owner = ?1)
+                  + (SELECT COALESCE(SUM(length), 0) FROM uploads WHERE owner = ?1)",
+        )?
+        .query_row(params![owner], |row| row.get(0))?;
+    Ok(used as u64)
+}
+
+// Проверка одного компонента пути — имени файла или папки
+pub fn validate_name(name: &str) -> std::result::Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("Имя не может быть пустым, \".\" или \"..\".".to_string());
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("Имя не должно быть длиннее {} байт.", MAX_NAME_LEN));
+    }
+    if name.chars().any(|c| c == '/' || c == '\\' || c.is_control()) {
+        return Err("Имя не должно содержать символы / и \\ и управляющие символы.".to_string());
+    }
+    if name.trim() != name {
+        return Err("Имя не должно начинаться или заканчиваться пробелом.".to_string());
+    }
+    Ok(())
+}
+
+// Приводит путь папки из запроса к виду "a/b" (корень — пустая строка)
+pub fn normalize_dir(dir: &str) -> std::result::Result<String, String> {
+    let segments: Vec<&str> = dir.split('/').filter(|segment| !segment.is_empty()).collect();
+    for segment in &segments {
+        validate_name(segment)?;
+    }
+    Ok(segments.join("/"))
+}
+
+pub fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// Папка, в которой лежит path ("" для корня)
+pub fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+// Последний компонент пути
+pub fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+pub fn folder_exists(conn: &Connection, owner: &str, path: &str) -> Result<bool> {
+    if path.is_empty() {
+        return Ok(true);
+    }
+    let count: i64 = conn
+        .prepare_cached("SELECT COUNT(*) FROM folders WHERE owner = ?1 AND path = ?2")?
+        .query_row(params![owner, path], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+// Когда создана папка; None — такой папки нет. У корня времени создания нет, для него 0
+pub fn folder_created_at(conn: &Connection, owner: &str, path: &str) -> Result<Option<u64>> {
+    if path.is_empty() {
+        return Ok(Some(0));
+    }
+    let mut stmt = conn.prepare_cached("SELECT created_at FROM folders WHERE owner = ?1 AND path = ?2")?;
+    let mut rows = stmt.query_map(params![owner, path], |row| row.get::<_, i64>(0))?;
+    Ok(rows.next().transpose()?.map(|t| t as u64))
+}
+
+// Занято ли имя файлом или папкой
+fn path_taken(conn: &Connection, owner: &str, path: &str) -> Result<bool> {
+    Ok(folder_exists(conn, owner, path)? || find_user_file(conn, owner, path)?.is_some())
+}
+
+// Создает папку dir и все недостающие родительские папки
+fn ensure_folders(conn: &Connection, owner: &str, dir: &str) -> std::result::Result<(), FileError> {
+    let mut path = String::new();
+    for segment in dir.split('/').filter(|segment| !segment.is_empty()) {
+        path = join_path(&path, segment);
+        if find_user_file(conn, owner, &path)?.is_some() {
+            return Err(FileError::Invalid(format!("\"{}\" — это файл, а не папка.", path)));
+        }
+        conn.prepare_cached(
+            "INSERT OR IGNORE INTO folders (owner, path, created_at) VALUES (?1, ?2, ?3)",
+        )?
+        .execute(params![owner, path, get_timestamp() as i64])?;
+    }
+    Ok(())
+}
+
+pub fn create_folder(conn: &Connection, owner: &str, path: &str) -> std::result::Result<(), FileError> {
+    if !folder_exists(conn, owner, parent_dir(path))? {
+        return Err(FileError::Invalid("Родительская папка не найдена.".to_string()));
+    }
+    if path_taken(conn, owner, path)? {
+        return Err(FileError::Invalid(format!("\"{}\" уже существует.", base_name(path))));
+    }
+    ensure_folders(conn, owner, path)
+}
+
+// Все папки пользователя (для выбора места при перемещении)
+pub fn list_all_folders(conn: &Connection, owner: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached("SELECT path FROM folders WHERE owner = ?1 ORDER BY path")?;
+    let folders = stmt.query_map(params![owner], |row| row.get(0))?;
+    folders.collect()
+}
+
+// Все папки и файлы внутри dir на любой глубине; пустой dir — все пространство пользователя
+pub fn list_subtree(conn: &Connection, owner: &str, dir: &str) -> Result<FolderListing> {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+
+    let mut stmt = conn.prepare_cached(
+        "SELECT path FROM folders WHERE owner = ?1 AND substr(path, 1, ?2) = ?3 ORDER BY path",
+    )?;
+    let folders = stmt
+        .query_map(params![owner, prefix.len() as i64, prefix], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM files WHERE owner = ?1 AND substr(path, 1, ?2) = ?3 ORDER BY path",
+        FILE_COLUMNS
+    ))?;
+    let files = stmt
+        .query_map(params![owner, prefix.len() as i64, prefix], file_from_row)?
+        .collect::<Result<Vec<FileRecord>>>()?;
+
+    Ok(FolderListing { folders, files })
+}
+
+// Непосредственное содержимое папки dir
+pub fn list_folder(conn: &Connection, owner: &str, dir: &str) -> Result<FolderListing> {
+    let prefix_len = if dir.is_empty() { 0 } else { dir.len() + 1 };
+    let is_child = |path: &str| !path[prefix_len..].contains('/');
+
+    let mut listing = list_subtree(conn, owner, dir)?;
+    listing.folders.retain(|path| is_child(path));
+    listing.files.retain(|file| is_child(&file.path));
+    Ok(listing)
+}
+
+// Переименование и перемещение файла — это смена его пути
+pub fn move_file(conn: &Connection, owner: &str, file: &FileRecord, new_path: &str) -> std::result::Result<(), FileError> {
+    if file.path == new_path {
+        return Ok(());
+    }
+    if !folder_exists(conn, owner, parent_dir(new_path))? {
+        return Err(FileError::Invalid("Папка назначения не найдена.".to_string()));
+    }
+    if path_taken(conn, owner, new_path)? {
+        return Err(FileError::Invalid(format!("\"{}\" уже существует.", new_path)));
+    }
+    conn.prepare_cached("UPDATE files SET path = ?2 WHERE id = ?1")?
+        .execute(params![file.id, new_path])?;
+    Ok(())
+}
+
+// Переносит папку вместе со всем содержимым
+pub fn move_folder(conn: &Connection, owner: &str, old_path: &str, new_path: &str) -> std::result::Result<(), FileError> {
+    if old_path == new_path {
+        return Ok(());
+    }
+    if !folder_exists(conn, owner, old_path)? {
+        return Err(FileError::Invalid("Папка не найдена.".to_string()));
+    }
+    if new_path.starts_with(&format!("{}/", old_path)) {
+        return Err(FileError::Invalid("Нельзя переместить папку внутрь самой себя.".to_string()));
+    }
+    if !folder_exists(conn, owner, parent_dir(new_path))? {
+        return Err(FileError::Invalid("Папка назначения не найдена.".to_string()));
+    }
+    if path_taken(conn, owner, new_path)? {
+        return Err(FileError::Invalid(format!("\"{}\" уже существует.", new_path)));
+    }
+
+    let old_prefix = format!("{}/", old_path);
+    let tx = conn.unchecked_transaction()?;
+    for table in ["folders", "files"] {
+        tx.execute(
+            &format!(
+                "UPDATE {} SET path = ?3 || substr(path, ?4) WHERE owner = ?1 AND (path = ?2 OR substr(path, 1, ?5) = ?6)",
+                table
+            ),
+            params![
+                owner,
+                old_path,
+                new_path,
+                old_path.len() as i64 + 1,
+                old_prefix.len() as i64,
+                old_prefix
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Копия записи под новым именем: содержимое не дублируется, у блоба появляется еще одна ссылка.
+// Вызывается под BLOB_LOCK внутри транзакции
+fn copy_record(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    file: &FileRecord,
+    new_path: &str,
+) -> std::result::Result<FileRecord, FileError> {
+    add_blob_ref(conn, &file.sha256, file.size)?;
+    if !storage.is_blob(&file.stored_path) {
+        // Файл, сохраненный до появления блобов, сначала попадает в хранилище блобов
+        write_blob(storage, &file.sha256, &Content::Bytes(&fs::read(&file.stored_path)?))?;
+    }
+    let mut copy = FileRecord {
+        id: 0,
+        owner: Some(owner.to_string()),
+        path: new_path.to_string(),
+        stored_path: storage.blob_path(&file.sha256),
+        original_name: base_name(new_path).to_string(),
+        size: file.size,
+        sha256: file.sha256.clone(),
+        content_type: file.content_type.clone(),
+        uploaded_at: get_timestamp(),
+    };
+    copy.id = save_file_record(conn, &copy)?;
+    Ok(copy)
+}
+
+pub fn copy_file(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    file: &FileRecord,
+    new_path: &str,
+) -> std::result::Result<FileRecord, FileError> {
+    if !folder_exists(conn, owner, parent_dir(new_path))? {
+        return Err(FileError::Invalid("Папка назначения не найдена.".to_string()));
+    }
+    if path_taken(conn, owner, new_path)? {
+        return Err(FileError::Invalid(format!("\"{}\" уже существует.", new_path)));
+    }
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let tx = conn.unchecked_transaction()?;
+    let copy = copy_record(&tx, storage, owner, file, new_path)?;
+    tx.commit()?;
+    Ok(copy)
+}
+
+// Копирует папку со всем содержимым; возвращает число скопированных файлов
+pub fn copy_folder(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    old_path: &str,
+    new_path: &str,
+) -> std::result::Result<usize, FileError> {
+    if !folder_exists(conn, owner, old_path)? {
+        return Err(FileError::Invalid("Папка не найдена.".to_string()));
+    }
+    if old_path.is_empty() || new_path == old_path || new_path.starts_with(&format!("{}/", old_path)) {
+        return Err(FileError::Invalid("Нельзя скопировать папку внутрь самой себя.".to_string()));
+    }
+    if !folder_exists(conn, owner, parent_dir(new_path))? {
+        return Err(FileError::Invalid("Папка назначения не найдена.".to_string()));
+    }
+    if path_taken(conn, owner, new_path)? {
+        return Err(FileError::Invalid(format!("\"{}\" уже существует.", new_path)));
+    }
+
+    let listing = list_subtree(conn, owner, old_path)?;
+    let moved = |path: &str| format!("{}{}", new_path, &path[old_path.len()..]);
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let tx = conn.unchecked_transaction()?;
+    ensure_folders(&tx, owner, new_path)?;
+    for folder in &listing.folders {
+        ensure_folders(&tx, owner, &moved(folder))?;
+    }
+    for file in &listing.files {
+        copy_record(&tx, storage, owner, file, &moved(&file.path))?;
+    }
+    tx.commit()?;
+    Ok(listing.files.len())
+}
+
+// Переносит файлы папки (на любой глубине) в корзину и удаляет саму папку; возвращает число файлов
+pub fn trash_folder(conn: &Connection, owner: &str, path: &str) -> std::result::Result<usize, FileError> {
+    if path.is_empty() || !folder_exists(conn, owner, path)? {
+        return Err(FileError::Invalid("Папка не найдена.".to_string()));
+    }
+    let prefix = format!("{}/", path);
+    let files: Vec<FileRecord> = {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM files WHERE owner = ?1 AND substr(path, 1, ?2) = ?3",
+            FILE_COLUMNS
+        ))?;
+        let files = stmt.query_map(params![owner, prefix.len() as i64, prefix], file_from_row)?;
+        files.collect::<Result<_>>()?
+    };
+    let tx = conn.unchecked_transaction()?;
+    for file in &files {
+        move_to_trash(&tx, file)?;
+    }
+    tx.prepare_cached("DELETE FROM folders WHERE owner = ?1 AND (path = ?2 OR substr(path, 1, ?3) = ?4)")?
+        .execute(params![owner, path, prefix.len() as i64, prefix])?;
+    tx.commit()?;
+    Ok(files.len())
+}
+
+fn delete_file_record(conn: &Connection, id: i64) -> Result<()> {
+    conn.prepare_cached("DELETE FROM files WHERE id = ?1")?.execute(params![id])?;
+    delete_file_shares(conn, id)?;
+    Ok(())
+}
+
+fn add_blob_ref(conn: &Connection, sha256: &str, size: u64) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO blobs (sha256, size, refcount) VALUES (?1, ?2, 1)
+         ON CONFLICT(sha256) DO UPDATE SET refcount = refcount + 1",
+    )?
+    .execute(params![sha256, size as i64])?;
+    Ok(())
+}
+
+// Снимает одну ссылку; true, если ссылок не осталось и блоб можно удалить с диска
+fn release_blob_ref(conn: &Connection, sha256: &str) -> Result<bool> {
+    conn.prepare_cached("UPDATE blobs SET refcount = refcount - 1 WHERE sha256 = ?1")?
+        .execute(params![sha256])?;
+    let deleted = conn
+        .prepare_cached("DELETE FROM blobs WHERE sha256 = ?1 AND refcount <= 0")?
+        .execute(params![sha256])?;
+    Ok(deleted > 0)
+}
+
+// Содержимое сохраняемого файла: в памяти или уже на диске (например, собранная загрузка)
+enum Content<'a> {
+    Bytes(&'a [u8]),
+    File(&'a str),
+}
+
+impl Content<'_> {
+    // SHA-256 и размер; файл читается частями, а не целиком
+    fn digest(&self) -> std::io::Result<(String, u64)> {
+        match self {
+            Content::Bytes(bytes) => Ok((sha256_hex(bytes), bytes.len() as u64)),
+            Content::File(path) => {
+                let mut hasher = Sha256::new();
+                let size = std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+                Ok((format!("{:x}", hasher.finalize()), size))
+            }
+        }
+    }
+}
+
+// Записывает содержимое в хранилище блобов, если его там еще нет.
+// Запись идет во временный файл с последующим rename, чтобы читатели не увидели половину файла;
+// файл с диска переносится переименованием
+fn write_blob(storage: &StorageConfig, sha256: &str, content: &Content) -> std::io::Result<()> {
+    let path = storage.blob_path(sha256);
+    if Path::new(&path).is_file() {
+        if let Content::File(source) = content {
+            fs::remove_file(source)?;
+        }
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match content {
+        Content::Bytes(bytes) => {
+            let tmp = format!("{}.{}.tmp", path, random_token(8)?);
+            fs::write(&tmp, bytes)?;
+            fs::rename(&tmp, &path)
+        }
+        Content::File(source) => fs::rename(source, &path),
+    }
+}
+
+// Сохраняет файл пользователя под именем path. Одинаковое содержимое хранится на диске один раз;
+// перезапись существующего имени освобождает прежний блоб, если на него больше никто не ссылается
+pub fn store_user_file(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    path: &str,
+    content: &[u8],
+    content_type: &str,
+) -> std::result::Result<FileRecord, FileError> {
+    store_content(conn, storage, owner, path, Content::Bytes(content), content_type)
+}
+
+// То же для содержимого, уже лежащего на диске в source (в пределах хранилища);
+// source переносится в хранилище блобов или удаляется, если такое содержимое уже есть
+pub fn store_user_file_from(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    path: &str,
+    source: &str,
+    content_type: &str,
+) -> std::result::Result<FileRecord, FileError> {
+    store_content(conn, storage, owner, path, Content::File(source), content_type)
+}
+
+fn store_content(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    path: &str,
+    content: Content,
+    content_type: &str,
+) -> std::result::Result<FileRecord, FileError> {
+    let (sha256, size) = content.digest()?;
+    // Текст для поиска; запись files подхватит его триггером при сохранении
+    let text = match &content {
+        Content::Bytes(bytes) => extract_text(content_type, bytes),
+        Content::File(source) => extract_file_text(content_type, source)?,
+    };
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Сначала ссылка, потом файл: сверка никогда не увидит блоб без ссылки и не удалит его
+    let tx = conn.unchecked_transaction()?;
+    if folder_exists(&tx, owner, path)? {
+        return Err(FileError::Invalid(format!("\"{}\" — это папка.", path)));
+    }
+    ensure_folders(&tx, owner, parent_dir(path))?;
+    let existing = find_user_file(&tx, owner, path)?;
+    add_blob_ref(&tx, &sha256, size)?;
+    if let Some(text) = &text {
+        index_text(&tx, &sha256, text)?;
+    }
+    let mut record = FileRecord {
+        id: existing.as_ref().map(|f| f.id).unwrap_or(0),
+        owner: Some(owner.to_string()),
+        path: path.to_string(),
+        stored_path: storage.blob_path(&sha256),
+        original_name: base_name(path).to_string(),
+        size,
+        sha256: sha256.clone(),
+        content_type: content_type.to_string(),
+        uploaded_at: get_timestamp(),
+    };
+    record.id = save_file_record(&tx, &record)?;
+    let freed = match &existing {
+        Some(old) if storage.is_blob(&old.stored_path) => release_blob_ref(&tx, &old.sha256)?,
+        _ => false,
+    };
+    tx.commit()?;
+
+    write_blob(storage, &sha256, &content)?;
+    if let Some(old) = existing {
+        // Файл, сохраненный до появления блобов, лежал отдельно — удаляем его как есть
+        if freed || !storage.is_blob(&old.stored_path) {
+            let _ = fs::remove_file(&old.stored_path);
+            let _ = fs::remove_file(storage.thumbnail_path(&old.sha256));
+        }
+    }
+    Ok(record)
+}
+
+// Удаляет файл; содержимое стирается с диска только вместе с последней ссылкой на него
+pub fn delete_file(
+    conn: &Connection,
+    storage: &StorageConfig,
+    file: &FileRecord,
+) -> std::result::Result<(), FileError> {
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let tx = conn.unchecked_transaction()?;
+    delete_file_record(&tx, file.id)?;
+    release_content(tx, storage, file)
+}
+
+// Освобождает содержимое удаленной в tx записи и фиксирует tx.
+// Блоб стирается только вместе с последней ссылкой; вызывается под BLOB_LOCK
+fn release_content(
+    tx: rusqlite::Transaction,
+    storage: &StorageConfig,
+    file: &FileRecord,
+) -> std::result::Result<(), FileError> {
+    let is_blob = storage.is_blob(&file.stored_path);
+    let freed = is_blob && release_blob_ref(&tx, &file.sha256)?;
+    tx.commit()?;
+    if freed || !is_blob {
+        match fs::remove_file(&file.stored_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(FileError::Io(e)),
+            _ => {}
+        }
+        let _ = fs::remove_file(storage.thumbnail_path(&file.sha256));
+    }
+    Ok(())
+}
+
+const TRASH_COLUMNS: &str =
+    "id, owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at, deleted_at";
+
+fn trash_from_row(row: &Row) -> Result<TrashItem> {
+    let file = file_from_row(row)?;
+    Ok(TrashItem {
+        id: file.id,
+        file: FileRecord { id: 0, ..file },
+        deleted_at: row.get::<_, i64>(9)? as u64,
+    })
+}
+
+// Запись переезжает в корзину вместе со ссылкой на содержимое, поэтому счетчик блоба не меняется.
+// Ссылки для скачивания при этом отзываются
+fn move_to_trash(conn: &Connection, file: &FileRecord) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO trash (owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at, deleted_at)
+         SELECT owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at, ?2 FROM files WHERE id = ?1",
+    )?
+    .execute(params![file.id, get_timestamp() as i64])?;
+    delete_file_record(conn, file.id)
+}
+
+// Удаление из файлового менеджера и API: личный файл попадает в корзину, общий удаляется сразу
+pub fn trash_file(conn: &Connection, storage: &StorageConfig, file: &FileRecord) -> std::result::Result<(), FileError> {
+    if file.owner.is_none() {
+        return delete_file(conn, storage, file);
+    }
+    let tx = conn.unchecked_transaction()?;
+    move_to_trash(&tx, file)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Корзина пользователя, сначала недавно удаленное
+pub fn list_trash(conn: &Connection, owner: &str) -> Result<Vec<TrashItem>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM trash WHERE owner = ?1 ORDER BY deleted_at DESC, id DESC",
+        TRASH_COLUMNS
+    ))?;
+    let items = stmt.query_map(params![owner], trash_from_row)?;
+    items.collect()
+}
+
+fn find_trash_item(conn: &Connection, owner: &str, id: i64) -> Result<Option<TrashItem>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM trash WHERE id = ?1 AND owner = ?2", TRASH_COLUMNS))?;
+    let mut rows = stmt.query_map(params![id, owner], trash_from_row)?;
+    rows.next().transpose()
+}
+
+// Возвращает файл на прежнее место, заново создавая удаленные папки; возвращает путь
+pub fn restore_from_trash(conn: &Connection, owner: &str, id: i64) -> std::result::Result<String, FileError> {
+    let item = find_trash_item(conn, owner, id)?.ok_or_else(|| FileError::Invalid("Файл не найден в корзине.".to_string()))?;
+    let path = item.file.path.clone();
+    if path_taken(conn, owner, &path)? {
+        return Err(FileError::Invalid(format!(
+            "\"{}\" уже существует: переименуйте или удалите его и повторите.",
+            path
+        )));
+    }
+    let tx = conn.unchecked_transaction()?;
+    ensure_folders(&tx, owner, parent_dir(&path))?;
+    save_file_record(&tx, &item.file)?;
+    tx.prepare_cached("DELETE FROM trash WHERE id = ?1")?.execute(params![item.id])?;
+    tx.commit()?;
+    Ok(path)
+}
+
+fn purge_item(conn: &Connection, storage: &StorageConfig, item: &TrashItem) -> std::result::Result<(), FileError> {
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let tx = conn.unchecked_transaction()?;
+    tx.prepare_cached("DELETE FROM trash WHERE id = ?1")?.execute(params![item.id])?;
+    release_content(tx, storage, &item.file)
+}
+
+// Окончательное удаление одного файла из корзины; возвращает его прежний путь
+pub fn purge_trash_item(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    id: i64,
+) -> std::result::Result<String, FileError> {
+    let item = find_trash_item(conn, owner, id)?.ok_or_else(|| FileError::Invalid("Файл не найден в корзине.".to_string()))?;
+    purge_item(conn, storage, &item)?;
+    Ok(item.file.path)
+}
+
+// Очищает корзину пользователя; возвращает число удаленных файлов
+pub fn empty_trash(conn: &Connection, storage: &StorageConfig, owner: &str) -> std::result::Result<usize, FileError> {
+    let items = list_trash(conn, owner)?;
+    for item in &items {
+        purge_item(conn, storage, item)?;
+    }
+    Ok(items.len())
+}
+
+// Удаляет из всех корзин файлы, удаленные раньше cutoff; возвращает их число
+pub fn purge_expired_trash(conn: &Connection, storage: &StorageConfig, cutoff: u64) -> std::result::Result<usize, FileError> {
+    let items: Vec<TrashItem> = {
+        let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM trash WHERE deleted_at < ?1", TRASH_COLUMNS))?;
+        let items = stmt.query_map(params![cutoff as i64], trash_from_row)?;
+        items.collect::<Result<_>>()?
+    };
+    for item in &items {
+        purge_item(conn, storage, item)?;
+    }
+    Ok(items.len())
+}
+
+// Удаляет все файлы пользователя (вместе с учетной записью); возвращает их число
+pub fn delete_user_files(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+) -> std::result::Result<usize, FileError> {
+    let files: Vec<FileRecord> = {
+        let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM files WHERE owner = ?1", FILE_COLUMNS))?;
+        let files = stmt.query_map(params![owner], file_from_row)?;
+        files.collect::<Result<_>>()?
+    };
+    for file in &files {
+        delete_file(conn, storage, file)?;
+    }
+    let trashed = empty_trash(conn, storage, owner)?;
+    delete_user_uploads(conn, storage, owner)?;
+    conn.prepare_cached("DELETE FROM folders WHERE owner = ?1")?.execute(params![owner])?;
+    Ok(files.len() + trashed)
+}
+
+// Приводит таблицу files в соответствие с диском:
+// - удаляет записи, содержимого которых больше нет;
+// - добавляет записи для файлов, положенных в UPLOAD_DIR и личные каталоги в обход сервера
+//   (файлы из личных каталогов переносятся в хранилище блобов);
+// - пересчитывает счетчики ссылок и удаляет блобы, на которые никто не ссылается
+pub fn reconcile(conn: &Connection, storage: &StorageConfig) -> std::result::Result<ReconcileReport, FileError> {
+    let mut report = ReconcileReport::default();
+
+    for file in list_file_records(conn)? {
+        if !Path::new(&file.stored_path).is_file() {
+            delete_file_record(conn, file.id)?;
+            report.removed.push(file.stored_path);
+        }
+    }
+    let trashed: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare_cached("SELECT id, stored_path FROM trash")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    for (id, stored_path) in trashed {
+        if !Path::new(&stored_path).is_file() {
+            conn.prepare_cached("DELETE FROM trash WHERE id = ?1")?.execute(params![id])?;
+            report.removed.push(stored_path);
+        }
+    }
+
+    reconcile_public_dir(conn, &mut report)?;
+
+    if Path::new(&storage.root).is_dir() {
+        for entry in fs::read_dir(&storage.root)? {
+            let entry = entry?;
+            let owner = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type()?.is_dir() || owner.starts_with('.') {
+                continue;
+            }
+            ingest_user_dir(conn, storage, &owner, &mut report)?;
+        }
+    }
+
+    recount_blobs(conn, storage, &mut report)?;
+    Ok(report)
+}
+
+// Общие файлы не дедуплицируются: они раздаются напрямую по своему пути
+fn reconcile_public_dir(conn: &Connection, report: &mut ReconcileReport) -> std::result::Result<(), FileError> {
+    if !Path::new(UPLOAD_DIR).exists() {
+        return Ok(());
+    }
+    let known = list_file_records(conn)?;
+    for entry in fs::read_dir(UPLOAD_DIR)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let stored_path = format!("{}/{}", UPLOAD_DIR, name);
+        let existing = known.iter().find(|f| f.stored_path == stored_path);
+
+        // Хеш пересчитываем всегда: файл могли переписать, не изменив ни размера, ни времени
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut fs::File::open(entry.path())?, &mut hasher)?;
+        let sha256 = format!("{:x}", hasher.finalize());
+        if matches!(existing, Some(f) if f.size == metadata.len() && f.sha256 == sha256) {
+            continue;
+        }
+        let record = match existing {
+            Some(f) => {
+                report.updated.push(stored_path.clone());
+                FileRecord {
+                    id: f.id,
+                    owner: None,
+                    path: f.path.clone(),
+                    stored_path,
+                    original_name: f.original_name.clone(),
+                    size: metadata.len(),
+                    sha256,
+                    content_type: f.content_type.clone(),
+                    uploaded_at: f.uploaded_at,
+                }
+            }
+            None => {
+                report.added.push(stored_path.clone());
+                FileRecord {
+                    id: 0,
+                    owner: None,
+                    path: name.clone(),
+                    stored_path,
+                    content_type: get_content_type(&name).to_string(),
+                    original_name: name,
+                    size: metadata.len(),
+                    sha256,
+                    uploaded_at: modified_secs(&metadata),
+                }
+            }
+        };
+        save_file_record(conn, &record)?;
+    }
+    Ok(())
+}
+
+// Переносит файлы из личного каталога в хранилище блобов, сохраняя имя и время изменения
+fn ingest_user_dir(
+    conn: &Connection,
+    storage: &StorageConfig,
+    owner: &str,
+    report: &mut ReconcileReport,
+) -> std::result::Result<(), FileError> {
+    let dir = format!("{}/{}", storage.root, owner);
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let disk_path = format!("{}/{}", dir, name);
+        let content = fs::read(&disk_path)?;
+        let content_type = match find_user_file(conn, owner, &name)? {
+            Some(f) => {
+                report.updated.push(disk_path.clone());
+                f.content_type
+            }
+            None => {
+                report.added.push(disk_path.clone());
+                get_content_type(&name).to_string()
+            }
+        };
+        let mut record = store_user_file(conn, storage, owner, &name, &content, &content_type)?;
+        record.uploaded_at = modified_secs(&metadata);
+        save_file_record(conn, &record)?;
+        // store_user_file уже удалил файл, если запись указывала на него; иначе это копия без записи
+        let _ = fs::remove_file(&disk_path);
+    }
+    Ok(())
+}
+
+// Счетчики ссылок строятся заново по таблицам files и trash; блобы без ссылок удаляются.
+// Строки blobs обновляются на месте, а не пересоздаются: удаление строки blobs удаляет
+// и извлеченный текст (search_texts), а он нужен всем блобам, на которые еще есть ссылки
+fn recount_blobs(
+    conn: &Connection,
+    storage: &StorageConfig,
+    report: &mut ReconcileReport,
+) -> std::result::Result<(), FileError> {
+    let _guard = BLOB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let prefix = format!("{}/", storage.blob_dir());
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("UPDATE blobs SET refcount = 0", [])?;
+    tx.execute(
+        "INSERT INTO blobs (sha256, size, refcount)
+         SELECT sha256, MAX(size), COUNT(*)
+         FROM (SELECT sha256, size, stored_path FROM files UNION ALL SELECT sha256, size, stored_path FROM trash)
+         WHERE substr(stored_path, 1, ?1) = ?2 GROUP BY sha256
+         ON CONFLICT (sha256) DO UPDATE SET size = excluded.size, refcount = excluded.refcount",
+        params![prefix.len() as i64, prefix],
+    )?;
+    tx.execute("DELETE FROM blobs WHERE refcount = 0", [])?;
+    tx.commit()?;
+
+    if !Path::new(&storage.blob_dir()).is_dir() {
+        return Ok(());
+    }
+    for shard in fs::read_dir(storage.blob_dir())? {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(shard.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Временные файлы незавершенных загрузок не трогаем
+            if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            let referenced: i64 = conn
+                .prepare_cached("SELECT COUNT(*) FROM blobs WHERE sha256 = ?1")?
+                .query_row(params![name], |row| row.get(0))?;
+            if referenced == 0 {
+                fs::remove_file(entry.path())?;
+                report.orphaned_blobs += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+
+    fn refcount(conn: &Connection, sha256: &str) -> Option<i64> {
+        conn.query_row("SELECT refcount FROM blobs WHERE sha256 = ?1", params![sha256], |row| row.get(0))
+            .ok()
+    }
+
+    #[test]
+    fn identical_files_share_a_blob() {
+        let db = TempDb::new("blobs");
+        let storage = TempStorage::new("blobs");
+        let conn = Connection::open(&db.0).unwrap();
+        let first = store_user_file(&conn, &storage.0, "bg", "a.txt", b"same", "text/plain").unwrap();
+        let second = store_user_file(&conn, &storage.0, "bg2", "docs/b.txt", b"same", "text/plain").unwrap();
+        assert_eq!(first.stored_path, second.stored_path);
+        assert_eq!(refcount(&conn, &first.sha256), Some(2));
+
+        // Удаление одной из копий оставляет содержимое другой
+        delete_file(&conn, &storage.0, &first).unwrap();
+        assert_eq!(refcount(&conn, &first.sha256), Some(1));
+        assert_eq!(fs::read(&second.stored_path).unwrap(), b"same");
+
+        // С последней ссылкой уходит и блоб
+        delete_file(&conn, &storage.0, &second).unwrap();
+        assert_eq!(refcount(&conn, &first.sha256), None);
+        assert!(!Path::new(&second.stored_path).exists());
+    }
+
+    #[test]
+    fn overwrite_releases_the_old_blob() {
+        let db = TempDb::new("overwrite");
+        let storage = TempStorage::new("overwrite");
+        let conn = Connection::open(&db.0).unwrap();
+        let old = store_user_file(&conn, &storage.0, "bg", "a.txt", b"old", "text/plain").unwrap();
+        let new = store_user_file(&conn, &storage.0, "bg", "a.txt", b"new", "text/plain").unwrap();
+        assert_eq!(new.id, old.id);
+        assert_eq!(refcount(&conn, &old.sha256), None);
+        assert!(!Path::new(&old.stored_path).exists());
+        assert_eq!(refcount(&conn, &new.sha256), Some(1));
+
+        // Перезапись тем же содержимым не меняет счетчик
+        store_user_file(&conn, &storage.0, "bg", "a.txt", b"new", "text/plain").unwrap();
+        assert_eq!(refcount(&conn, &new.sha256), Some(1));
+        assert_eq!(fs::read(&new.stored_path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn trash_restore_and_purge() {
+        let db = TempDb::new("trash");
+        let storage = TempStorage::new("trash");
+        let conn = Connection::open(&db.0).unwrap();
+        let file = store_user_file(&conn, &storage.0, "bg", "docs/a.txt", b"data", "text/plain").unwrap();
+        let copy = store_user_file(&conn, &storage.0, "bg", "b.txt", b"data", "text/plain").unwrap();
+
+        // В корзине файл держит свою ссылку на блоб
+        trash_file(&conn, &storage.0, &file).unwrap();
+        assert!(find_user_file(&conn, "bg", "docs/a.txt").unwrap().is_none());
+        assert_eq!(refcount(&conn, &file.sha256), Some(2));
+        let trash = list_trash(&conn, "bg").unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].file.path, "docs/a.txt");
+        assert!(list_trash(&conn, "bg2").unwrap().is_empty());
+        assert!(restore_from_trash(&conn, "bg2", trash[0].id).is_err());
+
+        // Восстановление возвращает файл на место и заново создает удаленную папку
+        conn.execute("DELETE FROM folders WHERE owner = 'bg'", []).unwrap();
+        assert_eq!(restore_from_trash(&conn, "bg", trash[0].id).unwrap(), "docs/a.txt");
+        assert!(folder_exists(&conn, "bg", "docs").unwrap());
+        let restored = find_user_file(&conn, "bg", "docs/a.txt").unwrap().unwrap();
+        assert_eq!(fs::read(&restored.stored_path).unwrap(), b"data");
+        assert!(list_trash(&conn, "bg").unwrap().is_empty());
+
+        // Занятое имя не перезаписывается
+        trash_file(&conn, &storage.0, &restored).unwrap();
+        store_user_file(&conn, &storage.0, "bg", "docs/a.txt", b"newer", "text/plain").unwrap();
+        let id = list_trash(&conn, "bg").unwrap()[0].id;
+        assert!(restore_from_trash(&conn, "bg", id).is_err());
+
+        // Окончательное удаление снимает ссылку; блоб живет, пока на него ссылается b.txt
+        assert_eq!(purge_trash_item(&conn, &storage.0, "bg", id).unwrap(), "docs/a.txt");
+        assert_eq!(refcount(&conn, &file.sha256), Some(1));
+        trash_file(&conn, &storage.0, &copy).unwrap();
+        assert_eq!(empty_trash(&conn, &storage.0, "bg").unwrap(), 1);
+        assert_eq!(refcount(&conn, &file.sha256), None);
+        assert!(!Path::new(&copy.stored_path).exists());
+    }
+
+    #[test]
+    fn expired_trash_is_purged() {
+        let db = TempDb::new("trash-expiry");
+        let storage = TempStorage::new("trash-expiry");
+        let conn = Connection::open(&db.0).unwrap();
+        let old = store_user_file(&conn, &storage.0, "bg", "old.txt", b"old", "text/plain").unwrap();
+        let recent = store_user_file(&conn, &storage.0, "bg", "recent.txt", b"recent", "text/plain").unwrap();
+        trash_file(&conn, &storage.0, &old).unwrap();
+        trash_file(&conn, &storage.0, &recent).unwrap();
+        conn.execute("UPDATE trash SET deleted_at = 100 WHERE path = 'old.txt'", []).unwrap();
+
+        assert_eq!(purge_expired_trash(&conn, &storage.0, 200).unwrap(), 1);
+        let trash = list_trash(&conn, "bg").unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].file.path, "recent.txt");
+        assert!(!Path::new(&old.stored_path).exists());
+        assert!(Path::new(&recent.stored_path).exists());
+    }
+
+    #[test]
+    fn used_bytes_counts_trash_and_uploads() {
+        let db = TempDb::new("used-bytes");
+        let storage = TempStorage::new("used-bytes");
+        let conn = Connection::open(&db.0).unwrap();
+        let file = store_user_file(&conn, &storage.0, "bg", "a.txt", b"12345", "text/plain").unwrap();
+        // Дедупликация квоту не увеличивает: копия считается полностью
+        store_user_file(&conn, &storage.0, "bg", "b.txt", b"12345", "text/plain").unwrap();
+        store_user_file(&conn, &storage.0, "bg2", "c.txt", b"123", "text/plain").unwrap();
+        assert_eq!(used_bytes(&conn, "bg").unwrap(), 10);
+
+        trash_file(&conn, &storage.0, &file).unwrap();
+        assert_eq!(used_bytes(&conn, "bg").unwrap(), 10);
+
+        // Незавершенная загрузка резервирует всю заявленную длину
+        let upload = crate::uploads::Upload {
+            id: "pending".to_string(),
+            owner: "bg".to_string(),
+            path: "big.bin".to_string(),
+            content_type: "application/octet-stream".to_string(),
+            length: 1000,
+            offset: 10,
+            created_at: 0,
+            expires_at: u64::MAX / 2,
+        };
+        crate::uploads::create_upload(&conn, &storage.0, &upload).unwrap();
+        assert_eq!(used_bytes(&conn, "bg").unwrap(), 1010);
+
+        empty_trash(&conn, &storage.0, "bg").unwrap();
+        assert_eq!(used_bytes(&conn, "bg").unwrap(), 1005);
+        assert_eq!(used_bytes(&conn, "bg2").unwrap(), 3);
+        assert_eq!(used_bytes(&conn, "nobody").unwrap(), 0);
+    }
+}
@@ -1,19 +1,72 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
-use std::error::Error;
 use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use rusqlite::Connection;
 
-use crate::db::{authenticate_user, register_user, user_exists};
-use crate::utils::{get_formatted_time, hash_password, log_to_file, parse_form_data};
+use crate::db::{
+    consume_reset_token, create_api_token, create_reset_token, delete_user_sessions, disable_totp, enable_totp,
+    find_reset_token, get_totp, list_api_tokens, revoke_api_token, set_totp_secret, store_recovery_codes,
+    update_totp_step, use_recovery_code,
+};
+use crate::archive::{write_archive, ArchiveEntry, ArchiveFormat, ChunkedWriter};
+use crate::auth::{authenticate, check_password, AuthOutcome, ALL_SCOPES, SCOPE_FILES_READ, SCOPE_FILES_WRITE, TOKEN_PREFIX};
+use crate::content_policy::{content_type_for, is_risky_type, read_head, ContentError};
+use crate::context::Context;
+use crate::files::{
+    base_name, copy_file, copy_folder, create_folder, empty_trash, find_file_record,
+    find_user_file, folder_created_at, folder_exists, join_path, list_all_folders, list_file_records, list_folder,
+    list_public_files, list_subtree, list_trash, list_visible_files, move_file, move_folder, normalize_dir,
+    parent_dir, purge_trash_item, restore_from_trash, store_user_file, store_user_file_from, trash_file,
+    trash_folder, used_bytes, validate_name, FileError, FileRecord, FileSort, UPLOAD_DIR,
+};
+use crate::multipart::{multipart_boundary, MultipartWriter, MULTIPART_OVERHEAD};
+use crate::policy::RegistrationPolicy;
+use crate::preview::{
+    has_thumbnail, read_text_preview, render_markdown, static_thumbnail, thumbnail, PreviewKind, PREVIEW_TEXT_BYTES,
+};
+use crate::scanner::{quarantine_file, ScanVerdict};
+use crate::search::{index_note, search, SearchHit, MATCH_END, MATCH_START, NOTE_FILE, SEARCH_LIMIT};
+use crate::store::{StoreError, User, UserSort};
+use crate::shares::{
+    create_share, find_share, list_shares, record_share_download, revoke_share, Share, SHARE_TOKEN_BYTES,
+};
+use crate::session::{complete_2fa, current_session, current_user, end_other_sessions, end_session, start_session};
+use crate::totp::{generate_secret, otpauth_uri, qr_svg, verify_code};
+use crate::uploads::{
+    create_upload, delete_upload, find_upload, lock_upload, part_path, set_upload_offset, temp_upload_path, Upload,
+    UPLOAD_ID_BYTES, UPLOAD_TTL_SECS,
+};
+use crate::utils::{
+    format_size, format_timestamp, get_content_type, get_formatted_time, get_header, get_timestamp, hash_password,
+    hash_password_salted, http_date, html_escape, json_escape, log_to_file, parse_form_data, parse_form_list,
+    random_token, verify_password_salted,
+};
+use crate::webdav::{
+    acquire_lock, dav_href, dav_path, dav_response, destination_path, drop_locks, file_etag, lock_response,
+    lock_timeout, locks_on, may_modify, multistatus, new_lock_token, parse_lockinfo, parse_propfind, parse_proppatch,
+    prop_name, propstat, refresh_lock, release_lock, submitted_tokens, DavLock, DavResource, DAV_METHODS, DAV_PREFIX,
+};
+
+// Сколько одноразовых кодов восстановления выдается при включении 2FA
+const RECOVERY_CODES_COUNT: usize = 10;
+// Длина кода восстановления в байтах (в hex вдвое больше символов)
+const RECOVERY_CODE_BYTES: usize = 10;
+// Пользователей на одной странице админ-панели
+const ADMIN_PAGE_SIZE: usize = 50;
+// Сколько строк показывает одна страница файлового менеджера
+const FILES_PAGE_SIZE: usize = 50;
+// Время жизни токена сброса пароля
+const RESET_TOKEN_TTL_SECS: u64 = 30 * 60;
 
 #[derive(Debug)]
 pub enum HttpError {
     Io(std::io::Error),
     Sqlite(rusqlite::Error),
+    Store(StoreError),
     Other(String),
 }
 /*
@@ -52,6 +105,23 @@ impl From<rusqlite::Error> for HttpError {
     }
 }
 
+impl From<StoreError> for HttpError {
+    fn from(err: StoreError) -> Self {
+        HttpError::Store(err)
+    }
+}
+
+// Ошибки файлового хранилища — это те же ошибки ввода-вывода и SQLite
+impl From<FileError> for HttpError {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::Io(err) => HttpError::Io(err),
+            FileError::Sqlite(err) => HttpError::Sqlite(err),
+            FileError::Invalid(message) => HttpError::Other(message),
+        }
+    }
+}
+
 impl From<String> for HttpError {
     fn from(err: String) -> Self {
         HttpError::Other(err)
@@ -63,6 +133,7 @@ impl std::fmt::Display for HttpError {
         match self {
             HttpError::Io(err) => write!(f, "IO error: {}", err),
             HttpError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+            HttpError::Store(err) => write!(f, "Store error: {}", err),
             HttpError::Other(err) => write!(f, "Error: {}", err),
         }
     }
@@ -70,50 +141,132 @@ impl std::fmt::Display for HttpError {
 
 impl std::error::Error for HttpError {}
 
-fn get_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
-}
-
-pub fn handle_connection(mut stream: TcpStream) -> Result<(), HttpError> {
+pub fn handle_connection(mut stream: TcpStream, ctx: &Context) -> Result<(), HttpError> {
     let mut buffer = [0; 8192];
     let bytes_read = stream.read(&mut buffer)?;
-    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
+    // Тело PATCH-запроса tus — двоичные данные, поэтому нужны исходные байты, а не строка
+    let raw = &buffer[..bytes_read];
+    let request = String::from_utf8_lossy(raw);
     let client_ip = stream.peer_addr()?.ip().to_string();
     let path = request
         .lines()
         .next()
         .and_then(|line| line.split_whitespace().nth(1))
         .unwrap_or("/");
+    // Параметры запроса (?token=...) отделяем от маршрута
+    let (route, query) = path.split_once('?').unwrap_or((path, ""));
 
     let log_entry = format!("[{}] {} requested {} at {}", client_ip, path, path, get_formatted_time());
     log_to_file(&log_entry)?;
 
-    if path.starts_with("/static/") {
-        let file_path = &path[1..];
+    if route.starts_with("/static/") {
+        let file_path = &route[1..];
+        // ".." вывел бы за пределы static/, например к личным файлам пользователей
+        if file_path.split(['/', '\\']).any(|segment| segment == "..") {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
         return serve_static(file_path, &mut stream);
     }
 
+    // У WebDAV свои методы (PROPFIND, MKCOL, ...), поэтому он выбирается по адресу, а не по методу
+    if route.strip_prefix(DAV_PREFIX).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')) {
+        return handle_webdav(&request, raw, &client_ip, route, ctx, &mut stream);
+    }
+
     //POST - request
     if request.starts_with("POST /register") {
-        return handle_register(&request, &mut stream);
+        return handle_register(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /login/2fa") {
+        return handle_login_2fa(&request, &client_ip, ctx, &mut stream);
     } else if request.starts_with("POST /login") {
-        return handle_login(&request, &mut stream);
+        return handle_login(&request, &client_ip, ctx, &mut stream);
     } else if request.starts_with("POST /save") {
-        return handle_save(&request, &mut stream);
+        return handle_save(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /files/mkdir") {
+        return handle_file_action(&request, "mkdir", ctx, &mut stream);
+    } else if request.starts_with("POST /files/rename") {
+        return handle_file_action(&request, "rename", ctx, &mut stream);
+    } else if request.starts_with("POST /files/move") {
+        return handle_file_action(&request, "move", ctx, &mut stream);
+    } else if request.starts_with("POST /files/delete") {
+        return handle_file_action(&request, "delete", ctx, &mut stream);
+    } else if request.starts_with("POST /files/trash/restore") {
+        return handle_trash_action(&request, "restore", ctx, &mut stream);
+    } else if request.starts_with("POST /files/trash/purge") {
+        return handle_trash_action(&request, "purge", ctx, &mut stream);
+    } else if request.starts_with("POST /files/trash/empty") {
+        return handle_trash_action(&request, "empty", ctx, &mut stream);
+    } else if request.starts_with("POST /files/share") {
+        return handle_share_create(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /files/unshare") {
+        return handle_file_action(&request, "unshare", ctx, &mut stream);
+    } else if request.starts_with("POST /s/") {
+        return handle_shared_file(&request, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("PUT /api/files/") {
+        return handle_api_put_file(&request, raw, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("DELETE /api/files/") {
+        return handle_api_delete_path(&request, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("DELETE /files/") {
+        return handle_api_delete_file(&request, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("OPTIONS /api/uploads") {
+        return handle_tus_options(&mut stream);
+    } else if request.starts_with("POST /api/uploads") {
+        return handle_tus_create(&request, &client_ip, ctx, &mut stream);
+    } else if request.starts_with("HEAD /api/uploads/") {
+        return handle_tus_head(&request, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("PATCH /api/uploads/") {
+        return handle_tus_patch(&request, raw, &client_ip, route, ctx, &mut stream);
+    } else if request.starts_with("DELETE /api/uploads/") {
+        return handle_tus_delete(&request, &client_ip, route, ctx, &mut stream);
     } else if request.starts_with("POST /upload") {
-        return handle_upload(&request, &mut stream);
+        return handle_upload(&request, raw, &client_ip, ctx, &mut stream);
+    } else if request.starts_with("POST /password") {
+        return handle_change_password(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /reset/confirm") {
+        return handle_reset_confirm(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /reset") {
+        return handle_reset_request(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /settings/tokens/revoke") {
+        return handle_token_revoke(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /settings/tokens") {
+        return handle_token_create(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /2fa/enable") {
+        return handle_2fa_enable(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /admin/unlock") {
+        return handle_admin_unlock(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /admin/disable-2fa") {
+        return handle_admin_disable_2fa(&request, ctx, &mut stream);
+    } else if request.starts_with("POST /admin/set-disabled") {
+        return handle_admin_set_disabled(&request, ctx, &mut stream);
     }
 
     //GET - request
-    match path {
+    match route {
         "/" => serve_file("index.html", &mut stream),
         "/about" => serve_file("about.html", &mut stream),
-        "/register" => serve_file("register.html", &mut stream),
-        "/files" => list_files(&mut stream), //новый маршрут для отображения файлов
+        "/register" => render_register_form(&ctx.registration, &HashMap::new(), &[], &mut stream),
+        "/files" => handle_file_manager(&request, query, ctx, &mut stream), //новый маршрут для отображения файлов
+        "/files/archive" => handle_archive(&request, query, ctx, &mut stream),
+        "/files/trash" => handle_trash_page(&request, query, ctx, &mut stream),
         "/upload" => serve_file("upload.html", &mut stream),
+        "/login" => serve_file("login.html", &mut stream),
+        "/logout" => handle_logout(&request, ctx, &mut stream),
+        "/2fa" => handle_2fa_setup(&request, ctx, &mut stream),
+        "/password" => handle_change_password_form(&request, ctx, &mut stream),
+        "/reset" => serve_file("reset_request.html", &mut stream),
+        "/reset/confirm" => handle_reset_confirm_form(query, &mut stream),
+        "/settings/tokens" => handle_tokens_page(&request, ctx, None, &mut stream),
+        "/api/files" => handle_api_list_files(&request, &client_ip, ctx, &mut stream),
+        "/search" => handle_search_page(&request, query, ctx, &mut stream),
+        "/api/search" => handle_api_search(&request, &client_ip, query, ctx, &mut stream),
+        "/admin" => handle_admin_panel(&request, query, ctx, &mut stream),
+        r if r.starts_with("/download/") => handle_download(&request, &client_ip, r, ctx, &mut stream),
+        r if r.starts_with("/thumb/static/") => handle_static_thumbnail(r, ctx, &mut stream),
+        r if r.starts_with("/thumb/") => handle_thumbnail(&request, &client_ip, r, ctx, &mut stream),
+        r if r.starts_with("/preview/") => handle_preview(&request, &client_ip, r, ctx, &mut stream),
+        r if r.starts_with("/s/") => handle_shared_file(&request, &client_ip, r, ctx, &mut stream),
         _=> {
             //возвращаем 404 для неизвестных маршрутов
             let response = not_found_response();
@@ -128,8 +281,17 @@ fn serve_static(path: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
     match std::fs::read(path) {
         Ok(contents) => {
             let content_type = get_content_type(path);
+            // Общие загруженные файлы лежат в static/ и отдаются отсюда же — с теми же предосторожностями
+            let mut extra_headers = String::new();
+            if path.starts_with(&format!("{}/", UPLOAD_DIR)) {
+                extra_headers.push_str("X-Content-Type-Options: nosniff\r\n");
+                if is_risky_type(content_type) {
+                    extra_headers.push_str(&attachment_header(base_name(path)));
+                }
+            }
             let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n",
+                "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nContent-Type: {}\r\n\r\n",
+                extra_headers,
                 contents.len(),
                 content_type
             );
@@ -147,10 +309,16 @@ fn serve_static(path: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
 }
 
 fn serve_file(filename: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
+    serve_file_with_headers(filename, "", stream)
+}
+
+// extra_headers — дополнительные заголовки, каждый с завершающим \r\n (например, Set-Cookie)
+fn serve_file_with_headers(filename: &str, extra_headers: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
     match std::fs::read_to_string(filename) {
         Ok(contents) => {
             let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
+                "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
+                extra_headers,
                 contents.len(),
                 contents
             );
@@ -166,107 +334,2179 @@ fn serve_file(filename: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
     Ok(())
 }
 
-fn list_files(stream: &mut TcpStream) -> Result<(), HttpError> {
-    let mut files_list = String::from("<table><tr><th>Имя файла</th><th>Размер (байт)</th><th>Изменен</th></tr>");
-    // PATH FOLDER static/
-    let static_dir = std::path::Path::new("static");
-
-    if static_dir.exists() {
-        //read folder
-        for entry in fs::read_dir(static_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            //get metadata (size, time, change time)
-            let metadata = entry.metadata()?;
-            let file_name = path.strip_prefix("static/").unwrap_or(&path).to_string_lossy();
-            //file size in bytes
-            let size = metadata.len();
-            //time last change
-            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
-            let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
-            let modified_time = get_formatted_time();
-
-            // Добавляем строку таблицы с именем, размером и временем
-            files_list.push_str(&format!(
-                "<tr><td><a href=\"/static/{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
-                file_name, file_name, size, modified_time
-            ));
+// Файловый менеджер: содержимое папки ?dir= в личном пространстве пользователя.
+// Администратор может открыть пространство другого пользователя через ?user=
+fn handle_file_manager(request: &str, query: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let params = parse_form_data(query);
+    let conn = ctx.pool.get()?;
+    let (owner, is_self) = match file_manager_owner(ctx, &conn, request, form_value(&params, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let mut view = ListingView::from_params(&params);
+    // Несуществующая папка открывается как корень
+    if !folder_exists(&conn, &owner.username, &view.dir)? {
+        view.dir.clear();
+    }
+    render_file_manager(ctx, &conn, &owner, is_self, &view, None, stream)
+}
+
+// Что показывает файловый менеджер: папка ?dir=, порядок ?sort=name|size|date&order=asc|desc, страница ?page=N
+// и вид ?view=list|grid (таблица или плитка с миниатюрами)
+struct ListingView {
+    dir: String,
+    sort: FileSort,
+    descending: bool,
+    page: usize,
+    grid: bool,
+}
+
+impl ListingView {
+    fn from_params(params: &HashMap<String, String>) -> ListingView {
+        ListingView {
+            // Некорректный путь открывается как корень
+            dir: normalize_dir(form_value(params, "dir")).unwrap_or_default(),
+            sort: params
+                .get("sort")
+                .and_then(|s| FileSort::from_param(s))
+                .unwrap_or(FileSort::Name),
+            descending: params.get("order").map(String::as_str) == Some("desc"),
+            page: params.get("page").and_then(|p| p.parse::<usize>().ok()).unwrap_or(1),
+            grid: params.get("view").map(String::as_str) == Some("grid"),
         }
     }
-else {
-    // Если папка отсутствует, показываем сообщение
-    files_list.push_str("<tr><td colspan=\"3\">Папка static отсутствует</td></tr>");
+
+    // Параметры порядка и вида для ссылок; при переходе в другую папку страница сбрасывается
+    fn query(&self) -> String {
+        self.query_as(self.grid)
+    }
+
+    // То же для ссылки на другой вид
+    fn query_as(&self, grid: bool) -> String {
+        format!(
+            "&sort={}&order={}&view={}",
+            self.sort.param(),
+            if self.descending { "desc" } else { "asc" },
+            if grid { "grid" } else { "list" }
+        )
+    }
 }
-    files_list.push_str("</table>");
-    // Формируем HTML-страницу
-    let body = format!(
-        r#"<!DOCTYPE html>
-<html lang="ru">
-<head>
-    <meta charset="UTF-8">
-    <title>Файловый менеджер</title>
-    <link rel="stylesheet" href="/static/styles.css">
-    <style>
-        table {{ border-collapse: collapse; width: 100%; }}
-        th, td {{ border: 1px solid #ddd; padding: 8px; text-align: left; }}
-        th {{ background-color: #f2f2f2; }}
-    </style>
-</head>
-<body>
-    <h1>Файловый менеджер</h1>
-    <p><a href="/upload">Загрузить файл</a> | <a href="/">На главную</a></p>
+
+// Чье пространство открыто: свое или (только для администратора) указанного пользователя.
+// Возвращает владельца и признак "свое"; если доступа нет, сам отправляет ответ и возвращает None
+fn file_manager_owner(
+    ctx: &Context,
+    conn: &Connection,
+    request: &str,
+    requested: &str,
+    stream: &mut TcpStream,
+) -> Result<Option<(User, bool)>, HttpError> {
+    let viewer = match current_user(conn, request)? {
+        Some(username) => ctx.users.find(&username)?.ok_or(StoreError::NotFound)?,
+        None => {
+            let response = "HTTP/1.1 303 See Other\r\nLocation: /login\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes())?;
+            stream.flush()?;
+            return Ok(None);
+        }
+    };
+    if requested.is_empty() || requested == viewer.username {
+        return Ok(Some((viewer, true)));
+    }
+    if !viewer.is_admin() {
+        send_html(stream, "403 Forbidden", &page("Доступ запрещен", "<h1>403 — Доступ запрещен</h1><p><a href=\"/files\">Мои файлы</a></p>"))?;
+        return Ok(None);
+    }
+    match ctx.users.find(requested)? {
+        Some(owner) => Ok(Some((owner, false))),
+        None => {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            Ok(None)
+        }
+    }
+}
+
+// Ссылка на папку в файловом менеджере
+fn files_link(dir: &str, owner: &User, is_self: bool) -> String {
+    let mut link = format!("/files?dir={}", urlencoding::encode(dir));
+    if !is_self {
+        link.push_str(&format!("&user={}", urlencoding::encode(&owner.username)));
+    }
+    link
+}
+
+fn render_file_manager(
+    ctx: &Context,
+    conn: &Connection,
+    owner: &User,
+    is_self: bool,
+    view: &ListingView,
+    error: Option<&str>,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let dir = view.dir.as_str();
+    let mut listing = list_folder(conn, &owner.username, dir)?;
+    view.sort.apply(&mut listing, view.descending);
+    let folder_link = |dir: &str| format!("{}{}", files_link(dir, owner, is_self), view.query());
+    let user_param = if is_self { String::new() } else { format!("&user={}", urlencoding::encode(&owner.username)) };
+    let archive_link = |folder: &str| format!("/files/archive?folder={}{}&format=zip", urlencoding::encode(folder), user_param);
+
+    // Скрытые поля, по которым действия понимают, в какой папке и чьем пространстве выполняются;
+    // порядок сортировки сохраняется после действия
+    let mut hidden = format!(
+        r#"<input type="hidden" name="dir" value="{}"><input type="hidden" name="sort" value="{}"><input type="hidden" name="order" value="{}"><input type="hidden" name="view" value="{}">"#,
+        html_escape(dir),
+        view.sort.param(),
+        if view.descending { "desc" } else { "asc" },
+        if view.grid { "grid" } else { "list" }
+    );
+    if !is_self {
+        hidden.push_str(&format!(r#"<input type="hidden" name="user" value="{}">"#, html_escape(&owner.username)));
+    }
+
+    // Хлебные крошки: корень / a / b
+    let mut location = format!(r#"<a href="{}">{}</a>"#, html_escape(&folder_link("")), html_escape(&owner.username));
+    let mut current = String::new();
+    for segment in dir.split('/').filter(|segment| !segment.is_empty()) {
+        current = join_path(&current, segment);
+        location.push_str(&format!(
+            r#" / <a href="{}">{}</a>"#,
+            html_escape(&folder_link(&current)),
+            html_escape(segment)
+        ));
+    }
+
+    let all_folders = list_all_folders(conn, &owner.username)?;
+    let mut shares: HashMap<i64, Vec<Share>> = HashMap::new();
+    for share in list_shares(conn, &owner.username)? {
+        shares.entry(share.file_id).or_default().push(share);
+    }
+    let now = get_timestamp();
+    // Ссылки на файл с кнопками отзыва и форма создания новой
+    let share_cell = |file: &FileRecord| {
+        let mut cell = String::new();
+        for share in shares.get(&file.id).map(Vec::as_slice).unwrap_or(&[]) {
+            let mut state = match share.expires_at {
+                _ if !share.is_active(now) => "недействительна".to_string(),
+                Some(at) => format!("до {}", format_timestamp(at)),
+                None => "бессрочная".to_string(),
+            };
+            match share.max_downloads {
+                Some(max) => state.push_str(&format!(", скачиваний {} из {}", share.downloads, max)),
+                None => state.push_str(&format!(", скачиваний {}", share.downloads)),
+            }
+            if share.password_hash.is_some() {
+                state.push_str(", с паролем");
+            }
+            cell.push_str(&format!(
+                r#"<div>🔗 {} <form method="POST" action="/files/unshare">{}<input type="hidden" name="share" value="{}"><button type="submit">Отозвать</button></form></div>"#,
+                state, hidden, share.id
+            ));
+        }
+        let expiry_options: String = SHARE_EXPIRY_OPTIONS
+            .iter()
+            .map(|(hours, title)| format!(r#"<option value="{}">{}</option>"#, hours, title))
+            .collect();
+        cell.push_str(&format!(
+            r#"<details><summary>Поделиться</summary><form method="POST" action="/files/share">{}<input type="hidden" name="id" value="{}">
+            Срок: <select name="expires">{}</select>
+            Скачиваний: <input type="number" name="max_downloads" min="1" placeholder="без ограничения">
+            Пароль: <input type="password" name="password" placeholder="необязательно">
+            <button type="submit">Создать ссылку</button></form></details>"#,
+            hidden, file.id, expiry_options
+        ));
+        cell
+    };
+    // Варианты для перемещения; папку нельзя переместить в нее саму и в ее подпапки
+    let move_options = |exclude: Option<&str>| {
+        let mut options = String::from(r#"<option value="">/</option>"#);
+        for folder in &all_folders {
+            if let Some(exclude) = exclude {
+                if folder == exclude || folder.starts_with(&format!("{}/", exclude)) {
+                    continue;
+                }
+            }
+            options.push_str(&format!(r#"<option value="{}">/{}</option>"#, html_escape(folder), html_escape(folder)));
+        }
+        options
+    };
+    let actions = |field: &str, value: &str, name: &str, exclude: Option<&str>, confirm: &str| {
+        format!(
+            r#"<form method="POST" action="/files/rename">{hidden}<input type="hidden" name="{field}" value="{value}"><input name="new_name" value="{name}" required><button type="submit">Переименовать</button></form>
+            <form method="POST" action="/files/move">{hidden}<input type="hidden" name="{field}" value="{value}"><select name="target">{options}</select><button type="submit">Переместить</button></form>
+            <form method="POST" action="/files/delete" onsubmit="return confirm('{confirm}')">{hidden}<input type="hidden" name="{field}" value="{value}"><button type="submit">Удалить</button></form>"#,
+            hidden = hidden,
+            field = field,
+            value = value,
+            name = html_escape(name),
+            options = move_options(exclude),
+            confirm = confirm
+        )
+    };
+
+    // На странице FILES_PAGE_SIZE строк: сначала папки, затем файлы.
+    // Строки с формами действий собираются только для видимой страницы
+    let total = listing.folders.len() + listing.files.len();
+    let pages = total.div_ceil(FILES_PAGE_SIZE).max(1);
+    let page_num = view.page.clamp(1, pages);
+    let start = (page_num - 1) * FILES_PAGE_SIZE;
+    let page_folders: Vec<&String> = listing.folders.iter().skip(start).take(FILES_PAGE_SIZE).collect();
+    let page_files = listing
+        .files
+        .iter()
+        .skip(start.saturating_sub(listing.folders.len()))
+        .take(FILES_PAGE_SIZE - page_folders.len());
+
+    let mut rows = Vec::with_capacity(FILES_PAGE_SIZE);
+    for folder in page_folders {
+        if view.grid {
+            rows.push(format!(
+                r#"<div class="card"><a href="{}"><div class="thumb">📁</div>{}</a><div class="meta">папка</div></div>"#,
+                html_escape(&folder_link(folder)),
+                html_escape(base_name(folder))
+            ));
+            continue;
+        }
+        rows.push(format!(
+            r#"<tr><td>📁 <a href="{}">{}</a></td><td>—</td><td>папка</td><td></td><td></td><td><a href="{}">ZIP</a> {}</td></tr>"#,
+            html_escape(&folder_link(folder)),
+            html_escape(base_name(folder)),
+            html_escape(&archive_link(folder)),
+            actions("folder", &html_escape(folder), base_name(folder), Some(folder), "Переместить папку со всем содержимым в корзину?")
+        ));
+    }
+    for file in page_files {
+        if view.grid {
+            // Плитка: миниатюра для изображений, значок для остальных; щелчок открывает предпросмотр
+            let picture = if has_thumbnail(file) {
+                format!(r#"<img src="/thumb/{}" alt="" loading="lazy">"#, file.id)
+            } else {
+                PreviewKind::of(&file.content_type).icon().to_string()
+            };
+            rows.push(format!(
+                r#"<div class="card"><a href="/preview/{}"><div class="thumb">{}</div>{}</a><div class="meta"><input type="checkbox" name="id" value="{}" form="archive"> {}</div></div>"#,
+                file.id,
+                picture,
+                html_escape(base_name(&file.path)),
+                file.id,
+                format_size(file.size)
+            ));
+            continue;
+        }
+        let preview_link = match PreviewKind::of(&file.content_type) {
+            PreviewKind::Unavailable => String::new(),
+            _ => format!(r#" <a href="/preview/{}" title="Просмотр">👁</a>"#, file.id),
+        };
+        rows.push(format!(
+            r#"<tr><td><input type="checkbox" name="id" value="{}" form="archive"> <a href="{}">{}</a>{}</td><td title="{} байт">{}</td><td>{}</td><td>{}</td><td><code>{}</code></td><td>{}{}</td></tr>"#,
+            file.id,
+            html_escape(&file_url(file)),
+            html_escape(base_name(&file.path)),
+            preview_link,
+            file.size,
+            format_size(file.size),
+            html_escape(&file.content_type),
+            format_timestamp(file.uploaded_at),
+            &file.sha256[..12.min(file.sha256.len())],
+            actions("id", &file.id.to_string(), base_name(&file.path), None, "Переместить файл в корзину?"),
+            share_cell(file)
+        ));
+    }
+
+    let mut entries = String::new();
+    if !dir.is_empty() {
+        let parent = html_escape(&folder_link(parent_dir(dir)));
+        entries.push_str(&if view.grid {
+            format!(r#"<div class="card"><a href="{}"><div class="thumb">⬆</div>..</a></div>"#, parent)
+        } else {
+            format!(r#"<tr><td><a href="{}">..</a></td><td colspan="5"></td></tr>"#, parent)
+        });
+    }
+    for row in &rows {
+        entries.push_str(row);
+    }
+    if total == 0 {
+        entries.push_str(if view.grid { "<p>Папка пуста</p>" } else { r#"<tr><td colspan="6">Папка пуста</td></tr>"# });
+    }
+
+    // Заголовок сортируемого столбца: повторный щелчок меняет направление
+    let sort_header = |column: FileSort, title: &str| {
+        let (next_order, mark) = match (column == view.sort, view.descending) {
+            (true, false) => ("desc", " ▲"),
+            (true, true) => ("asc", " ▼"),
+            (false, _) => ("asc", ""),
+        };
+        format!(
+            r#"<th><a href="{}&sort={}&order={}">{}</a>{}</th>"#,
+            html_escape(&files_link(dir, owner, is_self)), column.param(), next_order, title, mark
+        )
+    };
+    let listing_html = if view.grid {
+        format!(r#"<div class="grid">{}</div>"#, entries)
+    } else {
+        format!(
+            "<table><tr>{}{}<th>Тип</th>{}<th>SHA-256</th><th>Действия</th></tr>{}</table>",
+            sort_header(FileSort::Name, "Имя"),
+            sort_header(FileSort::Size, "Размер"),
+            sort_header(FileSort::Date, "Изменен"),
+            entries
+        )
+    };
+    let view_link = |grid: bool| format!("{}{}", files_link(dir, owner, is_self), view.query_as(grid));
+    let views = if view.grid {
+        format!(r#"Вид: <a href="{}">таблица</a> | <strong>плитка</strong>"#, html_escape(&view_link(false)))
+    } else {
+        format!(r#"Вид: <strong>таблица</strong> | <a href="{}">плитка</a>"#, html_escape(&view_link(true)))
+    };
+
+    let page_link = |page: usize| format!("{}{}&page={}", files_link(dir, owner, is_self), view.query(), page);
+    let mut pager = String::new();
+    if pages > 1 {
+        pager = format!("Страница {} из {}", page_num, pages);
+        if page_num > 1 {
+            pager.push_str(&format!(r#" | <a href="{}">Назад</a>"#, html_escape(&page_link(page_num - 1))));
+        }
+        if page_num < pages {
+            pager.push_str(&format!(r#" | <a href="{}">Вперед</a>"#, html_escape(&page_link(page_num + 1))));
+        }
+    }
+
+    // Общие файлы из старого публичного каталога — только для просмотра
+    let public = list_public_files(conn)?;
+    let public_html = if public.is_empty() {
+        String::new()
+    } else {
+        let items: String = public
+            .iter()
+            .map(|file| {
+                format!(
+                    r#"<li><a href="{}">{}</a> ({})</li>"#,
+                    html_escape(&file_url(file)),
+                    html_escape(&file.path),
+                    format_size(file.size)
+                )
+            })
+            .collect();
+        format!("<h3>Общие файлы</h3><ul>{}</ul>", items)
+    };
+
+    let error_html = match error {
+        Some(error) => format!(r#"<p class="errors">{}</p>"#, html_escape(error)),
+        None => String::new(),
+    };
+    let user_query = if is_self { String::new() } else { format!("?user={}", urlencoding::encode(&owner.username)) };
+    let quota = format!(
+        r#"Использовано {} из {} | <a href="/files/trash{}">Корзина ({})</a>"#,
+        format_size(used_bytes(conn, &owner.username)?),
+        format_size(ctx.storage.quota_for(owner)),
+        html_escape(&user_query),
+        list_trash(conn, &owner.username)?.len()
+    );
+    // Отмеченные файлы или, если ничего не отмечено, вся текущая папка
+    let archive = format!(
+        r#"<form id="archive" action="/files/archive" method="get">
+    <input type="hidden" name="folder" value="{}">{}
+    <select name="format"><option value="zip">ZIP</option><option value="tgz">tar.gz</option></select>
+    <button type="submit">Скачать архивом</button> (отмеченные файлы или всю папку)
+</form>"#,
+        html_escape(dir),
+        if is_self { String::new() } else { format!(r#"<input type="hidden" name="user" value="{}">"#, html_escape(&owner.username)) }
+    );
+    // Загружать можно только в свое пространство
+    let upload = if is_self {
+        format!(
+            r#"<h3>Загрузить новый файл</h3>
+<form action="/upload" method="post" enctype="multipart/form-data">
     {}
-</body>
-</html>"#,
-        files_list
+    <input type="file" name="file">
+    <button type="submit">Загрузить</button>
+</form>"#,
+            hidden
+        )
+    } else {
+        String::new()
+    };
+
+    let html = std::fs::read_to_string("file_manager.html")?
+        .replace("{{LOCATION}}", &location)
+        .replace("{{ERROR}}", &error_html)
+        .replace("{{QUOTA}}", &quota)
+        .replace("{{VIEWS}}", &views)
+        .replace("{{LISTING}}", &listing_html)
+        .replace("{{PAGER}}", &pager)
+        .replace("{{ARCHIVE}}", &archive)
+        .replace("{{HIDDEN}}", &hidden)
+        .replace("{{UPLOAD}}", &upload)
+        .replace("{{PUBLIC}}", &public_html);
+    let status = if error.is_some() { "400 Bad Request" } else { "200 OK" };
+    send_html(stream, status, &html)
+}
+
+// Элемент, над которым выполняется действие
+enum FileEntry {
+    File(FileRecord),
+    Folder(String),
+}
+
+// Файл (поле id) или папка (поле folder) из формы; чужие файлы не находятся
+fn selected_entry(conn: &Connection, owner: &str, form_data: &HashMap<String, String>) -> Result<FileEntry, FileError> {
+    if let Some(id) = form_data.get("id") {
+        return match id.parse::<i64>().ok().map(|id| find_file_record(conn, id)).transpose()? {
+            Some(Some(file)) if file.owner.as_deref() == Some(owner) => Ok(FileEntry::File(file)),
+            _ => Err(FileError::Invalid("Файл не найден.".to_string())),
+        };
+    }
+    match normalize_dir(form_value(form_data, "folder")) {
+        Ok(folder) if !folder.is_empty() && folder_exists(conn, owner, &folder)? => Ok(FileEntry::Folder(folder)),
+        _ => Err(FileError::Invalid("Папка не найдена.".to_string())),
+    }
+}
+
+// Выполняет действие файлового менеджера и возвращает его описание для журнала
+fn apply_file_action(
+    conn: &Connection,
+    ctx: &Context,
+    owner: &str,
+    action: &str,
+    dir: &str,
+    form_data: &HashMap<String, String>,
+) -> Result<String, FileError> {
+    match action {
+        "mkdir" => {
+            let name = form_value(form_data, "name");
+            validate_name(name).map_err(FileError::Invalid)?;
+            let path = join_path(dir, name);
+            create_folder(conn, owner, &path)?;
+            Ok(format!("created folder {}", path))
+        }
+        "rename" | "move" => {
+            let entry = selected_entry(conn, owner, form_data)?;
+            let old_path = match &entry {
+                FileEntry::File(file) => file.path.clone(),
+                FileEntry::Folder(folder) => folder.clone(),
+            };
+            let new_path = if action == "rename" {
+                let new_name = form_value(form_data, "new_name");
+                validate_name(new_name).map_err(FileError::Invalid)?;
+                join_path(parent_dir(&old_path), new_name)
+            } else {
+                let target = normalize_dir(form_value(form_data, "target")).map_err(FileError::Invalid)?;
+                join_path(&target, base_name(&old_path))
+            };
+            if let FileEntry::File(_) = &entry {
+                // Переименование не должно обходить список разрешенных типов
+                ctx.content_policy
+                    .check_type(&new_path)
+                    .map_err(|e| FileError::Invalid(e.message().to_string()))?;
+            }
+            match &entry {
+                FileEntry::File(file) => move_file(conn, owner, file, &new_path)?,
+                FileEntry::Folder(folder) => move_folder(conn, owner, folder, &new_path)?,
+            }
+            Ok(format!("moved {} to {}", old_path, new_path))
+        }
+        "delete" => match selected_entry(conn, owner, form_data)? {
+            FileEntry::File(file) => {
+                trash_file(conn, &ctx.storage, &file)?;
+                Ok(format!("moved file {} to trash", file.path))
+            }
+            FileEntry::Folder(folder) => {
+                let count = trash_folder(conn, owner, &folder)?;
+                Ok(format!("moved folder {} with {} files to trash", folder, count))
+            }
+        },
+        "unshare" => {
+            let id = form_value(form_data, "share").parse::<i64>().unwrap_or(0);
+            if !revoke_share(conn, owner, id)? {
+                return Err(FileError::Invalid("Ссылка не найдена.".to_string()));
+            }
+            Ok(format!("revoked share link {}", id))
+        }
+        _ => Err(FileError::Invalid("Неизвестное действие.".to_string())),
+    }
+}
+
+// POST /files/mkdir, /files/rename, /files/move, /files/delete
+fn handle_file_action(request: &str, action: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let conn = ctx.pool.get()?;
+    let (owner, is_self) = match file_manager_owner(ctx, &conn, request, form_value(&form_data, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let viewer = current_user(&conn, request)?.unwrap_or_default();
+    let mut view = ListingView::from_params(&form_data);
+
+    match apply_file_action(&conn, ctx, &owner.username, action, &view.dir, &form_data) {
+        Ok(description) => {
+            let log_entry = format!(
+                "File manager: {} {} in space of {} at {}",
+                viewer, description, owner.username, get_formatted_time()
+            );
+            log_to_file(&log_entry)?;
+            let response = format!(
+                "HTTP/1.1 303 See Other\r\nLocation: {}{}\r\nContent-Length: 0\r\n\r\n",
+                files_link(&view.dir, &owner, is_self),
+                view.query()
+            );
+            stream.write_all(response.as_bytes())?;
+            stream.flush()?;
+            Ok(())
+        }
+        Err(FileError::Invalid(message)) => {
+            if !folder_exists(&conn, &owner.username, &view.dir)? {
+                view.dir.clear();
+            }
+            render_file_manager(ctx, &conn, &owner, is_self, &view, Some(&message), stream)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// GET /files/archive?folder=...&id=...&format=zip|tgz — папка или отмеченные файлы одним архивом.
+// Архив собирается на лету и отдается частями (chunked), без временных файлов
+fn handle_archive(request: &str, query: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let params = parse_form_data(query);
+    let conn = ctx.pool.get()?;
+    let (owner, _) = match file_manager_owner(ctx, &conn, request, form_value(&params, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let format = ArchiveFormat::from_param(form_value(&params, "format")).unwrap_or(ArchiveFormat::Zip);
+    let folder = match normalize_dir(form_value(&params, "folder")) {
+        Ok(folder) if folder_exists(&conn, &owner.username, &folder)? => folder,
+        _ => {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
+    };
+    // Имена в архиве — относительно папки, в которой сделан выбор
+    let relative = |path: &str| path.strip_prefix(&format!("{}/", folder)).unwrap_or(path).to_string();
+
+    let ids = parse_form_list(query, "id");
+    let mut entries = Vec::new();
+    let archive_name;
+    if ids.is_empty() {
+        // Вся папка вместе с вложенными, включая пустые папки
+        archive_name = if folder.is_empty() { owner.username.clone() } else { base_name(&folder).to_string() };
+        let listing = list_subtree(&conn, &owner.username, &folder)?;
+        let now = get_timestamp();
+        entries.push(ArchiveEntry { name: archive_name.clone(), source: None, modified: now });
+        for path in &listing.folders {
+            entries.push(ArchiveEntry { name: format!("{}/{}", archive_name, relative(path)), source: None, modified: now });
+        }
+        for file in &listing.files {
+            entries.push(ArchiveEntry {
+                name: format!("{}/{}", archive_name, relative(&file.path)),
+                source: Some(file.stored_path.clone().into()),
+                modified: file.uploaded_at,
+            });
+        }
+    } else {
+        // Только файлы самого владельца: чужой id в запросе означает 404, а не пропуск
+        archive_name = "files".to_string();
+        for id in &ids {
+            let file = match id.parse::<i64>() {
+                Ok(id) => find_file_record(&conn, id)?,
+                Err(_) => None,
+            };
+            match file.filter(|file| file.owner.as_deref() == Some(owner.username.as_str())) {
+                Some(file) => entries.push(ArchiveEntry {
+                    name: relative(&file.path),
+                    source: Some(file.stored_path.into()),
+                    modified: file.uploaded_at,
+                }),
+                None => {
+                    stream.write_all(not_found_response().as_bytes())?;
+                    stream.flush()?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    let viewer = current_user(&conn, request)?.unwrap_or_default();
+    // Соединение с базой не держим, пока архив уходит клиенту
+    drop(conn);
+
+    let log_entry = format!(
+        "File manager: {} downloaded {} entries of {} as {} at {}",
+        viewer, entries.len(), owner.username, format.extension(), get_formatted_time()
     );
+    log_to_file(&log_entry)?;
 
-    // Формируем HTTP-ответ
     let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
-        body.len(),
-        body
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n{}Transfer-Encoding: chunked\r\nCache-Control: private\r\n\r\n",
+        format.content_type(),
+        attachment_header(&format!("{}.{}", archive_name, format.extension()))
     );
     stream.write_all(response.as_bytes())?;
+    // Буфер собирает мелкие записи заголовков в части разумного размера
+    let body = BufWriter::with_capacity(64 * 1024, ChunkedWriter::new(&mut *stream));
+    let body = write_archive(format, &entries, body)?;
+    body.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
+// Content-Disposition для скачивания: ASCII-имя для старых клиентов и полное в filename*
+fn attachment_header(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    format!(
+        "Content-Disposition: attachment; filename=\"{}\"; filename*=UTF-8''{}\r\n",
+        fallback,
+        urlencoding::encode(filename)
+    )
+}
+
+// GET /files/trash — корзина; администратор может открыть чужую через ?user=
+fn handle_trash_page(request: &str, query: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let params = parse_form_data(query);
+    let conn = ctx.pool.get()?;
+    let (owner, is_self) = match file_manager_owner(ctx, &conn, request, form_value(&params, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    render_trash(ctx, &conn, &owner, is_self, None, stream)
+}
+
+fn render_trash(
+    ctx: &Context,
+    conn: &Connection,
+    owner: &User,
+    is_self: bool,
+    error: Option<&str>,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let hidden = if is_self {
+        String::new()
+    } else {
+        format!(r#"<input type="hidden" name="user" value="{}">"#, html_escape(&owner.username))
+    };
+    let mut entries = String::new();
+    for item in list_trash(conn, &owner.username)? {
+        let path = &item.file.path;
+        entries.push_str(&format!(
+            r#"<tr><td>{}</td><td>/{}</td><td title="{} байт">{}</td><td>{}</td><td>
+            <form method="POST" action="/files/trash/restore">{hidden}<input type="hidden" name="id" value="{id}"><button type="submit">Восстановить</button></form>
+            <form method="POST" action="/files/trash/purge" onsubmit="return confirm('Удалить файл окончательно?')">{hidden}<input type="hidden" name="id" value="{id}"><button type="submit">Удалить навсегда</button></form></td></tr>"#,
+            html_escape(base_name(path)),
+            html_escape(parent_dir(path)),
+            item.file.size,
+            format_size(item.file.size),
+            format_timestamp(item.deleted_at),
+            hidden = hidden,
+            id = item.id
+        ));
+    }
+    if entries.is_empty() {
+        entries.push_str(r#"<tr><td colspan="5">Корзина пуста</td></tr>"#);
+    }
+    let error_html = match error {
+        Some(error) => format!(r#"<p class="errors">{}</p>"#, html_escape(error)),
+        None => String::new(),
+    };
+    let retention_days = ctx.storage.trash_retention_secs / (24 * 60 * 60);
+    let html = std::fs::read_to_string("trash.html")?
+        .replace("{{OWNER}}", &html_escape(&owner.username))
+        .replace("{{ERROR}}", &error_html)
+        .replace("{{RETENTION}}", &format!("{} дн.", retention_days))
+        .replace("{{ENTRIES}}", &entries)
+        .replace("{{HIDDEN}}", &hidden)
+        .replace("{{BACK}}", &html_escape(&files_link("", owner, is_self)));
+    let status = if error.is_some() { "400 Bad Request" } else { "200 OK" };
+    send_html(stream, status, &html)
+}
+
+// POST /files/trash/restore, /files/trash/purge, /files/trash/empty
+fn handle_trash_action(request: &str, action: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let conn = ctx.pool.get()?;
+    let (owner, is_self) = match file_manager_owner(ctx, &conn, request, form_value(&form_data, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let viewer = current_user(&conn, request)?.unwrap_or_default();
+    let id = form_value(&form_data, "id").parse::<i64>().unwrap_or(0);
+
+    let result = match action {
+        "restore" => restore_from_trash(&conn, &owner.username, id).map(|path| format!("restored {} from trash", path)),
+        "purge" => purge_trash_item(&conn, &ctx.storage, &owner.username, id)
+            .map(|path| format!("purged {} from trash", path)),
+        _ => empty_trash(&conn, &ctx.storage, &owner.username).map(|count| format!("emptied trash ({} files)", count)),
+    };
+    match result {
+        Ok(description) => {
+            let log_entry = format!(
+                "File manager: {} {} in space of {} at {}",
+                viewer, description, owner.username, get_formatted_time()
+            );
+            log_to_file(&log_entry)?;
+            let location = if is_self {
+                "/files/trash".to_string()
+            } else {
+                format!("/files/trash?user={}", urlencoding::encode(&owner.username))
+            };
+            let response = format!("HTTP/1.1 303 See Other\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location);
+            stream.write_all(response.as_bytes())?;
+            stream.flush()?;
+            Ok(())
+        }
+        Err(FileError::Invalid(message)) => render_trash(ctx, &conn, &owner, is_self, Some(&message), stream),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// DELETE /files/<id> — удаление файла скриптом (токен с областью files:write); файл попадает в корзину
+fn handle_api_delete_file(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_WRITE)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+    let conn = ctx.pool.get()?;
+    let file = match route["/files/".len()..].parse::<i64>() {
+        Ok(id) => find_file_record(&conn, id)?,
+        Err(_) => None,
+    };
+    let file = match file {
+        Some(file) if file.owner.as_deref() == Some(username.as_str()) || (file.owner.is_some() && is_admin(ctx, &username)?) => file,
+        _ => return send_json(stream, "404 Not Found", "", r#"{"error":"file not found"}"#),
+    };
+    trash_file(&conn, &ctx.storage, &file)?;
+    let log_entry = format!("API: {} moved file {} to trash at {}", username, file.path, get_formatted_time());
+    log_to_file(&log_entry)?;
+    stream.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")?;
     stream.flush()?;
     Ok(())
+}
+
+// Форма регистрации с сообщениями об ошибках и ранее введенными значениями (кроме пароля)
+fn render_register_form(
+    policy: &RegistrationPolicy,
+    form_data: &HashMap<String, String>,
+    errors: &[String],
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let errors_html = if errors.is_empty() {
+        String::new()
+    } else {
+        let items: String = errors
+            .iter()
+            .map(|error| format!("<li>{}</li>", html_escape(error)))
+            .collect();
+        format!(r#"<ul class="errors">{}</ul>"#, items)
+    };
+    let invite_html = if policy.requires_invite() {
+        r#"<label>Код приглашения:</label><br>
+    <input name="invite" required><br>"#
+    } else {
+        ""
+    };
+    let html = std::fs::read_to_string("register.html")?
+        .replace("{{ERRORS}}", &errors_html)
+        .replace("{{USERNAME}}", &html_escape(form_value(form_data, "username")))
+        .replace("{{DISPLAY_NAME}}", &html_escape(form_value(form_data, "display_name")))
+        .replace("{{EMAIL}}", &html_escape(form_value(form_data, "email")))
+        .replace("{{INVITE}}", invite_html);
+    send_html(stream, "200 OK", &html)
+}
 
+fn form_value<'a>(form_data: &'a HashMap<String, String>, key: &str) -> &'a str {
+    form_data.get(key).map(|value| value.trim()).unwrap_or("")
 }
 
-fn handle_register(request: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
+fn handle_register(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let policy = &ctx.registration;
     let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
     let form_data = parse_form_data(body);
     let username = form_data.get("username").cloned().unwrap_or_default();
     let password = form_data.get("password").cloned().unwrap_or_default();
+    let invite = form_data.get("invite").cloned().unwrap_or_default();
+    let display_name = form_value(&form_data, "display_name");
+    let email = form_value(&form_data, "email");
+
+    if let Err(error) = policy.check_mode(&invite) {
+        return render_register_form(policy, &form_data, &[error], stream);
+    }
+
+    let mut errors = policy.validate_username(&username);
+    errors.extend(policy.validate_password(&username, &password));
+    errors.extend(policy.validate_profile(display_name, email));
+    if !errors.is_empty() {
+        return render_register_form(policy, &form_data, &errors, stream);
+    }
+
+    if ctx.users.username_taken(&username, policy.case_insensitive_unique)? {
+        let error = "Пользователь с таким именем уже существует.".to_string();
+        return render_register_form(policy, &form_data, &[error], stream);
+    }
+
     let hash = hash_password(&password);
+    let created = ctx.users.create(&username, &hash).and_then(|mut user| {
+        if display_name.is_empty() && email.is_empty() {
+            return Ok(());
+        }
+        user.display_name = display_name.to_string();
+        user.email = email.to_string();
+        ctx.users.update(&user)
+    });
+    if let Err(e) = created {
+        let log_entry = format!("Registration of {} failed: {} at {}", username, e, get_formatted_time());
+        log_to_file(&log_entry)?;
+        let error = "Не удалось создать пользователя, попробуйте еще раз.".to_string();
+        return render_register_form(policy, &form_data, &[error], stream);
+    }
+
+    serve_file("registered.html", stream)
+}
 
-    let conn = Connection::open("users.db")?;
+fn handle_login(request: &str, client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let limiter = &ctx.limiter;
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let username = form_data.get("username").cloned().unwrap_or_default();
+    let password = form_data.get("password").cloned().unwrap_or_default();
 
-    if user_exists(&conn, &username)? {
-        serve_file("user_exists.html", stream)?;
-    } else if register_user(&conn, &username, &hash).is_ok() {
-        serve_file("registered.html", stream)?;
+    // Слишком частые или заблокированные попытки отклоняем до проверки пароля
+    if let Err(retry_after) = limiter.check(client_ip, &username) {
+        let response = too_many_requests_response(retry_after);
+        stream.write_all(response.as_bytes())?;
+        stream.flush()?;
+        return Ok(());
+    }
+
+    let conn = ctx.pool.get()?;
+    if let Some(user) = check_password(ctx, &username, &password)? {
+        // Пароль верный, но учетная запись отключена администратором
+        if user.disabled {
+            let log_entry = format!("[{}] Login of disabled user {} refused at {}", client_ip, username, get_formatted_time());
+            log_to_file(&log_entry)?;
+            return send_html(stream, "403 Forbidden", &page("Учетная запись отключена", "<h1>Учетная запись отключена</h1><p>Обратитесь к администратору. <a href=\"/\">На главную</a></p>"));
+        }
+        // Если включена 2FA, сессия остается неполной до ввода кода
+        let needs_2fa = matches!(get_totp(&conn, &username)?, Some((_, true, _)));
+        let cookie = start_session(&conn, &username, needs_2fa)?;
+        let headers = format!("Set-Cookie: {}\r\n", cookie);
+        if needs_2fa {
+            serve_file_with_headers("login_2fa.html", &headers, stream)?;
+        } else {
+            limiter.record_success(&username);
+            ctx.users.record_login(user.id, client_ip)?;
+            serve_file_with_headers("welcome.html", &headers, stream)?;
+        }
     } else {
+        limiter.record_failure(client_ip, &username);
         serve_file("unauthorized.html", stream)?;
     }
 
     Ok(())
 }
 
-fn handle_login(request: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
+// Второй шаг входа: код из приложения-аутентификатора или код восстановления
+fn handle_login_2fa(request: &str, client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let limiter = &ctx.limiter;
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let code = form_data.get("code").cloned().unwrap_or_default();
+
+    let conn = ctx.pool.get()?;
+    let session = match current_session(&conn, request)? {
+        Some(session) if session.pending_2fa => session,
+        _ => return serve_file("unauthorized.html", stream),
+    };
+
+    if let Err(retry_after) = limiter.check(client_ip, &session.username) {
+        let response = too_many_requests_response(retry_after);
+        stream.write_all(response.as_bytes())?;
+        stream.flush()?;
+        return Ok(());
+    }
+
+    let verified = match get_totp(&conn, &session.username)? {
+        Some((secret, true, last_step)) => {
+            if let Some(step) = verify_code(&secret, &code, get_timestamp(), last_step) {
+                update_totp_step(&conn, &session.username, step)?;
+                true
+            } else if use_recovery_code(&conn, &session.username, &code.trim().to_ascii_lowercase())? {
+                let log_entry = format!(
+                    "Recovery code used by {} at {}",
+                    session.username,
+                    get_formatted_time()
+                );
+                log_to_file(&log_entry)?;
+                true
+            } else {
+                false
+            }
+        }
+        // 2FA отключили, пока пользователь вводил код
+        _ => true,
+    };
+
+    if verified {
+        complete_2fa(&conn, &session)?;
+        limiter.record_success(&session.username);
+        if let Some(user) = ctx.users.find(&session.username)? {
+            ctx.users.record_login(user.id, client_ip)?;
+        }
+        serve_file("welcome.html", stream)
+    } else {
+        limiter.record_failure(client_ip, &session.username);
+        serve_file("unauthorized.html", stream)
+    }
+}
+
+fn handle_change_password_form(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    if current_user(&conn, request)?.is_none() {
+        return serve_file("unauthorized.html", stream);
+    }
+    serve_file("change_password.html", stream)
+}
+
+// Смена пароля вошедшим пользователем: требуется текущий пароль
+fn handle_change_password(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let policy = &ctx.registration;
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let old_password = form_data.get("old_password").cloned().unwrap_or_default();
+    let new_password = form_data.get("new_password").cloned().unwrap_or_default();
+    let confirm = form_data.get("confirm").cloned().unwrap_or_default();
+
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+
+    let mut user = match check_password(ctx, &username, &old_password)? {
+        Some(user) => user,
+        None => return send_html(stream, "200 OK", &page("Смена пароля", "<h1>Неверный текущий пароль</h1><p><a href=\"/password\">Попробовать снова</a></p>")),
+    };
+    if new_password != confirm {
+        return send_html(stream, "200 OK", &page("Смена пароля", "<h1>Новые пароли не совпадают</h1><p><a href=\"/password\">Попробовать снова</a></p>"));
+    }
+    let errors = policy.validate_password(&username, &new_password);
+    if !errors.is_empty() {
+        return send_html(stream, "200 OK", &page("Смена пароля", &password_errors_html(&errors, "/password")));
+    }
+
+    user.set_password(&new_password);
+    ctx.users.update(&user)?;
+    // Остальные сессии могли открыть по прежнему паролю; текущая продолжает работать
+    end_other_sessions(&conn, request, &username)?;
+    let log_entry = format!("Password changed for {} at {}", username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    send_html(stream, "200 OK", &page("Смена пароля", "<h1>Пароль изменен</h1><p><a href=\"/\">На главную</a></p>"))
+}
+
+fn password_errors_html(errors: &[String], back: &str) -> String {
+    let items: String = errors
+        .iter()
+        .map(|error| format!("<li>{}</li>", html_escape(error)))
+        .collect();
+    format!(
+        r#"<h1>Пароль не подходит</h1><ul class="errors">{}</ul><p><a href="{}">Попробовать снова</a></p>"#,
+        items,
+        html_escape(back)
+    )
+}
+
+// Запрос сброса пароля: токен уходит через Notifier, в базе остается только его хеш
+fn handle_reset_request(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
     let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
     let form_data = parse_form_data(body);
     let username = form_data.get("username").cloned().unwrap_or_default();
-    let password = form_data.get("password").cloned().unwrap_or_default();
-    let hash = hash_password(&password);
 
-    let conn = Connection::open("users.db")?;
-    if authenticate_user(&conn, &username, &hash)? {
-        serve_file("welcome.html", stream)?;
+    let conn = ctx.pool.get()?;
+    if ctx.users.find(&username)?.is_some() {
+        let token = random_token(32)?;
+        create_reset_token(&conn, &hash_password(&token), &username, get_timestamp() + RESET_TOKEN_TTL_SECS)?;
+
+        let message = format!(
+            "Для сброса пароля перейдите по ссылке (действует {} мин.):\n{}/reset/confirm?token={}",
+            RESET_TOKEN_TTL_SECS / 60,
+            ctx.base_url,
+            token
+        );
+        ctx.notifier.notify(&username, "Сброс пароля", &message)?;
+
+        let log_entry = format!("Password reset requested for {} at {}", username, get_formatted_time());
+        log_to_file(&log_entry)?;
+    }
+
+    // Ответ одинаковый независимо от существования пользователя
+    send_html(stream, "200 OK", &page("Сброс пароля", "<h1>Запрос принят</h1><p>Если такой пользователь существует, ему отправлена ссылка для сброса пароля.</p><p><a href=\"/\">На главную</a></p>"))
+}
+
+fn handle_reset_confirm_form(query: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let params = parse_form_data(query);
+    let token = params.get("token").cloned().unwrap_or_default();
+    let html = std::fs::read_to_string("reset_confirm.html")?.replace("{{TOKEN}}", &html_escape(&token));
+    send_html(stream, "200 OK", &html)
+}
+
+fn handle_reset_confirm(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let policy = &ctx.registration;
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let token = form_data.get("token").cloned().unwrap_or_default();
+    let new_password = form_data.get("new_password").cloned().unwrap_or_default();
+    let confirm = form_data.get("confirm").cloned().unwrap_or_default();
+
+    let invalid_link = || {
+        page("Сброс пароля", "<h1>Ссылка недействительна или устарела</h1><p><a href=\"/reset\">Запросить новую</a></p>")
+    };
+    let conn = ctx.pool.get()?;
+    let token_hash = hash_password(&token);
+    let username = match find_reset_token(&conn, &token_hash, get_timestamp())? {
+        Some(username) => username,
+        None => return send_html(stream, "200 OK", &invalid_link()),
+    };
+
+    // Пароли проверяем до погашения токена, чтобы опечатка не сжигала ссылку
+    if new_password != confirm {
+        return send_html(stream, "200 OK", &page("Сброс пароля", "<h1>Новые пароли не совпадают</h1><p>Вернитесь назад и повторите ввод.</p>"));
+    }
+    let errors = policy.validate_password(&username, &new_password);
+    if !errors.is_empty() {
+        let back = format!("/reset/confirm?token={}", urlencoding::encode(&token));
+        return send_html(stream, "200 OK", &page("Сброс пароля", &password_errors_html(&errors, &back)));
+    }
+
+    // Токен мог погасить параллельный запрос
+    if consume_reset_token(&conn, &token_hash, get_timestamp())?.as_deref() != Some(username.as_str()) {
+        return send_html(stream, "200 OK", &invalid_link());
+    }
+
+    let mut user = ctx.users.find(&username)?.ok_or(StoreError::NotFound)?;
+    user.set_password(&new_password);
+    ctx.users.update(&user)?;
+    // Старые сессии могли принадлежать тому, кто узнал прежний пароль
+    end_other_sessions(&conn, request, &username)?;
+    let log_entry = format!("Password reset completed for {} at {}", username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    send_html(stream, "200 OK", &page("Сброс пароля", "<h1>Пароль изменен</h1><p><a href=\"/login\">Войти</a></p>"))
+}
+
+fn handle_logout(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let cookie = end_session(&conn, request)?;
+    let response = format!(
+        "HTTP/1.1 303 See Other\r\nSet-Cookie: {}\r\nLocation: /\r\nContent-Length: 0\r\n\r\n",
+        cookie
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Страница подключения 2FA: секрет, otpauth-URI и QR-код для сканирования
+fn handle_2fa_setup(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+
+    let secret = match get_totp(&conn, &username)? {
+        Some((_, true, _)) => {
+            return send_html(stream, "200 OK", &page("2FA", "<h1>Двухфакторная аутентификация уже включена</h1><p><a href=\"/\">На главную</a></p>"));
+        }
+        // Неподтвержденный секрет переиспользуем, чтобы перезагрузка страницы не ломала уже отсканированный код
+        Some((secret, false, _)) => secret,
+        None => {
+            let secret = generate_secret()?;
+            set_totp_secret(&conn, &username, &secret)?;
+            secret
+        }
+    };
+
+    let uri = otpauth_uri(&username, &secret);
+    let html = std::fs::read_to_string("totp_setup.html")?
+        .replace("{{QR}}", &qr_svg(&uri).unwrap_or_default())
+        .replace("{{URI}}", &html_escape(&uri))
+        .replace("{{SECRET}}", &secret);
+    send_html(stream, "200 OK", &html)
+}
+
+// Подтверждение подключения 2FA первым кодом и выдача кодов восстановления
+fn handle_2fa_enable(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let code = form_data.get("code").cloned().unwrap_or_default();
+
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+    let secret = match get_totp(&conn, &username)? {
+        Some((secret, false, _)) => secret,
+        _ => return serve_file("unauthorized.html", stream),
+    };
+    let step = match verify_code(&secret, &code, get_timestamp(), 0) {
+        Some(step) => step,
+        None => {
+            return send_html(stream, "200 OK", &page("2FA", "<h1>Неверный код</h1><p><a href=\"/2fa\">Попробовать снова</a></p>"));
+        }
+    };
+
+    // Коды показываются один раз, в базе остаются только их хеши
+    let mut codes = Vec::with_capacity(RECOVERY_CODES_COUNT);
+    for _ in 0..RECOVERY_CODES_COUNT {
+        codes.push(random_token(RECOVERY_CODE_BYTES)?);
+    }
+    let hashes = codes.iter().map(|code| hash_password_salted(code)).collect::<Result<Vec<_>, _>>()?;
+    store_recovery_codes(&conn, &username, &hashes)?;
+    enable_totp(&conn, &username, step)?;
+
+    let log_entry = format!("2FA enabled for {} at {}", username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    let items: String = codes.iter().map(|code| format!("<li><code>{}</code></li>", code)).collect();
+    let body = format!(
+        "<h1>Двухфакторная аутентификация включена</h1>\
+         <p>Сохраните коды восстановления. Каждый можно использовать один раз вместо кода из приложения:</p>\
+         <ul>{}</ul><p><a href=\"/\">На главную</a></p>",
+        items
+    );
+    send_html(stream, "200 OK", &page("2FA", &body))
+}
+
+// Страница персональных токенов: список, создание и отзыв.
+// new_token показывается один раз сразу после создания
+fn handle_tokens_page(request: &str, ctx: &Context, new_token: Option<&str>, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+
+    let now = get_timestamp();
+    let mut rows = String::new();
+    for token in list_api_tokens(&conn, &username)? {
+        let status = if token.revoked {
+            "отозван"
+        } else if token.expires_at.is_some_and(|t| t <= now) {
+            "истек"
+        } else {
+            "активен"
+        };
+        let revoke = if token.revoked {
+            String::new()
+        } else {
+            format!(
+                r#"<form method="POST" action="/settings/tokens/revoke"><input type="hidden" name="id" value="{}"><button type="submit">Отозвать</button></form>"#,
+                token.id
+            )
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            html_escape(&token.name),
+            html_escape(&token.scopes),
+            format_timestamp(token.created_at),
+            token.expires_at.map(format_timestamp).unwrap_or_else(|| "бессрочно".to_string()),
+            token.last_used_at.map(format_timestamp).unwrap_or_else(|| "—".to_string()),
+            status,
+            revoke
+        ));
+    }
+
+    let new_token_html = match new_token {
+        Some(token) => format!(
+            "<p><b>Новый токен:</b> <code>{}</code><br>Скопируйте его сейчас — больше он показан не будет.</p>",
+            html_escape(token)
+        ),
+        None => String::new(),
+    };
+    let scopes_html: String = ALL_SCOPES
+        .iter()
+        .map(|scope| format!(r#"<label><input type="checkbox" name="scope_{0}" value="1"> {0}</label><br>"#, scope))
+        .collect();
+
+    let html = std::fs::read_to_string("tokens.html")?
+        .replace("{{NEW_TOKEN}}", &new_token_html)
+        .replace("{{TOKENS}}", &rows)
+        .replace("{{SCOPES}}", &scopes_html);
+    send_html(stream, "200 OK", &html)
+}
+
+fn handle_token_create(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let name = form_data.get("name").cloned().unwrap_or_default();
+    let expires_days: u64 = form_data
+        .get("expires_days")
+        .and_then(|d| d.trim().parse().ok())
+        .unwrap_or(0);
+    let scopes: Vec<&str> = ALL_SCOPES
+        .iter()
+        .copied()
+        .filter(|scope| form_data.contains_key(&format!("scope_{}", scope)))
+        .collect();
+
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+    if name.trim().is_empty() || scopes.is_empty() {
+        return send_html(stream, "200 OK", &page("Токены", "<h1>Укажите название и хотя бы одну область доступа</h1><p><a href=\"/settings/tokens\">Назад</a></p>"));
+    }
+
+    let token = format!("{}{}", TOKEN_PREFIX, random_token(32)?);
+    let now = get_timestamp();
+    // 0 дней — бессрочный токен
+    let expires_at = if expires_days > 0 { Some(now + expires_days * 24 * 60 * 60) } else { None };
+    create_api_token(&conn, &username, name.trim(), &hash_password(&token), &scopes.join(","), now, expires_at)?;
+
+    let log_entry = format!("API token '{}' created for {} at {}", name.trim(), username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    // Страница берет свое соединение из пула
+    drop(conn);
+    handle_tokens_page(request, ctx, Some(&token), stream)
+}
+
+fn handle_token_revoke(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let id: i64 = form_data.get("id").and_then(|id| id.parse().ok()).unwrap_or(0);
+
+    let conn = ctx.pool.get()?;
+    let username = match current_user(&conn, request)? {
+        Some(username) => username,
+        None => return serve_file("unauthorized.html", stream),
+    };
+    if revoke_api_token(&conn, &username, id)? {
+        let log_entry = format!("API token {} revoked by {} at {}", id, username, get_formatted_time());
+        log_to_file(&log_entry)?;
+    }
+
+    let response = "HTTP/1.1 303 See Other\r\nLocation: /settings/tokens\r\nContent-Length: 0\r\n\r\n";
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Список загруженных файлов в JSON для скриптов
+fn handle_api_list_files(request: &str, client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_READ)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+
+    let conn = ctx.pool.get()?;
+    let files = if is_admin(ctx, &username)? {
+        list_file_records(&conn)?
     } else {
-        serve_file("unauthorized.html", stream)?;
+        list_visible_files(&conn, &username)?
+    };
+    let items: Vec<String> = files.iter().map(file_json).collect();
+    send_json(stream, "200 OK", "", &format!(r#"{{"files":[{}]}}"#, items.join(",")))
+}
+
+// Описание файла в ответах API
+fn file_json(file: &FileRecord) -> String {
+    // "modified" оставлен для совместимости со старыми клиентами
+    format!(
+        r#"{{"id":{},"name":"{}","path":"{}","url":"{}","size":{},"content_type":"{}","sha256":"{}","owner":{},"uploaded_at":{},"modified":{}}}"#,
+        file.id,
+        json_escape(&file.original_name),
+        json_escape(&file.path),
+        json_escape(&file_url(file)),
+        file.size,
+        json_escape(&file.content_type),
+        file.sha256,
+        file.owner
+            .as_ref()
+            .map(|owner| format!("\"{}\"", json_escape(owner)))
+            .unwrap_or_else(|| "null".to_string()),
+        file.uploaded_at,
+        file.uploaded_at
+    )
+}
+
+fn json_error(stream: &mut TcpStream, status: &str, message: &str) -> Result<(), HttpError> {
+    send_json(stream, status, "", &format!(r#"{{"error":"{}"}}"#, json_escape(message)))
+}
+
+// Путь файла из адреса /api/files/<path>: каталоги через "/", компоненты проверяются как в файловом менеджере
+fn api_file_path(route: &str) -> Result<String, String> {
+    let path = urlencoding::decode(&route["/api/files/".len()..]).map_err(|_| "invalid path encoding".to_string())?;
+    let path = path.trim_matches('/');
+    let dir = normalize_dir(parent_dir(path))?;
+    validate_name(base_name(path))?;
+    Ok(join_path(&dir, base_name(path)))
+}
+
+// Content-MD5 и Digest (RFC 3230: sha-256 или md5, значения в base64), которые прислал клиент
+struct ExpectedDigests {
+    md5: Vec<Vec<u8>>,
+    sha256: Vec<Vec<u8>>,
+}
+
+fn expected_digests(request: &str) -> Result<ExpectedDigests, String> {
+    let mut expected = ExpectedDigests { md5: Vec::new(), sha256: Vec::new() };
+    let decode = |value: &str| STANDARD.decode(value.trim()).map_err(|_| format!("invalid digest value '{}'", value));
+    if let Some(value) = get_header(request, "Content-MD5") {
+        expected.md5.push(decode(value)?);
+    }
+    for item in get_header(request, "Digest").unwrap_or("").split(',').filter(|item| !item.trim().is_empty()) {
+        let (algorithm, value) = item.split_once('=').ok_or_else(|| format!("invalid Digest '{}'", item))?;
+        match algorithm.trim().to_ascii_lowercase().as_str() {
+            "md5" => expected.md5.push(decode(value)?),
+            "sha-256" => expected.sha256.push(decode(value)?),
+            // Неизвестные алгоритмы по RFC 3230 пропускаются
+            _ => {}
+        }
+    }
+    Ok(expected)
+}
+
+// Пишет тело в файл и одновременно считает его MD5 и SHA-256
+struct DigestWriter<W: Write> {
+    inner: W,
+    md5: md5::Context,
+    sha256: sha2::Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.md5.consume(&buf[..n]);
+        sha2::Digest::update(&mut self.sha256, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Чем закончился прием файла из тела PUT-запроса
+enum PutOutcome {
+    Stored { record: FileRecord, created: bool },
+    // Запрос отклонен: статус ответа и пояснение
+    Rejected(&'static str, String),
+    // Клиент ушел, не дослав тело, — отвечать некому
+    Aborted,
+}
+
+// Сохраняет сырое тело PUT-запроса как файл path пользователя, не собирая его в памяти.
+// If-None-Match: * запрещает перезапись; Content-MD5, Digest и правила ContentPolicy проверяются до сохранения
+fn receive_user_file(
+    request: &str,
+    raw: &[u8],
+    ctx: &Context,
+    username: &str,
+    path: &str,
+    stream: &mut TcpStream,
+) -> Result<PutOutcome, HttpError> {
+    let expected = match expected_digests(request) {
+        Ok(expected) => expected,
+        Err(message) => return Ok(PutOutcome::Rejected("400 Bad Request", message)),
+    };
+    let content_length = match get_header(request, "Content-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => return Ok(PutOutcome::Rejected("411 Length Required", "Content-Length is required".to_string())),
+    };
+    if let Err(e) = ctx.content_policy.check_upload(path, content_length) {
+        return Ok(PutOutcome::Rejected(content_status(&e), e.message().to_string()));
+    }
+    if ctx.storage.user_dir(username).is_none() {
+        return Err(HttpError::Other(format!("No storage space for user '{}'", username)));
+    }
+    let create_only = get_header(request, "If-None-Match") == Some("*");
+    let exists_error = || Ok(PutOutcome::Rejected("412 Precondition Failed", "file already exists".to_string()));
+
+    let conn = ctx.pool.get()?;
+    if folder_exists(&conn, username, path)? {
+        return Ok(PutOutcome::Rejected("409 Conflict", "path is a folder".to_string()));
+    }
+    if create_only && find_user_file(&conn, username, path)?.is_some() {
+        return exists_error();
+    }
+    let (used, quota) = quota_room(ctx, &conn, username, path)?;
+    if used + content_length > quota {
+        return Ok(PutOutcome::Rejected("413 Request Entity Too Large", "storage quota exceeded".to_string()));
     }
+    drop(conn);
+
+    // Тело пишется во временный файл в хранилище, оттуда переносится в блоб без копирования
+    let temp = temp_upload_path(&ctx.storage)?;
+    let mut out = DigestWriter {
+        inner: BufWriter::new(fs::File::create(&temp)?),
+        md5: md5::Context::new(),
+        sha256: sha2::Sha256::default(),
+    };
+    let (received, result) = receive_body(request, raw, stream, content_length, &mut out);
+    let DigestWriter { inner, md5, sha256 } = out;
+    drop(inner);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    if received < content_length {
+        let _ = fs::remove_file(&temp);
+        return Ok(PutOutcome::Aborted);
+    }
+    let md5 = md5.compute().0.to_vec();
+    let sha256 = sha2::Digest::finalize(sha256).to_vec();
+    if expected.md5.iter().any(|digest| *digest != md5) || expected.sha256.iter().any(|digest| *digest != sha256) {
+        let _ = fs::remove_file(&temp);
+        return Ok(PutOutcome::Rejected("400 Bad Request", "digest mismatch".to_string()));
+    }
+    if let Err(e) = ctx.content_policy.check_content(path, &read_head(&temp)?) {
+        let _ = fs::remove_file(&temp);
+        return Ok(PutOutcome::Rejected(content_status(&e), e.message().to_string()));
+    }
+    // Файл становится виден только после проверки сканером
+    let quarantined = quarantine_file(&ctx.storage, &temp)?;
+    if !scan_quarantined(ctx, username, path, &quarantined)? {
+        return Ok(PutOutcome::Rejected("422 Unprocessable Entity", SCAN_REJECTED.to_string()));
+    }
+
+    let conn = ctx.pool.get()?;
+    let existed = find_user_file(&conn, username, path)?.is_some();
+    // Пока тело передавалось, файл мог появиться
+    if create_only && existed {
+        let _ = fs::remove_file(&quarantined);
+        return exists_error();
+    }
+    match store_user_file_from(&conn, &ctx.storage, username, path, &quarantined, content_type_for(path)) {
+        Ok(record) => Ok(PutOutcome::Stored { record, created: !existed }),
+        Err(FileError::Invalid(message)) => {
+            let _ = fs::remove_file(&quarantined);
+            Ok(PutOutcome::Rejected("409 Conflict", message))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Ответ на файл, не прошедший правила ContentPolicy
+fn content_status(error: &ContentError) -> &'static str {
+    match error {
+        ContentError::TooLarge(_) => "413 Request Entity Too Large",
+        ContentError::Rejected(_) => "415 Unsupported Media Type",
+    }
+}
+
+// Пояснение для клиента, когда сканер не пропустил файл
+const SCAN_REJECTED: &str = "file rejected by the upload scanner";
+
+// Проверяет файл из карантина сканером и записывает вердикт в журнал; true — файл чистый
+// и его можно переносить в хранилище. Зараженный файл и файл, который проверить не удалось,
+// удаляются после записи в журнал
+fn scan_quarantined(ctx: &Context, username: &str, path: &str, quarantined: &str) -> Result<bool, HttpError> {
+    let (clean, verdict) = match ctx.scanner.scan(std::path::Path::new(quarantined)) {
+        ScanVerdict::Clean => (true, "clean".to_string()),
+        ScanVerdict::Infected(found) => {
+            let size = fs::metadata(quarantined).map(|meta| meta.len()).unwrap_or(0);
+            (false, format!("infected ({}), {} bytes deleted", found, size))
+        }
+        ScanVerdict::Failed(reason) => (false, format!("scan failed ({})", reason)),
+    };
+    let log_entry = format!(
+        "Scan of {} uploaded by {} with {}: {} at {}",
+        path, username, ctx.scanner.name(), verdict, get_formatted_time()
+    );
+    log_to_file(&log_entry)?;
+    if !clean {
+        let _ = fs::remove_file(quarantined);
+    }
+    Ok(clean)
+}
+
+// PUT /api/files/<path> — сырое тело запроса становится файлом пользователя
+fn handle_api_put_file(
+    request: &str,
+    raw: &[u8],
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_WRITE)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+    let path = match api_file_path(route) {
+        Ok(path) => path,
+        Err(message) => return json_error(stream, "400 Bad Request", &message),
+    };
+    match receive_user_file(request, raw, ctx, &username, &path, stream)? {
+        PutOutcome::Stored { record, created } => {
+            let log_entry = format!("API: {} put file {} ({} bytes) at {}", username, path, record.size, get_formatted_time());
+            log_to_file(&log_entry)?;
+            let status = if created { "201 Created" } else { "200 OK" };
+            send_json(stream, status, "", &file_json(&record))
+        }
+        PutOutcome::Rejected(status, message) => json_error(stream, status, &message),
+        PutOutcome::Aborted => Ok(()),
+    }
+}
 
+// DELETE /api/files/<path> — файл или папка уходят в корзину
+fn handle_api_delete_path(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_WRITE)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+    let path = match api_file_path(route) {
+        Ok(path) => path,
+        Err(message) => return json_error(stream, "400 Bad Request", &message),
+    };
+    let conn = ctx.pool.get()?;
+    let body = if let Some(file) = find_user_file(&conn, &username, &path)? {
+        trash_file(&conn, &ctx.storage, &file)?;
+        format!(r#"{{"deleted":"{}","type":"file","files":1}}"#, json_escape(&path))
+    } else if folder_exists(&conn, &username, &path)? {
+        let count = trash_folder(&conn, &username, &path)?;
+        format!(r#"{{"deleted":"{}","type":"folder","files":{}}}"#, json_escape(&path), count)
+    } else {
+        return json_error(stream, "404 Not Found", "file not found");
+    };
+    let log_entry = format!("API: {} moved {} to trash at {}", username, path, get_formatted_time());
+    log_to_file(&log_entry)?;
+    send_json(stream, "200 OK", "", &body)
+}
+
+// Файл по маршруту <prefix><id>, доступный пользователю запроса: свой, общий или любой для администратора.
+// Чужим пользователям отвечаем 404, чтобы не раскрывать существование файла. Если доступа нет,
+// сам отправляет ответ и возвращает None
+fn readable_file(
+    request: &str,
+    client_ip: &str,
+    id: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<Option<(String, FileRecord)>, HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_READ)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => {
+            send_auth_error(stream, outcome)?;
+            return Ok(None);
+        }
+    };
+
+    let conn = ctx.pool.get()?;
+    let file = match id.parse::<i64>() {
+        Ok(id) => find_file_record(&conn, id)?,
+        Err(_) => None,
+    };
+    let allowed = match &file {
+        Some(file) => match &file.owner {
+            Some(owner) => *owner == username || is_admin(ctx, &username)?,
+            None => true,
+        },
+        None => false,
+    };
+    match file.filter(|_| allowed) {
+        Some(file) => Ok(Some((username, file))),
+        None => {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            Ok(None)
+        }
+    }
+}
+
+// GET /download/<id> — отдает файл владельцу или администратору
+fn handle_download(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    match readable_file(request, client_ip, &route["/download/".len()..], ctx, stream)? {
+        Some((_, file)) => send_stored_file(stream, &file, false),
+        None => Ok(()),
+    }
+}
+
+// GET /thumb/<id> — миниатюра изображения (PNG), созданная при первом запросе и сохраненная в кэше
+fn handle_thumbnail(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let file = match readable_file(request, client_ip, &route["/thumb/".len()..], ctx, stream)? {
+        Some((_, file)) => file,
+        None => return Ok(()),
+    };
+    match thumbnail(&ctx.storage, &file)? {
+        Some(path) => send_thumbnail(stream, &path, &file.sha256, "private"),
+        None => {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            Ok(())
+        }
+    }
+}
+
+// GET /thumb/static/<path> — миниатюра картинки из static/; static/ открыт всем, как и его миниатюры
+fn handle_static_thumbnail(route: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let file_path = &route["/thumb/".len()..];
+    // Те же ограничения, что и у самих файлов static/
+    let thumb = if file_path.split(['/', '\\']).any(|segment| segment == "..") {
+        None
+    } else {
+        static_thumbnail(&ctx.storage, file_path)?
+    };
+    match thumb {
+        Some((path, key)) => send_thumbnail(stream, &path, &key, "public"),
+        None => {
+            stream.write_all(not_found_response().as_bytes())?;
+            stream.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn send_thumbnail(stream: &mut TcpStream, path: &str, etag: &str, cache: &str) -> Result<(), HttpError> {
+    let contents = fs::read(path)?;
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: image/png\r\nETag: \"{}\"\r\nCache-Control: {}, max-age=86400\r\n\r\n",
+        contents.len(),
+        etag,
+        cache
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(&contents)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// GET /preview/<id> — страница просмотра: изображение, аудио и видео встраиваются,
+// текст показывается как есть, Markdown — оформленным
+fn handle_preview(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let (username, file) = match readable_file(request, client_ip, &route["/preview/".len()..], ctx, stream)? {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let url = html_escape(&file_url(&file));
+    let content = match PreviewKind::of(&file.content_type) {
+        PreviewKind::Image => format!(r#"<img src="{}" alt="{}">"#, url, html_escape(base_name(&file.path))),
+        PreviewKind::Audio => format!(r#"<audio controls preload="metadata" src="{}"></audio>"#, url),
+        PreviewKind::Video => format!(r#"<video controls preload="metadata" src="{}"></video>"#, url),
+        kind @ (PreviewKind::Markdown | PreviewKind::Text) => {
+            let (text, truncated) = read_text_preview(&file.stored_path)?;
+            let mut content = if kind == PreviewKind::Markdown {
+                format!(r#"<div class="markdown">{}</div>"#, render_markdown(&text))
+            } else {
+                format!("<pre>{}</pre>", html_escape(&text))
+            };
+            if truncated {
+                content.push_str(&format!(
+                    "<p><em>Показаны первые {} — скачайте файл целиком.</em></p>",
+                    format_size(PREVIEW_TEXT_BYTES)
+                ));
+            }
+            content
+        }
+        PreviewKind::Unavailable => "<p>Для этого типа файлов предпросмотр недоступен.</p>".to_string(),
+    };
+
+    // Обратно — в папку файла; администратор возвращается в пространство владельца
+    let back = match &file.owner {
+        Some(owner) if *owner != username => format!(
+            "/files?dir={}&user={}",
+            urlencoding::encode(parent_dir(&file.path)),
+            urlencoding::encode(owner)
+        ),
+        Some(_) => format!("/files?dir={}", urlencoding::encode(parent_dir(&file.path))),
+        None => "/files".to_string(),
+    };
+    let info = format!(
+        "{} | {} | загружен {}",
+        format_size(file.size),
+        html_escape(&file.content_type),
+        format_timestamp(file.uploaded_at)
+    );
+    // Содержимое подставляется последним: текст файла не должен попасть под замену других меток
+    let html = std::fs::read_to_string("preview.html")?
+        .replace("{{TITLE}}", &html_escape(base_name(&file.path)))
+        .replace("{{INFO}}", &info)
+        .replace("{{DOWNLOAD}}", &url)
+        .replace("{{BACK}}", &html_escape(&back))
+        .replace("{{CONTENT}}", &content);
+    send_html(stream, "200 OK", &html)
+}
+
+// Поиск от имени пользователя: администратор ищет по всем файлам, остальные — по своим и общим
+fn run_search(ctx: &Context, username: &str, query: &str) -> Result<Vec<SearchHit>, HttpError> {
+    let conn = ctx.pool.get()?;
+    let owner = if is_admin(ctx, username)? { None } else { Some(username) };
+    Ok(search(&conn, query, owner, SEARCH_LIMIT)?)
+}
+
+// Текст из индекса в HTML: экранируется, а метки совпадений становятся <mark>
+fn highlight_html(text: &str) -> String {
+    html_escape(text)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+// Текст без меток совпадений
+fn strip_marks(text: &str) -> String {
+    text.replace([MATCH_START, MATCH_END], "")
+}
+
+// GET /search?q= — поиск по именам файлов, их тексту и заметке
+fn handle_search_page(request: &str, query: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let username = match current_user(&*ctx.pool.get()?, request)? {
+        Some(username) => username,
+        None => {
+            let response = "HTTP/1.1 303 See Other\r\nLocation: /login\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
+    };
+    let params = parse_form_data(query);
+    let q = form_value(&params, "q").trim();
+
+    let results = if q.is_empty() {
+        String::new()
+    } else {
+        let hits = run_search(ctx, &username, q)?;
+        let mut items = String::new();
+        for hit in &hits {
+            let title = match hit.file_id {
+                Some(id) => format!(r#"<a href="/preview/{}">{}</a>"#, id, highlight_html(&hit.name)),
+                None => format!("<strong>{}</strong>", highlight_html(&hit.name)),
+            };
+            let owner = match &hit.owner {
+                Some(owner) if *owner != username => format!(" — {}", html_escape(owner)),
+                Some(_) => String::new(),
+                None if hit.file_id.is_some() => " — общий файл".to_string(),
+                None => String::new(),
+            };
+            items.push_str(&format!(
+                r#"<li>{}<span class="meta">{}</span><div class="snippet">{}</div></li>"#,
+                title,
+                owner,
+                highlight_html(&hit.snippet)
+            ));
+        }
+        match hits.len() {
+            0 => "<p>Ничего не найдено.</p>".to_string(),
+            count if count >= SEARCH_LIMIT => {
+                format!("<p>Показаны первые {} результатов — уточните запрос.</p><ol>{}</ol>", count, items)
+            }
+            count => format!("<p>Найдено: {}</p><ol>{}</ol>", count, items),
+        }
+    };
+    let html = std::fs::read_to_string("search.html")?
+        .replace("{{QUERY}}", &html_escape(q))
+        .replace("{{RESULTS}}", &results);
+    send_html(stream, "200 OK", &html)
+}
+
+// GET /api/search?q= — то же в JSON. path и snippet — текст; highlight — они же в HTML с <mark>
+fn handle_api_search(
+    request: &str,
+    client_ip: &str,
+    query: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_READ)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+    let params = parse_form_data(query);
+    let q = form_value(&params, "q").trim();
+    if q.is_empty() {
+        return json_error(stream, "400 Bad Request", "query parameter q is required");
+    }
+    let items: Vec<String> = run_search(ctx, &username, q)?
+        .iter()
+        .map(|hit| {
+            let (kind, id, url) = match hit.file_id {
+                Some(id) => ("file", id.to_string(), format!("\"/preview/{}\"", id)),
+                None => ("note", "null".to_string(), "null".to_string()),
+            };
+            format!(
+                r#"{{"kind":"{}","id":{},"path":"{}","owner":{},"url":{},"score":{:.4},"snippet":"{}","highlight":{{"path":"{}","snippet":"{}"}}}}"#,
+                kind,
+                id,
+                json_escape(&strip_marks(&hit.name)),
+                hit.owner
+                    .as_ref()
+                    .map(|owner| format!("\"{}\"", json_escape(owner)))
+                    .unwrap_or_else(|| "null".to_string()),
+                url,
+                hit.score,
+                json_escape(&strip_marks(&hit.snippet)),
+                json_escape(&highlight_html(&hit.name)),
+                json_escape(&highlight_html(&hit.snippet))
+            )
+        })
+        .collect();
+    send_json(
+        stream,
+        "200 OK",
+        "",
+        &format!(r#"{{"query":"{}","results":[{}]}}"#, json_escape(q), items.join(","))
+    )
+}
+
+// Заголовки для отдачи загруженного пользователем файла: браузер не должен угадывать тип,
+// а HTML, SVG и скрипты открываются только как вложение, не от имени нашего сайта
+fn user_content_headers(file: &FileRecord, attachment: bool) -> String {
+    let mut headers = "X-Content-Type-Options: nosniff\r\n".to_string();
+    if attachment || is_risky_type(&file.content_type) {
+        headers.push_str(&attachment_header(base_name(&file.path)));
+    }
+    headers
+}
+
+// Открывает файл хранилища для потоковой отдачи: сам файл и его размер на диске
+fn open_stored(file: &FileRecord) -> Option<(fs::File, u64)> {
+    let handle = fs::File::open(&file.stored_path).ok()?;
+    let len = handle.metadata().ok()?.len();
+    Some((handle, len))
+}
+
+// Отдает содержимое файла из хранилища; если его нет на диске — 404.
+// attachment — всегда предлагать сохранить файл, а не открывать его в браузере.
+// Файл копируется в сокет по частям, а не читается в память целиком
+fn send_stored_file(stream: &mut TcpStream, file: &FileRecord, attachment: bool) -> Result<(), HttpError> {
+    let Some((contents, len)) = open_stored(file) else {
+        stream.write_all(not_found_response().as_bytes())?;
+        stream.flush()?;
+        return Ok(());
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nContent-Type: {}\r\nCache-Control: private\r\n\r\n",
+        user_content_headers(file, attachment),
+        len,
+        file.content_type
+    );
+    stream.write_all(response.as_bytes())?;
+    std::io::copy(&mut contents.take(len), stream)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Сроки действия ссылки в форме "Поделиться": (часы, подпись); 0 — бессрочно
+const SHARE_EXPIRY_OPTIONS: &[(u64, &str)] = &[(24, "1 день"), (24 * 7, "7 дней"), (24 * 30, "30 дней"), (1, "1 час"), (0, "бессрочно")];
+
+// POST /files/share — новая публичная ссылка на файл. Токен показывается один раз
+fn handle_share_create(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let conn = ctx.pool.get()?;
+    let (owner, is_self) = match file_manager_owner(ctx, &conn, request, form_value(&form_data, "user"), stream)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let mut view = ListingView::from_params(&form_data);
+    let file = match selected_entry(&conn, &owner.username, &form_data) {
+        Ok(FileEntry::File(file)) => file,
+        _ => {
+            if !folder_exists(&conn, &owner.username, &view.dir)? {
+                view.dir.clear();
+            }
+            return render_file_manager(ctx, &conn, &owner, is_self, &view, Some("Файл не найден."), stream);
+        }
+    };
+
+    let now = get_timestamp();
+    let hours = form_value(&form_data, "expires").parse::<u64>().unwrap_or(0);
+    // Пустое поле или 0 — без ограничения
+    let max_downloads = form_value(&form_data, "max_downloads").parse::<u64>().ok().filter(|&n| n > 0);
+    let password = form_value(&form_data, "password");
+    let share = Share {
+        id: 0,
+        file_id: file.id,
+        owner: owner.username.clone(),
+        password_hash: if password.is_empty() { None } else { Some(hash_password_salted(password)?) },
+        expires_at: if hours > 0 { Some(now + hours * 60 * 60) } else { None },
+        max_downloads,
+        downloads: 0,
+        created_at: now,
+    };
+    let token = random_token(SHARE_TOKEN_BYTES)?;
+    let id = create_share(&conn, &share, &hash_password(&token))?;
+
+    let viewer = current_user(&conn, request)?.unwrap_or_default();
+    let log_entry = format!(
+        "File manager: {} created share link {} for {} of {} at {}",
+        viewer, id, file.path, owner.username, get_formatted_time()
+    );
+    log_to_file(&log_entry)?;
+
+    let link = format!("{}/s/{}", ctx.base_url, token);
+    let body = format!(
+        r#"<h1>Ссылка на {} создана</h1>
+<p><input size="80" readonly value="{}"></p>
+<p>Сохраните ее сейчас: больше она показана не будет.</p>
+<p><a href="{}">Вернуться к файлам</a></p>"#,
+        html_escape(base_name(&file.path)),
+        html_escape(&link),
+        html_escape(&format!("{}{}", files_link(&view.dir, &owner, is_self), view.query()))
+    );
+    send_html(stream, "200 OK", &page("Ссылка создана", &body))
+}
+
+// GET /s/<token> — скачивание по публичной ссылке; POST /s/<token> — то же с паролем.
+// Недействительная, истекшая и израсходованная ссылки неотличимы: везде 404
+fn handle_shared_file(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let token = &route["/s/".len()..];
+    let now = get_timestamp();
+    let found = match find_share(&conn, &hash_password(token))?.filter(|share| share.is_active(now)) {
+        Some(share) => find_file_record(&conn, share.file_id)?
+            .filter(|file| file.owner.as_deref() == Some(share.owner.as_str()))
+            .map(|file| (share, file)),
+        None => None,
+    };
+    let (share, file) = match found {
+        Some(found) => found,
+        None => {
+            let body = "<h1>404 — Ссылка недействительна</h1><p>Ссылка не существует, отозвана или истекла.</p>";
+            return send_html(stream, "404 Not Found", &page("Ссылка недействительна", body));
+        }
+    };
+
+    if let Some(password_hash) = &share.password_hash {
+        // Подбор пароля ограничивается так же, как вход, — по IP и по ссылке
+        let limiter_key = format!("share:{}", share.id);
+        if let Err(retry_after) = ctx.limiter.check(client_ip, &limiter_key) {
+            stream.write_all(too_many_requests_response(retry_after).as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
+        let password = if request.starts_with("POST ") {
+            let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+            parse_form_data(body).remove("password")
+        } else {
+            None
+        };
+        let error = match password {
+            Some(password) if verify_password_salted(&password, password_hash) => {
+                ctx.limiter.record_success(&limiter_key);
+                None
+            }
+            Some(_) => {
+                ctx.limiter.record_failure(client_ip, &limiter_key);
+                Some("<p class=\"errors\">Неверный пароль</p>")
+            }
+            None => Some(""),
+        };
+        if let Some(error) = error {
+            let body = format!(
+                r#"<h1>Файл {} защищен паролем</h1>{}
+<form method="POST" action="/s/{}">
+    <input type="password" name="password" required autofocus>
+    <button type="submit">Скачать</button>
+</form>"#,
+                html_escape(base_name(&file.path)),
+                error,
+                html_escape(token)
+            );
+            let status = if error.is_empty() { "200 OK" } else { "403 Forbidden" };
+            return send_html(stream, status, &page("Файл защищен паролем", &body));
+        }
+    }
+
+    // Лимит проверяется атомарно: параллельные скачивания не превысят max_downloads
+    if !record_share_download(&conn, share.id)? {
+        let body = "<h1>404 — Ссылка недействительна</h1><p>Ссылка не существует, отозвана или истекла.</p>";
+        return send_html(stream, "404 Not Found", &page("Ссылка недействительна", body));
+    }
+    drop(conn);
+    let log_entry = format!(
+        "[{}] Share link {} of {} downloaded ({}) at {}",
+        client_ip, share.id, share.owner, file.path, get_formatted_time()
+    );
+    log_to_file(&log_entry)?;
+    send_stored_file(stream, &file, true)
+}
+
+// Личные файлы отдаются только через проверку доступа, общие — напрямую из static/
+fn file_url(file: &FileRecord) -> String {
+    match file.owner {
+        Some(_) => format!("/download/{}", file.id),
+        None => format!("/{}", file.stored_path),
+    }
+}
+
+fn is_admin(ctx: &Context, username: &str) -> Result<bool, HttpError> {
+    Ok(ctx.users.find(username)?.is_some_and(|user| user.is_admin()))
+}
+
+// Ответ API-клиенту, не прошедшему аутентификацию
+fn send_auth_error(stream: &mut TcpStream, outcome: AuthOutcome) -> Result<(), HttpError> {
+    match outcome {
+        AuthOutcome::Forbidden => send_json(stream, "403 Forbidden", "", r#"{"error":"insufficient scope"}"#),
+        AuthOutcome::Throttled(retry_after) => send_json(
+            stream,
+            "429 Too Many Requests",
+            &format!("Retry-After: {}\r\n", retry_after),
+            r#"{"error":"too many failed attempts"}"#,
+        ),
+        _ => send_json(
+            stream,
+            "401 Unauthorized",
+            "WWW-Authenticate: Basic realm=\"web_server\", Bearer realm=\"web_server\"\r\n",
+            r#"{"error":"authentication required"}"#,
+        ),
+    }
+}
+
+fn send_json(stream: &mut TcpStream, status: &str, extra_headers: &str, body: &str) -> Result<(), HttpError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nContent-Type: application/json\r\n\r\n{}",
+        status,
+        extra_headers,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn handle_admin_disable_2fa(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let admin = match require_admin(ctx, &conn, request, stream)? {
+        Some(admin) => admin,
+        None => return Ok(()),
+    };
+
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let username = form_data.get("username").cloned().unwrap_or_default();
+
+    disable_totp(&conn, &username)?;
+    let log_entry = format!("Admin {} disabled 2FA for {} at {}", admin, username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    let response = "HTTP/1.1 303 See Other\r\nLocation: /admin\r\nContent-Length: 0\r\n\r\n";
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Отключение и включение учетной записи; у отключенного пользователя завершаются все сессии
+fn handle_admin_set_disabled(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let admin = match require_admin(ctx, &conn, request, stream)? {
+        Some(admin) => admin,
+        None => return Ok(()),
+    };
+
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let username = form_data.get("username").cloned().unwrap_or_default();
+    let disabled = form_data.get("disabled").map(String::as_str) == Some("1");
+
+    // Себя отключить нельзя по той же причине, что и удалить
+    if username != admin {
+        if let Some(mut user) = ctx.users.find(&username)? {
+            user.disabled = disabled;
+            ctx.users.update(&user)?;
+            if disabled {
+                delete_user_sessions(&conn, &username)?;
+            }
+            let action = if disabled { "disabled" } else { "enabled" };
+            let log_entry = format!("Admin {} {} user {} at {}", admin, action, username, get_formatted_time());
+            log_to_file(&log_entry)?;
+        }
+    }
+
+    let response = "HTTP/1.1 303 See Other\r\nLocation: /admin\r\nContent-Length: 0\r\n\r\n";
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Возвращает имя администратора; иначе отправляет 403 и возвращает None
+fn require_admin(
+    ctx: &Context,
+    conn: &Connection,
+    request: &str,
+    stream: &mut TcpStream,
+) -> Result<Option<String>, HttpError> {
+    let user = match current_user(conn, request)? {
+        Some(username) => ctx.users.find(&username)?,
+        None => None,
+    };
+    match user {
+        Some(user) if user.is_admin() => Ok(Some(user.username)),
+        _ => {
+            send_html(stream, "403 Forbidden", &page("Доступ запрещен", "<h1>403 — Доступ запрещен</h1><p><a href=\"/login\">Войти</a></p>"))?;
+            Ok(None)
+        }
+    }
+}
+
+// Минимальная HTML-страница для коротких сообщений
+fn page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="ru">
+<head>
+    <meta charset="UTF-8">
+    <title>{}</title>
+    <link rel="stylesheet" href="/static/styles.css">
+</head>
+<body>
+    {}
+</body>
+</html>"#,
+        title, body
+    )
+}
+
+fn send_html(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), HttpError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
     Ok(())
 }
 
@@ -290,31 +2530,136 @@ fn not_found_response() -> String {
     )
 }
 
-fn get_content_type(path: &str) -> &str {
-    match path.rsplit('.').next() {
-        Some("html") => "text/html",
-        Some("css") => "text/css",
-        Some("js") => "application/javascript",
-        Some("png") => "image/png",
-        Some("jpg" | "jpeg") => "image/jpeg",
-        Some("txt") => "text/plain",
-        _ => "application/octet-stream",
-    }
+fn too_many_requests_response(retry_after: u64) -> String {
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="ru">
+<head>
+    <meta charset="UTF-8">
+    <title>Слишком много попыток</title>
+</head>
+<body>
+    <h1>429 — Слишком много попыток входа</h1>
+    <p>Повторите попытку через {} с. <a href="/">На главную</a></p>
+</body>
+</html>"#,
+        retry_after
+    );
+
+    format!(
+        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
+        retry_after,
+        body.len(),
+        body
+    )
 }
 
-pub fn handle_admin_panel(stream: &mut TcpStream) -> Result<(), HttpError> {
-    //let conn = Connection::open("user.db")?;
-    let conn = Connection::open("users.db").map_err(HttpError::from)?;
+pub fn handle_admin_panel(request: &str, query: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let limiter = &ctx.limiter;
+    let conn = ctx.pool.get()?;
+    if require_admin(ctx, &conn, request, stream)?.is_none() {
+        return Ok(());
+    }
 
-    let mut stmt = conn.prepare("SELECT id, username FROM users")?;
-    let users_iter = stmt.query_map([], |row| {
-        Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
-    })?;
+    // Постраничный вывод пользователей: ?page=N, нумерация с 1; порядок — ?sort=...&order=asc|desc
+    let params = parse_form_data(query);
+    let sort = params
+        .get("sort")
+        .and_then(|s| UserSort::from_param(s))
+        .unwrap_or(UserSort::Id);
+    let descending = params.get("order").map(String::as_str) == Some("desc");
+    let order = if descending { "desc" } else { "asc" };
+    let total = ctx.users.count()?;
+    let pages = total.div_ceil(ADMIN_PAGE_SIZE).max(1);
+    let page_num = params
+        .get("page")
+        .and_then(|p| p.parse::<usize>().ok())
+        .unwrap_or(1)
+        .clamp(1, pages);
 
     let mut table_rows = String::new();
-    for user in users_iter {
-        let (id, username) = user?;
-        table_rows.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>", id, username));
+    for user in ctx.users.list((page_num - 1) * ADMIN_PAGE_SIZE, ADMIN_PAGE_SIZE, sort, descending)? {
+        let has_2fa = matches!(get_totp(&conn, &user.username)?, Some((_, true, _)));
+        let (id, role) = (user.id, &user.role);
+        let username = html_escape(&user.username);
+        let last_login = match (user.last_login_at, &user.last_login_ip) {
+            (Some(at), Some(ip)) => format!("{} ({})", format_timestamp(at), html_escape(ip)),
+            (Some(at), None) => format_timestamp(at),
+            _ => "никогда".to_string(),
+        };
+        let status_cell = format!(
+            r#"{} <form method="POST" action="/admin/set-disabled"><input type="hidden" name="username" value="{}"><input type="hidden" name="disabled" value="{}"><button type="submit">{}</button></form>"#,
+            if user.disabled { "отключен" } else { "активен" },
+            username,
+            if user.disabled { "0" } else { "1" },
+            if user.disabled { "Включить" } else { "Отключить" }
+        );
+        // Отключение 2FA для пользователя, потерявшего доступ к приложению и кодам
+        let totp_cell = if has_2fa {
+            format!(
+                r#"<form method="POST" action="/admin/disable-2fa"><input type="hidden" name="username" value="{}"><button type="submit">Отключить 2FA</button></form>"#,
+                username
+            )
+        } else {
+            "нет".to_string()
+        };
+        table_rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            id,
+            username,
+            html_escape(&user.display_name),
+            html_escape(&user.email),
+            html_escape(role),
+            format_timestamp(user.created_at),
+            format_timestamp(user.updated_at),
+            last_login,
+            status_cell,
+            totp_cell
+        ));
+    }
+
+    // Заголовок сортируемого столбца: повторный щелчок меняет направление
+    let sort_header = |column: UserSort, title: &str| {
+        let (next_order, mark) = match (column == sort, descending) {
+            (true, false) => ("desc", " ▲"),
+            (true, true) => ("asc", " ▼"),
+            (false, _) => ("asc", ""),
+        };
+        format!(
+            r#"<th><a href="/admin?sort={}&order={}">{}</a>{}</th>"#,
+            column.param(), next_order, title, mark
+        )
+    };
+    let header_row = format!(
+        "<tr>{}{}<th>Отображаемое имя</th><th>Email</th><th>Роль</th>{}<th>Изменен</th>{}<th>Статус</th><th>2FA</th></tr>",
+        sort_header(UserSort::Id, "ID"),
+        sort_header(UserSort::Username, "Имя пользователя"),
+        sort_header(UserSort::CreatedAt, "Создан"),
+        sort_header(UserSort::LastLogin, "Последний вход")
+    );
+
+    let mut pager = format!("Страница {} из {}", page_num, pages);
+    if page_num > 1 {
+        pager.push_str(&format!(
+            r#" | <a href="/admin?page={}&sort={}&order={}">Назад</a>"#,
+            page_num - 1, sort.param(), order
+        ));
+    }
+    if page_num < pages {
+        pager.push_str(&format!(
+            r#" | <a href="/admin?page={}&sort={}&order={}">Вперед</a>"#,
+            page_num + 1, sort.param(), order
+        ));
+    }
+
+    // Активные блокировки входа с кнопкой снятия
+    let mut lockout_rows = String::new();
+    for (key, failures, remaining) in limiter.locked() {
+        let key = html_escape(&key);
+        lockout_rows.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td>{}</td><td><form method="POST" action="/admin/unlock"><input type="hidden" name="key" value="{}"><button type="submit">Разблокировать</button></form></td></tr>"#,
+            key, failures, remaining, key
+        ));
     }
 
     let html = format!(r#"
@@ -324,20 +2669,26 @@ pub fn handle_admin_panel(stream: &mut TcpStream) -> Result<(), HttpError> {
             <meta charset="UTF-8">
             <title>Админ-панель</title>
             <style>
-                table {{ border-collapse: collapse; width: 50%; }}
+                table {{ border-collapse: collapse; width: 100%; }}
                 th, td {{ border: 1px solid black; padding: 8px; }}
             </style>
         </head>
         <body>
             <h1>Админ-панель</h1>
             <table>
-                <tr><th>ID</th><th>Имя пользователя</th></tr>
+                {}
+                {}
+            </table>
+            <p>{}</p>
+            <h2>Блокировки входа</h2>
+            <table>
+                <tr><th>Ключ</th><th>Ошибок</th><th>Осталось (с)</th><th></th></tr>
                 {}
             </table>
             <p><a href="/">На главную</a></p>
         </body>
         </html>
-    "#, table_rows);
+    "#, header_row, table_rows, pager, lockout_rows);
 
     let response = format!(
         "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
@@ -351,147 +2702,954 @@ pub fn handle_admin_panel(stream: &mut TcpStream) -> Result<(), HttpError> {
 
 }
 
-fn handle_file_manager(stream: &mut TcpStream) -> Result<(), HttpError> {
-    let files = fs::read_dir("uploads")?
-        .filter_map(Result::ok)
-        .filter(|e| e.path().is_file())
-        .map(|e| {
-            let name = e.file_name().into_string().unwrap_or_default();
-            format!(r#"<li><a href="/files/{}">{}</a></li>"#, name, name)
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
+fn handle_admin_unlock(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let limiter = &ctx.limiter;
+    let conn = ctx.pool.get()?;
+    if require_admin(ctx, &conn, request, stream)?.is_none() {
+        return Ok(());
+    }
+
+    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+    let form_data = parse_form_data(body);
+    let key = form_data.get("key").cloned().unwrap_or_default();
+
+    if limiter.unlock(&key) {
+        let log_entry = format!("Admin unlocked {} at {}", key, get_formatted_time());
+        log_to_file(&log_entry)?;
+    }
+
+    // Возвращаемся в админ-панель
+    let response = "HTTP/1.1 303 See Other\r\nLocation: /admin\r\nContent-Length: 0\r\n\r\n";
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Принимает тело запроса длиной content_length в out, не собирая его в памяти: начало тела уже
+// прочитано вместе с заголовками (raw), остальное читается из сокета. Возвращает число принятых
+// байт вместе с результатом — при обрыве соединения принятое уже записано в out
+fn receive_body<W: Write>(
+    request: &str,
+    raw: &[u8],
+    stream: &mut TcpStream,
+    content_length: u64,
+    out: &mut W,
+) -> (u64, std::io::Result<()>) {
+    // Клиент с Expect: 100-continue ждет разрешения, прежде чем слать тело
+    if get_header(request, "Expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue")) {
+        if let Err(e) = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n") {
+            return (0, Err(e));
+        }
+    }
+    let body_start = raw.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4).unwrap_or(raw.len());
+    let initial = &raw[body_start..raw.len().min(body_start + content_length as usize)];
+    if let Err(e) = out.write_all(initial) {
+        return (0, Err(e));
+    }
+    let mut received = initial.len() as u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    while received < content_length {
+        let want = ((content_length - received) as usize).min(buf.len());
+        let n = match stream.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => return (received, Err(e)),
+        };
+        if let Err(e) = out.write_all(&buf[..n]) {
+            return (received, Err(e));
+        }
+        received += n as u64;
+    }
+    (received, out.flush())
+}
 
-    let mut html = std::fs::read_to_string("file_manager.html")?;
-    html = html.replace("{{FILES}}", &files);
+// Возобновляемая загрузка по протоколу tus 1.0 (https://tus.io/protocols/resumable-upload):
+// POST /api/uploads создает загрузку, PATCH /api/uploads/<id> дописывает данные с Upload-Offset,
+// HEAD сообщает, сколько уже принято, DELETE отменяет загрузку
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
 
+// Ответ tus без тела; extra_headers — дополнительные заголовки, каждый с завершающим \r\n
+fn send_tus(stream: &mut TcpStream, status: &str, extra_headers: &str) -> Result<(), HttpError> {
     let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
-        html.len(),
-        html
+        "HTTP/1.1 {}\r\nTus-Resumable: {}\r\n{}Content-Length: 0\r\n\r\n",
+        status, TUS_VERSION, extra_headers
     );
-
     stream.write_all(response.as_bytes())?;
     stream.flush()?;
     Ok(())
 }
 
-// Обрабатывает загрузку файлов через POST /upload
-fn handle_upload(request: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
-    // Логируем полный запрос для отладки
+// Общие проверки запросов tus: версия протокола и вход с правом files:write.
+// Возвращает имя пользователя; если запрос отклонен, ответ уже отправлен
+fn tus_user(request: &str, client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<Option<String>, HttpError> {
+    if get_header(request, "Tus-Resumable") != Some(TUS_VERSION) {
+        send_tus(stream, "412 Precondition Failed", &format!("Tus-Version: {}\r\n", TUS_VERSION))?;
+        return Ok(None);
+    }
+    match authenticate(ctx, request, client_ip, SCOPE_FILES_WRITE)? {
+        AuthOutcome::Authorized(username) => Ok(Some(username)),
+        outcome => {
+            send_auth_error(stream, outcome)?;
+            Ok(None)
+        }
+    }
+}
+
+// Загрузка из адреса /api/uploads/<id>; чужая загрузка не находится
+fn tus_upload(conn: &Connection, route: &str, username: &str) -> Result<Option<Upload>, HttpError> {
+    let id = &route["/api/uploads/".len()..];
+    Ok(find_upload(conn, id)?.filter(|upload| upload.owner == username && upload.expires_at > get_timestamp()))
+}
+
+// Upload-Metadata: пары "ключ base64-значение" через запятую
+fn parse_upload_metadata(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.split_whitespace();
+            let key = parts.next()?;
+            let value = match parts.next() {
+                Some(encoded) => String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+// Сколько места занято без перезаписываемого файла и какая квота у пользователя
+fn quota_room(ctx: &Context, conn: &Connection, username: &str, path: &str) -> Result<(u64, u64), HttpError> {
+    let user = ctx.users.find(username)?.ok_or(StoreError::NotFound)?;
+    let replaced = find_user_file(conn, username, path)?.map(|file| file.size).unwrap_or(0);
+    Ok((used_bytes(conn, username)?.saturating_sub(replaced), ctx.storage.quota_for(&user)))
+}
+
+fn handle_tus_options(stream: &mut TcpStream) -> Result<(), HttpError> {
+    send_tus(
+        stream,
+        "204 No Content",
+        &format!("Tus-Version: {}\r\nTus-Extension: {}\r\n", TUS_VERSION, TUS_EXTENSIONS),
+    )
+}
+
+// POST /api/uploads: Upload-Length и Upload-Metadata (filename, необязательные dir и filetype)
+fn handle_tus_create(request: &str, client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let username = match tus_user(request, client_ip, ctx, stream)? {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let length = match get_header(request, "Upload-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) => length,
+        // Upload-Defer-Length не поддерживается: размер нужен сразу для проверки квоты
+        None => return send_tus(stream, "400 Bad Request", ""),
+    };
+    let metadata = parse_upload_metadata(get_header(request, "Upload-Metadata").unwrap_or(""));
+    let file_name = metadata.get("filename").map(String::as_str).unwrap_or("");
+    let dir = match validate_name(file_name).and_then(|_| normalize_dir(form_value(&metadata, "dir"))) {
+        Ok(dir) => dir,
+        Err(_) => return send_tus(stream, "400 Bad Request", ""),
+    };
+    if ctx.storage.user_dir(&username).is_none() {
+        return Err(HttpError::Other(format!("No storage space for user '{}'", username)));
+    }
+    let path = join_path(&dir, file_name);
+    if let Err(e) = ctx.content_policy.check_upload(&path, length) {
+        return send_tus(stream, content_status(&e), "");
+    }
+
+    let conn = ctx.pool.get()?;
+    if folder_exists(&conn, &username, &path)? {
+        return send_tus(stream, "409 Conflict", "");
+    }
+    let (used, quota) = quota_room(ctx, &conn, &username, &path)?;
+    if used + length > quota {
+        return send_tus(stream, "413 Request Entity Too Large", "");
+    }
+
+    let now = get_timestamp();
+    // Тип из метаданных (filetype) не используется: его определяет сервер по расширению
+    let content_type = content_type_for(file_name).to_string();
+    let upload = Upload {
+        id: random_token(UPLOAD_ID_BYTES)?,
+        owner: username.clone(),
+        path,
+        content_type,
+        length,
+        offset: 0,
+        created_at: now,
+        expires_at: now + UPLOAD_TTL_SECS,
+    };
+    create_upload(&conn, &ctx.storage, &upload)?;
     let log_entry = format!(
-        "Full request (first 1000 chars): {} at {}",
-        request.chars().take(1000).collect::<String>(),
-        get_formatted_time()
+        "Resumable upload {} of {} ({} bytes) started by {} at {}",
+        upload.id, upload.path, length, username, get_formatted_time()
     );
     log_to_file(&log_entry)?;
+    send_tus(
+        stream,
+        "201 Created",
+        &format!(
+            "Location: /api/uploads/{}\r\nUpload-Expires: {}\r\n",
+            upload.id,
+            http_date(upload.expires_at)
+        ),
+    )
+}
 
-    // Извлекаем Content-Length для проверки размера тела
-    let content_length = request
-        .lines()
-        .find(|line| line.starts_with("Content-Length:"))
-        .and_then(|line| line.split_whitespace().nth(1))
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(0);
-    let log_entry = format!("Content-Length: {} at {}", content_length, get_formatted_time());
+// HEAD /api/uploads/<id> — сколько байт уже принято
+fn handle_tus_head(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match tus_user(request, client_ip, ctx, stream)? {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let conn = ctx.pool.get()?;
+    match tus_upload(&conn, route, &username)? {
+        Some(upload) => send_tus(
+            stream,
+            "200 OK",
+            &format!(
+                "Upload-Offset: {}\r\nUpload-Length: {}\r\nUpload-Expires: {}\r\nCache-Control: no-store\r\n",
+                upload.offset,
+                upload.length,
+                http_date(upload.expires_at)
+            ),
+        ),
+        None => send_tus(stream, "404 Not Found", ""),
+    }
+}
+
+// PATCH /api/uploads/<id> — данные с позиции Upload-Offset. Принятое сохраняется на диске,
+// даже если соединение оборвется посреди запроса; после последнего байта файл попадает в хранилище
+fn handle_tus_patch(
+    request: &str,
+    raw: &[u8],
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match tus_user(request, client_ip, ctx, stream)? {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    if get_header(request, "Content-Type") != Some("application/offset+octet-stream") {
+        return send_tus(stream, "415 Unsupported Media Type", "");
+    }
+    let conn = ctx.pool.get()?;
+    let upload = match tus_upload(&conn, route, &username)? {
+        Some(upload) => upload,
+        None => return send_tus(stream, "404 Not Found", ""),
+    };
+    let _lock = match lock_upload(&upload.id) {
+        Some(lock) => lock,
+        None => return send_tus(stream, "423 Locked", ""),
+    };
+    if get_header(request, "Upload-Offset").and_then(|value| value.parse::<u64>().ok()) != Some(upload.offset) {
+        return send_tus(stream, "409 Conflict", &format!("Upload-Offset: {}\r\n", upload.offset));
+    }
+    let content_length = match get_header(request, "Content-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) if upload.offset + length <= upload.length => length,
+        _ => return send_tus(stream, "400 Bad Request", ""),
+    };
+    // Соединение с базой не держим, пока принимаются данные
+    drop(conn);
+
+    // Хвост после записанного смещения мог остаться от оборванного запроса — отбрасываем его
+    let part = part_path(&ctx.storage, &upload.id);
+    let mut file = fs::OpenOptions::new().write(true).open(&part)?;
+    file.set_len(upload.offset)?;
+    file.seek(SeekFrom::Start(upload.offset))?;
+
+    let (received, result) = receive_body(request, raw, stream, content_length, &mut file);
+    let offset = upload.offset + received;
+    let conn = ctx.pool.get()?;
+    set_upload_offset(&conn, &upload.id, offset, get_timestamp() + UPLOAD_TTL_SECS)?;
+    result?;
+    if received < content_length {
+        // Клиент закрыл соединение раньше времени: принятое сохранено, продолжит с нового смещения
+        return Ok(());
+    }
+
+    if offset == upload.length {
+        drop(file);
+        if ctx.content_policy.check_content(&upload.path, &read_head(&part)?).is_err() {
+            delete_upload(&conn, &ctx.storage, &upload.id)?;
+            return send_tus(stream, "415 Unsupported Media Type", "");
+        }
+        // used уже включает длину этой загрузки: она зарезервирована при создании
+        let (used, quota) = quota_room(ctx, &conn, &username, &upload.path)?;
+        if used > quota {
+            delete_upload(&conn, &ctx.storage, &upload.id)?;
+            return send_tus(stream, "413 Request Entity Too Large", "");
+        }
+        // Принятые данные уходят в карантин, сама загрузка больше не нужна
+        let quarantined = quarantine_file(&ctx.storage, &part)?;
+        delete_upload(&conn, &ctx.storage, &upload.id)?;
+        drop(conn);
+        if !scan_quarantined(ctx, &username, &upload.path, &quarantined)? {
+            return send_tus(stream, "422 Unprocessable Entity", "");
+        }
+        let conn = ctx.pool.get()?;
+        match store_user_file_from(&conn, &ctx.storage, &username, &upload.path, &quarantined, &upload.content_type) {
+            Err(FileError::Invalid(_)) => {
+                let _ = fs::remove_file(&quarantined);
+                return send_tus(stream, "409 Conflict", "");
+            }
+            result => result?,
+        };
+        let log_entry = format!(
+            "Resumable upload {} completed: {} saved for {} at {}",
+            upload.id, upload.path, username, get_formatted_time()
+        );
+        log_to_file(&log_entry)?;
+    }
+    send_tus(stream, "204 No Content", &format!("Upload-Offset: {}\r\n", offset))
+}
+
+// DELETE /api/uploads/<id> — отмена загрузки
+fn handle_tus_delete(
+    request: &str,
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let username = match tus_user(request, client_ip, ctx, stream)? {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let conn = ctx.pool.get()?;
+    let upload = match tus_upload(&conn, route, &username)? {
+        Some(upload) => upload,
+        None => return send_tus(stream, "404 Not Found", ""),
+    };
+    let _lock = match lock_upload(&upload.id) {
+        Some(lock) => lock,
+        None => return send_tus(stream, "423 Locked", ""),
+    };
+    delete_upload(&conn, &ctx.storage, &upload.id)?;
+    send_tus(stream, "204 No Content", "")
+}
+
+// Наибольший размер XML-тела запроса WebDAV
+const DAV_MAX_BODY: u64 = 64 * 1024;
+
+// Запрос WebDAV после аутентификации
+struct DavRequest<'a> {
+    request: &'a str,
+    username: String,
+    // Путь в пространстве пользователя ("" — корень)
+    path: String,
+    // XML-тело PROPFIND, PROPPATCH, LOCK и MKCOL; у остальных методов пустое
+    body: String,
+}
+
+impl DavRequest<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        get_header(self.request, name)
+    }
+
+    // Ресурс заблокирован, а токен блокировки в заголовке If не предъявлен
+    fn locked(&self, path: &str, deep: bool) -> bool {
+        !may_modify(&self.username, path, deep, &submitted_tokens(self.header("If")))
+    }
+}
+
+// Ответ WebDAV-клиенту; тело — XML (multistatus, lockdiscovery) или пустое
+fn send_dav(stream: &mut TcpStream, status: &str, extra_headers: &str, body: &str) -> Result<(), HttpError> {
+    let content_type = if body.is_empty() { "" } else { "Content-Type: application/xml; charset=utf-8\r\n" };
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}{}Content-Length: {}\r\n\r\n{}",
+        status,
+        extra_headers,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+// XML-тело запроса целиком: в первое чтение из сокета оно может не поместиться.
+// None — ответ уже отправлен или клиент ушел
+fn read_dav_body(request: &str, raw: &[u8], stream: &mut TcpStream) -> Result<Option<String>, HttpError> {
+    let length = get_header(request, "Content-Length").and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+    if length > DAV_MAX_BODY {
+        send_dav(stream, "413 Request Entity Too Large", "", "")?;
+        return Ok(None);
+    }
+    let mut body = Vec::new();
+    let (received, result) = receive_body(request, raw, stream, length, &mut body);
+    result?;
+    if received < length {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+// Файл или папка по пути из адреса WebDAV; корень — тоже папка
+fn dav_entry(conn: &Connection, username: &str, path: &str) -> Result<Option<FileEntry>, HttpError> {
+    if let Some(file) = find_user_file(conn, username, path)? {
+        return Ok(Some(FileEntry::File(file)));
+    }
+    Ok(folder_exists(conn, username, path)?.then(|| FileEntry::Folder(path.to_string())))
+}
+
+fn dav_folder_resource(conn: &Connection, username: &str, path: &str) -> Result<DavResource, HttpError> {
+    Ok(DavResource::folder(path, folder_created_at(conn, username, path)?.unwrap_or(0)))
+}
+
+// WebDAV (классы 1 и 2) поверх личного пространства пользователя: /dav/ — его корневая папка.
+// Вход — по паролю учетной записи (Basic) или персональному токену, как в API
+fn handle_webdav(
+    request: &str,
+    raw: &[u8],
+    client_ip: &str,
+    route: &str,
+    ctx: &Context,
+    stream: &mut TcpStream,
+) -> Result<(), HttpError> {
+    let method = request.split_whitespace().next().unwrap_or("");
+    // OPTIONS клиенты присылают до входа, чтобы узнать, поддерживает ли сервер WebDAV
+    if method == "OPTIONS" {
+        let headers = format!("DAV: 1, 2\r\nAllow: {}\r\nMS-Author-Via: DAV\r\n", DAV_METHODS);
+        return send_dav(stream, "200 OK", &headers, "");
+    }
+    let scope = if matches!(method, "GET" | "HEAD" | "PROPFIND") { SCOPE_FILES_READ } else { SCOPE_FILES_WRITE };
+    let username = match authenticate(ctx, request, client_ip, scope)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+    let path = match dav_path(route) {
+        Ok(path) => path,
+        Err(_) => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+    let body = if matches!(method, "PROPFIND" | "PROPPATCH" | "LOCK" | "MKCOL") {
+        match read_dav_body(request, raw, stream)? {
+            Some(body) => body,
+            None => return Ok(()),
+        }
+    } else {
+        String::new()
+    };
+    let dav = DavRequest { request, username, path, body };
+
+    match method {
+        "PROPFIND" => handle_dav_propfind(&dav, ctx, stream),
+        "PROPPATCH" => handle_dav_proppatch(&dav, ctx, stream),
+        "MKCOL" => handle_dav_mkcol(&dav, ctx, stream),
+        "GET" => handle_dav_get(&dav, false, ctx, stream),
+        "HEAD" => handle_dav_get(&dav, true, ctx, stream),
+        "PUT" => handle_dav_put(&dav, raw, ctx, stream),
+        "DELETE" => handle_dav_delete(&dav, ctx, stream),
+        "COPY" => handle_dav_copy_move(&dav, false, ctx, stream),
+        "MOVE" => handle_dav_copy_move(&dav, true, ctx, stream),
+        "LOCK" => handle_dav_lock(&dav, ctx, stream),
+        "UNLOCK" => handle_dav_unlock(&dav, stream),
+        _ => send_dav(stream, "405 Method Not Allowed", &format!("Allow: {}\r\n", DAV_METHODS), ""),
+    }
+}
+
+// PROPFIND: свойства ресурса и, для папки, ее содержимого (Depth: 0, 1 или infinity)
+fn handle_dav_propfind(dav: &DavRequest, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let props = match parse_propfind(&dav.body) {
+        Ok(props) => props,
+        Err(_) => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+    let depth = dav.header("Depth").unwrap_or("infinity");
+    let conn = ctx.pool.get()?;
+    let mut resources = Vec::new();
+    match dav_entry(&conn, &dav.username, &dav.path)? {
+        None => return send_dav(stream, "404 Not Found", "", ""),
+        Some(FileEntry::File(file)) => resources.push(DavResource::file(&file)),
+        Some(FileEntry::Folder(folder)) => {
+            resources.push(dav_folder_resource(&conn, &dav.username, &folder)?);
+            if depth != "0" {
+                let listing = if depth == "1" {
+                    list_folder(&conn, &dav.username, &folder)?
+                } else {
+                    list_subtree(&conn, &dav.username, &folder)?
+                };
+                for subfolder in &listing.folders {
+                    resources.push(dav_folder_resource(&conn, &dav.username, subfolder)?);
+                }
+                resources.extend(listing.files.iter().map(DavResource::file));
+            }
+        }
+    }
+    drop(conn);
+
+    let responses: String = resources
+        .iter()
+        .map(|resource| resource.prop_response(&props, &locks_on(&dav.username, &resource.path)))
+        .collect();
+    send_dav(stream, "207 Multi-Status", "", &multistatus(&responses))
+}
+
+// PROPPATCH: собственных свойств сервер не хранит, поэтому каждое изменение отклоняется
+fn handle_dav_proppatch(dav: &DavRequest, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let names = match parse_proppatch(&dav.body) {
+        Ok(names) => names,
+        Err(_) => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+    let conn = ctx.pool.get()?;
+    let folder = match dav_entry(&conn, &dav.username, &dav.path)? {
+        Some(entry) => matches!(entry, FileEntry::Folder(_)),
+        None => return send_dav(stream, "404 Not Found", "", ""),
+    };
+    drop(conn);
+    if dav.locked(&dav.path, false) {
+        return send_dav(stream, "423 Locked", "", "");
+    }
+    let props: String = names.iter().map(|(ns, name)| prop_name(ns, name)).collect();
+    let response = dav_response(&dav_href(&dav.path, folder), &propstat(&props, "403 Forbidden"));
+    send_dav(stream, "207 Multi-Status", "", &multistatus(&response))
+}
+
+fn handle_dav_mkcol(dav: &DavRequest, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    if !dav.body.is_empty() {
+        return send_dav(stream, "415 Unsupported Media Type", "", "");
+    }
+    let conn = ctx.pool.get()?;
+    if dav_entry(&conn, &dav.username, &dav.path)?.is_some() {
+        return send_dav(stream, "405 Method Not Allowed", &format!("Allow: {}\r\n", DAV_METHODS), "");
+    }
+    if !folder_exists(&conn, &dav.username, parent_dir(&dav.path))? {
+        return send_dav(stream, "409 Conflict", "", "");
+    }
+    if dav.locked(&dav.path, false) {
+        return send_dav(stream, "423 Locked", "", "");
+    }
+    match create_folder(&conn, &dav.username, &dav.path) {
+        Err(FileError::Invalid(_)) => return send_dav(stream, "409 Conflict", "", ""),
+        result => result?,
+    }
+    let log_entry = format!("WebDAV: {} created folder {} at {}", dav.username, dav.path, get_formatted_time());
     log_to_file(&log_entry)?;
+    send_dav(stream, "201 Created", "", "")
+}
 
-    // Извлекаем boundary из заголовка Content-Type
-    let boundary = request
-        .lines()
-        .find(|line| line.starts_with("Content-Type: multipart/form-data"))
-        .and_then(|line| line.split("boundary=").nth(1))
-        .map(|s| s.trim())
-        .ok_or_else(|| HttpError::Other("Missing boundary in Content-Type".to_string()))?;
+// GET и HEAD: содержимое файла или простая HTML-страница со списком папки
+fn handle_dav_get(dav: &DavRequest, head: bool, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    let body = match dav_entry(&conn, &dav.username, &dav.path)? {
+        None => return send_dav(stream, "404 Not Found", "", ""),
+        Some(FileEntry::File(file)) => {
+            // Файл копируется в сокет по частям, соединение с базой для этого не нужно
+            drop(conn);
+            let Some((contents, len)) = open_stored(&file) else {
+                return send_dav(stream, "404 Not Found", "", "");
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n{}ETag: {}\r\nLast-Modified: {}\r\nContent-Length: {}\r\nContent-Type: {}\r\nCache-Control: private\r\n\r\n",
+                user_content_headers(&file, false),
+                file_etag(&file),
+                http_date(file.uploaded_at),
+                len,
+                file.content_type
+            );
+            stream.write_all(response.as_bytes())?;
+            if !head {
+                std::io::copy(&mut contents.take(len), stream)?;
+            }
+            stream.flush()?;
+            return Ok(());
+        }
+        Some(FileEntry::Folder(folder)) => {
+            let listing = list_folder(&conn, &dav.username, &folder)?;
+            let mut items = String::new();
+            for subfolder in &listing.folders {
+                items.push_str(&format!(
+                    "<li><a href=\"{}\">{}/</a></li>",
+                    html_escape(&dav_href(subfolder, true)),
+                    html_escape(base_name(subfolder))
+                ));
+            }
+            for file in &listing.files {
+                items.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a> ({})</li>",
+                    html_escape(&dav_href(&file.path, false)),
+                    html_escape(base_name(&file.path)),
+                    format_size(file.size)
+                ));
+            }
+            let title = format!("/{}", html_escape(&folder));
+            let html = page(&title, &format!("<h1>{}</h1>\n    <ul>{}</ul>", title, items));
+            html.into_bytes()
+        }
+    };
+    drop(conn);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html; charset=utf-8\r\nCache-Control: private\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(response.as_bytes())?;
+    if !head {
+        stream.write_all(&body)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
 
-    // Формируем boundary с префиксом "--"
-    let boundary_str = format!("--{}", boundary);
+fn handle_dav_put(dav: &DavRequest, raw: &[u8], ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let conn = ctx.pool.get()?;
+    if dav.path.is_empty() || folder_exists(&conn, &dav.username, &dav.path)? {
+        return send_dav(stream, "405 Method Not Allowed", &format!("Allow: {}\r\n", DAV_METHODS), "");
+    }
+    // Промежуточные папки WebDAV не создает — их создают через MKCOL
+    if !folder_exists(&conn, &dav.username, parent_dir(&dav.path))? {
+        return send_dav(stream, "409 Conflict", "", "");
+    }
+    drop(conn);
+    if dav.locked(&dav.path, false) {
+        return send_dav(stream, "423 Locked", "", "");
+    }
+    match receive_user_file(dav.request, raw, ctx, &dav.username, &dav.path, stream)? {
+        PutOutcome::Stored { record, created } => {
+            let log_entry = format!(
+                "WebDAV: {} put file {} ({} bytes) at {}",
+                dav.username,
+                dav.path,
+                record.size,
+                get_formatted_time()
+            );
+            log_to_file(&log_entry)?;
+            let status = if created { "201 Created" } else { "204 No Content" };
+            send_dav(stream, status, &format!("ETag: {}\r\n", file_etag(&record)), "")
+        }
+        PutOutcome::Rejected(status, _) => send_dav(stream, status, "", ""),
+        PutOutcome::Aborted => Ok(()),
+    }
+}
 
-    // Извлекаем тело запроса
-    let body = request
-        .splitn(2, "\r\n\r\n")
-        .nth(1)
-        .ok_or_else(|| HttpError::Other("Invalid request body".to_string()))?;
+// DELETE: файл или папка уходят в корзину, как из файлового менеджера
+fn handle_dav_delete(dav: &DavRequest, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    if dav.path.is_empty() {
+        return send_dav(stream, "403 Forbidden", "", "");
+    }
+    if dav.locked(&dav.path, true) {
+        return send_dav(stream, "423 Locked", "", "");
+    }
+    let conn = ctx.pool.get()?;
+    match dav_entry(&conn, &dav.username, &dav.path)? {
+        None => return send_dav(stream, "404 Not Found", "", ""),
+        Some(FileEntry::File(file)) => trash_file(&conn, &ctx.storage, &file)?,
+        Some(FileEntry::Folder(folder)) => {
+            trash_folder(&conn, &dav.username, &folder)?;
+        }
+    }
+    drop_locks(&dav.username, &dav.path);
+    let log_entry = format!("WebDAV: {} moved {} to trash at {}", dav.username, dav.path, get_formatted_time());
+    log_to_file(&log_entry)?;
+    send_dav(stream, "204 No Content", "", "")
+}
+
+// COPY и MOVE в пределах пространства пользователя. Overwrite: T (по умолчанию) отправляет
+// занятое место назначения в корзину, Overwrite: F в этом случае отвечает 412
+fn handle_dav_copy_move(dav: &DavRequest, is_move: bool, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let destination = match dav.header("Destination").map(|value| destination_path(value, dav.header("Host"))) {
+        Some(Some(Ok(destination))) => destination,
+        // Копирование на другой сервер не поддерживается
+        Some(None) => return send_dav(stream, "502 Bad Gateway", "", ""),
+        _ => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+    let inside_source = destination.starts_with(&format!("{}/", dav.path));
+    if dav.path.is_empty() || destination.is_empty() || destination == dav.path || inside_source {
+        return send_dav(stream, "403 Forbidden", "", "");
+    }
+    let overwrite = !dav.header("Overwrite").is_some_and(|value| value.eq_ignore_ascii_case("F"));
+
+    let conn = ctx.pool.get()?;
+    let source = match dav_entry(&conn, &dav.username, &dav.path)? {
+        Some(source) => source,
+        None => return send_dav(stream, "404 Not Found", "", ""),
+    };
+    if !folder_exists(&conn, &dav.username, parent_dir(&destination))? {
+        return send_dav(stream, "409 Conflict", "", "");
+    }
+    if (is_move && dav.locked(&dav.path, true)) || dav.locked(&destination, true) {
+        return send_dav(stream, "423 Locked", "", "");
+    }
+    // Переименование не должно обходить список разрешенных типов
+    if matches!(source, FileEntry::File(_)) && ctx.content_policy.check_type(&destination).is_err() {
+        return send_dav(stream, "415 Unsupported Media Type", "", "");
+    }
+    let existing = dav_entry(&conn, &dav.username, &destination)?;
+    if existing.is_some() && !overwrite {
+        return send_dav(stream, "412 Precondition Failed", "", "");
+    }
+    if !is_move {
+        // Копия занимает квоту, даже если содержимое на диске общее; замененное уходит в корзину и тоже считается
+        let size = match &source {
+            FileEntry::File(file) => file.size,
+            FileEntry::Folder(folder) => list_subtree(&conn, &dav.username, folder)?.files.iter().map(|file| file.size).sum(),
+        };
+        let user = ctx.users.find(&dav.username)?.ok_or(StoreError::NotFound)?;
+        if used_bytes(&conn, &dav.username)? + size > ctx.storage.quota_for(&user) {
+            return send_dav(stream, "507 Insufficient Storage", "", "");
+        }
+    }
+
+    match &existing {
+        Some(FileEntry::File(file)) => trash_file(&conn, &ctx.storage, file)?,
+        Some(FileEntry::Folder(folder)) => {
+            trash_folder(&conn, &dav.username, folder)?;
+        }
+        None => {}
+    }
+    drop_locks(&dav.username, &destination);
+    let result = match &source {
+        FileEntry::File(file) if is_move => move_file(&conn, &dav.username, file, &destination),
+        FileEntry::Folder(folder) if is_move => move_folder(&conn, &dav.username, folder, &destination),
+        FileEntry::File(file) => copy_file(&conn, &ctx.storage, &dav.username, file, &destination).map(|_| ()),
+        // Depth: 0 копирует только саму папку, без содержимого
+        FileEntry::Folder(_) if dav.header("Depth") == Some("0") => create_folder(&conn, &dav.username, &destination),
+        FileEntry::Folder(folder) => copy_folder(&conn, &ctx.storage, &dav.username, folder, &destination).map(|_| ()),
+    };
+    match result {
+        Err(FileError::Invalid(_)) => return send_dav(stream, "409 Conflict", "", ""),
+        result => result?,
+    }
+    if is_move {
+        drop_locks(&dav.username, &dav.path);
+    }
 
-    // Логируем тело запроса
     let log_entry = format!(
-        "Upload body (first 1000 chars): {} at {}",
-        body.chars().take(1000).collect::<String>(),
+        "WebDAV: {} {} {} to {} at {}",
+        dav.username,
+        if is_move { "moved" } else { "copied" },
+        dav.path,
+        destination,
         get_formatted_time()
     );
     log_to_file(&log_entry)?;
+    let status = if existing.is_some() { "204 No Content" } else { "201 Created" };
+    send_dav(stream, status, "", "")
+}
 
-    // Разделяем тело на части по boundary
-    let parts = body.split(&boundary_str).filter(|part| {
-        let trimmed = part.trim();
-        !trimmed.is_empty() && trimmed != "--" && trimmed != "--\r\n" && !trimmed.starts_with("--")
-    });
+// LOCK: новая блокировка или, без тела, продление уже взятой
+fn handle_dav_lock(dav: &DavRequest, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let timeout = lock_timeout(dav.header("Timeout"));
+    if dav.body.trim().is_empty() {
+        let tokens = submitted_tokens(dav.header("If"));
+        return match refresh_lock(&dav.username, &dav.path, &tokens, timeout) {
+            Some(lock) => send_dav(stream, "200 OK", "", &lock_response(&lock)),
+            None => send_dav(stream, "412 Precondition Failed", "", ""),
+        };
+    }
+    let info = match parse_lockinfo(&dav.body) {
+        Ok(info) => info,
+        Err(_) => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+
+    let conn = ctx.pool.get()?;
+    let exists = dav_entry(&conn, &dav.username, &dav.path)?.is_some();
+    if !exists && !folder_exists(&conn, &dav.username, parent_dir(&dav.path))? {
+        return send_dav(stream, "409 Conflict", "", "");
+    }
+    if !exists && ctx.content_policy.check_type(&dav.path).is_err() {
+        return send_dav(stream, "415 Unsupported Media Type", "", "");
+    }
+    let lock = DavLock {
+        token: new_lock_token()?,
+        owner: dav.username.clone(),
+        path: dav.path.clone(),
+        deep: dav.header("Depth") != Some("0"),
+        shared: info.shared,
+        holder: info.holder,
+        expires_at: get_timestamp() + timeout,
+    };
+    let lock = match acquire_lock(lock) {
+        Some(lock) => lock,
+        None => return send_dav(stream, "423 Locked", "", ""),
+    };
+    // Блокировка свободного имени создает пустой файл (RFC 4918, раздел 7.3)
+    if !exists {
+        if let Err(e) = store_user_file(&conn, &ctx.storage, &dav.username, &dav.path, &[], content_type_for(&dav.path)) {
+            release_lock(&dav.username, &dav.path, &lock.token);
+            return Err(e.into());
+        }
+    }
+    let status = if exists { "200 OK" } else { "201 Created" };
+    send_dav(stream, status, &format!("Lock-Token: <{}>\r\n", lock.token), &lock_response(&lock))
+}
+
+fn handle_dav_unlock(dav: &DavRequest, stream: &mut TcpStream) -> Result<(), HttpError> {
+    let token = match submitted_tokens(dav.header("Lock-Token")).into_iter().next() {
+        Some(token) => token,
+        None => return send_dav(stream, "400 Bad Request", "", ""),
+    };
+    if release_lock(&dav.username, &dav.path, &token) {
+        send_dav(stream, "204 No Content", "", "")
+    } else {
+        send_dav(stream, "409 Conflict", "", "")
+    }
+}
+
+// Страница с причиной, по которой файл из формы не загружен
+fn send_upload_error(stream: &mut TcpStream, status: &str, message: &str) -> Result<(), HttpError> {
+    let body = format!(r#"<h1>Файл не загружен</h1><p>{}</p><p><a href="/files">Мои файлы</a></p>"#, html_escape(message));
+    send_html(stream, status, &page("Файл не загружен", &body))
+}
 
-    let mut file_name = String::new();
-    let mut file_content = Vec::new();
+// Обрабатывает загрузку файлов через POST /upload
+fn handle_upload(request: &str, raw: &[u8], client_ip: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
+    // Загружать могут только вошедшие пользователи или скрипты с токеном files:write
+    let username = match authenticate(ctx, request, client_ip, SCOPE_FILES_WRITE)? {
+        AuthOutcome::Authorized(username) => username,
+        outcome => return send_auth_error(stream, outcome),
+    };
+
+    // Заголовки и тело в журнал не пишем: в них пароли, токены и cookie
+    let log_entry = format!("POST /upload by {} at {}", username, get_formatted_time());
+    log_to_file(&log_entry)?;
+
+    let boundary = match get_header(request, "Content-Type").and_then(multipart_boundary) {
+        Some(boundary) => boundary,
+        None => return send_upload_error(stream, "400 Bad Request", "Ожидается форма multipart/form-data."),
+    };
+    let content_length = match get_header(request, "Content-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => return send_upload_error(stream, "411 Length Required", "Не указана длина запроса."),
+    };
+    if ctx.storage.user_dir(&username).is_none() {
+        return Err(HttpError::Other(format!("No storage space for user '{}'", username)));
+    }
 
-    // Обрабатываем каждую часть multipart
-    for part in parts {
+    // Имя файла станет известно только из тела, поэтому до приема заявленная длина сверяется
+    // с общим ограничением размера и свободным местом с запасом на разметку формы
+    let conn = ctx.pool.get()?;
+    let user = ctx.users.find(&username)?.ok_or(StoreError::NotFound)?;
+    let quota = ctx.storage.quota_for(&user);
+    let free = quota.saturating_sub(used_bytes(&conn, &username)?);
+    drop(conn);
+    let too_large = ctx.content_policy.max_file_bytes.is_some_and(|limit| content_length > limit + MULTIPART_OVERHEAD);
+    if too_large || content_length > free + MULTIPART_OVERHEAD {
         let log_entry = format!(
-            "Processing part (first 500 chars): {} at {}",
-            part.chars().take(500).collect::<String>(),
-            get_formatted_time()
+            "Upload by {} rejected: body of {} bytes is too large at {}",
+            username, content_length, get_formatted_time()
         );
         log_to_file(&log_entry)?;
+        return send_upload_error(stream, "413 Payload Too Large", "Файл слишком большой или не помещается в квоту.");
+    }
 
-        // Проверяем наличие Content-Disposition и name="file"
-        if part.contains("Content-Disposition: form-data") && part.contains("name=\"file\"") {
-            // Извлекаем имя файла
-            let lines: Vec<&str> = part.lines().collect();
-            for line in &lines {
-                if line.contains("filename=\"") && !line.contains("filename=\"\"") {
-                    if let Some(name) = line
-                        .split("filename=\"")
-                        .nth(1)
-                        .and_then(|s| s.split('\"').next())
-                        .filter(|s| !s.is_empty())
-                    {
-                        file_name = name.to_string();
-                        break;
-                    }
-                }
-            }
-
-            // Извлекаем содержимое файла
-            if let Some(content_start) = part.find("\r\n\r\n") {
-                let content = &part[content_start + 4..];
-                let content_end = content
-                    .rfind("\r\n--")
-                    .filter(|&end| end > 0)
-                    .unwrap_or_else(|| content.rfind("\r\n").unwrap_or(content.len()));
-                if content_end > 0 {
-                    file_content = content[..content_end].as_bytes().to_vec();
-                }
+    // Тело читается целиком по Content-Length; содержимое файла сразу пишется во временный файл в хранилище
+    let temp = temp_upload_path(&ctx.storage)?;
+    let mut parser = MultipartWriter::new(&boundary, BufWriter::new(fs::File::create(&temp)?));
+    let (received, result) = receive_body(request, raw, stream, content_length, &mut parser);
+    let parsed = result.and_then(|_| parser.finish());
+    let form = match parsed {
+        Ok((form, file)) => {
+            drop(file);
+            form
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            if received < content_length && e.kind() != std::io::ErrorKind::InvalidData {
+                // Клиент ушел, не дослав тело, — отвечать некому
+                return Ok(());
             }
+            let log_entry = format!("Failed upload by {}: {} at {}", username, e, get_formatted_time());
+            log_to_file(&log_entry)?;
+            return send_upload_error(stream, "400 Bad Request", "Форма загрузки повреждена.");
         }
-    }
+    };
 
-    // Проверяем, были ли извлечены имя и содержимое файла
-    if file_name.is_empty() || file_content.is_empty() {
+    let file_name = form.file_name.unwrap_or_default();
+    // Папка назначения из файлового менеджера (поле dir)
+    let upload_dir = form.fields.get("dir").cloned().unwrap_or_default();
+    if file_name.is_empty() || form.file_size == 0 {
+        let _ = fs::remove_file(&temp);
         let log_entry = format!(
             "Failed upload: file_name='{}', content_len={} at {}",
-            file_name, file_content.len(), get_formatted_time()
+            file_name, form.file_size, get_formatted_time()
+        );
+        log_to_file(&log_entry)?;
+        return send_upload_error(stream, "400 Bad Request", "Выберите непустой файл.");
+    }
+
+    // Оставляем только имя: каталоги в filename не должны влиять на то, куда попадет файл
+    let file_name = std::path::Path::new(&file_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let target = validate_name(&file_name).and_then(|_| normalize_dir(&upload_dir));
+    let upload_dir = match target {
+        Ok(dir) => dir,
+        Err(error) => {
+            let _ = fs::remove_file(&temp);
+            return send_upload_error(stream, "400 Bad Request", &error);
+        }
+    };
+    let file_path = join_path(&upload_dir, &file_name);
+
+    // Тип определяется по расширению и должен совпадать с содержимым; тип, указанный браузером, не используется
+    let head = read_head(&temp)?;
+    let checked = ctx
+        .content_policy
+        .check_upload(&file_path, form.file_size)
+        .and_then(|_| ctx.content_policy.check_content(&file_path, &head));
+    if let Err(error) = checked {
+        let _ = fs::remove_file(&temp);
+        let log_entry = format!(
+            "Upload of {} by {} rejected: {} at {}",
+            file_name, username, error.message(), get_formatted_time()
         );
         log_to_file(&log_entry)?;
-        return Err(HttpError::Other("Invalid file upload: missing file name or content".to_string()));
+        return send_upload_error(stream, content_status(&error), error.message());
     }
 
-    // Создаем папку static/uploads/
-    fs::create_dir_all("static/uploads")?;
+    // Проверяем квоту; перезаписываемый файл освобождает свое место
+    let conn = ctx.pool.get()?;
+    let (used, quota) = quota_room(ctx, &conn, &username, &file_path)?;
+    drop(conn);
+    if used + form.file_size > quota {
+        let _ = fs::remove_file(&temp);
+        let log_entry = format!(
+            "Upload of {} by {} rejected: quota {} exceeded at {}",
+            file_name, username, quota, get_formatted_time()
+        );
+        log_to_file(&log_entry)?;
+        let body = format!(
+            r#"<h1>Недостаточно места</h1><p>Файл {} ({}) не помещается в квоту: занято {} из {}.</p><p><a href="/files">Мои файлы</a></p>"#,
+            html_escape(&file_name),
+            format_size(form.file_size),
+            format_size(used),
+            format_size(quota)
+        );
+        return send_html(stream, "413 Payload Too Large", &page("Недостаточно места", &body));
+    }
 
-    // Формируем путь для сохранения файла
-    let file_path = format!("static/uploads/{}", file_name);
+    // Файл становится виден только после проверки сканером
+    let quarantined = quarantine_file(&ctx.storage, &temp)?;
+    if !scan_quarantined(ctx, &username, &file_path, &quarantined)? {
+        let body = format!(
+            r#"<h1>Файл не загружен</h1><p>Файл {} не прошел проверку на вирусы.</p><p><a href="/files">Мои файлы</a></p>"#,
+            html_escape(&file_name)
+        );
+        return send_html(stream, "422 Unprocessable Entity", &page("Файл не загружен", &body));
+    }
 
-    // Сохраняем файл
-    fs::write(&file_path, &file_content)?;
+    // Сохраняем файл и запоминаем, кто и что загрузил; одинаковое содержимое хранится один раз
+    let conn = ctx.pool.get()?;
+    match store_user_file_from(&conn, &ctx.storage, &username, &file_path, &quarantined, content_type_for(&file_name)) {
+        Err(FileError::Invalid(error)) => {
+            let _ = fs::remove_file(&quarantined);
+            return send_upload_error(stream, "400 Bad Request", &error);
+        }
+        result => result?,
+    };
 
     // Логируем успешную загрузку
-    let log_entry = format!("Uploaded file {} at {}", file_name, get_formatted_time());
+    let log_entry = format!("Uploaded file {} by {} at {}", file_name, username, get_formatted_time());
     log_to_file(&log_entry)?;
 
     // Формируем HTML-ответ с подтверждением
@@ -505,10 +3663,11 @@ fn handle_upload(request: &str, stream: &mut TcpStream) -> Result<(), HttpError>
 </head>
 <body>
     <h1>Файл {} загружен</h1>
-    <p><a href="/files">Посмотреть файлы</a> | <a href="/upload">Загрузить ещё</a> | <a href="/">На главную</a></p>
+    <p><a href="/files?dir={}">Посмотреть файлы</a> | <a href="/upload">Загрузить ещё</a> | <a href="/">На главную</a></p>
 </body>
 </html>"#,
-        file_name
+        html_escape(&file_path),
+        html_escape(&urlencoding::encode(&upload_dir))
     );
 
     // Формируем HTTP-ответ
@@ -525,16 +3684,16 @@ fn handle_upload(request: &str, stream: &mut TcpStream) -> Result<(), HttpError>
     Ok(())
 }
 
-fn handle_save(request: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
+fn handle_save(request: &str, ctx: &Context, stream: &mut TcpStream) -> Result<(), HttpError> {
     // Извлекаем тело запроса
     let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
     // Парсим данные формы (application/x-www-form-urlencoded)
     let form_data = parse_form_data(body);
     let content = form_data.get("content").cloned().unwrap_or_default();
-    let filename = "user_content.txt";
 
-    // Сохраняем текст в файл
-    fs::write(filename, content.as_bytes())?;
+    // Сохраняем текст в файл; заметка ищется через /search
+    fs::write(NOTE_FILE, content.as_bytes())?;
+    index_note(&*ctx.pool.get()?, &content)?;
 
     // Формируем HTML-ответ
     let response_body = r#"<!DOCTYPE html>
@@ -561,3 +3720,130 @@ fn handle_save(request: &str, stream: &mut TcpStream) -> Result<(), HttpError> {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    use crate::context::TestContext;
+    use crate::files::store_user_file;
+    use crate::limiter::{Clock, LimiterConfig, LoginLimiter};
+    use crate::shares::{create_share, Share};
+
+    // Часы стоят на месте: задержки ограничителя не истекают посреди теста
+    struct FrozenClock;
+
+    impl Clock for FrozenClock {
+        fn now(&self) -> u64 {
+            1_000_000
+        }
+    }
+
+    // Ответ обработчика целиком: он пишет в локальный сокет, ответ читается с другого конца
+    fn respond(handler: impl FnOnce(&mut TcpStream) -> Result<(), HttpError>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(handler(&mut server).is_ok());
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn status(response: &str) -> &str {
+        response.split("\r\n").next().unwrap_or("")
+    }
+
+    // Файл bg и две ссылки на него с паролем "pass 1": /s/first и /s/second
+    fn shared_file(name: &str) -> TestContext {
+        let mut test = TestContext::new(name);
+        test.ctx.limiter = LoginLimiter::new(LimiterConfig::default(), FrozenClock);
+        let conn = test.ctx.pool.get().unwrap();
+        let file = store_user_file(&conn, &test.ctx.storage, "bg", "secret.txt", b"top secret", "text/plain").unwrap();
+        for token in ["first", "second"] {
+            let share = Share {
+                id: 0,
+                file_id: file.id,
+                owner: "bg".to_string(),
+                password_hash: Some(hash_password_salted("pass 1").unwrap()),
+                expires_at: None,
+                max_downloads: None,
+                downloads: 0,
+                created_at: 0,
+            };
+            create_share(&conn, &share, &hash_password(token)).unwrap();
+        }
+        drop(conn);
+        test
+    }
+
+    fn open_share(ctx: &Context, token: &str, password: Option<&str>, ip: &str) -> String {
+        let route = format!("/s/{}", token);
+        let request = match password {
+            Some(password) => format!(
+                "POST {} HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\npassword={}",
+                route,
+                urlencoding::encode(password)
+            ),
+            None => format!("GET {} HTTP/1.1\r\n\r\n", route),
+        };
+        respond(|stream| handle_shared_file(&request, ip, &route, ctx, stream))
+    }
+
+    #[test]
+    fn password_protected_share() {
+        let test = shared_file("share-password");
+        let ctx = &test.ctx;
+        // Без пароля — форма, а не содержимое
+        let response = open_share(ctx, "first", None, "192.0.2.1");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains(r#"name="password""#));
+        assert!(!response.contains("top secret"));
+
+        let response = open_share(ctx, "first", Some("pass 1"), "192.0.2.1");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("top secret"));
+
+        let response = open_share(ctx, "unknown", Some("pass 1"), "192.0.2.1");
+        assert_eq!(status(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn share_password_attempts_are_throttled() {
+        let test = shared_file("share-throttle");
+        let ctx = &test.ctx;
+        let response = open_share(ctx, "first", Some("wrong"), "192.0.2.1");
+        assert_eq!(status(&response), "HTTP/1.1 403 Forbidden");
+        assert!(!response.contains("top secret"));
+
+        // Пока идет задержка, не проходит даже верный пароль — ни с этого адреса, ни с другого
+        let response = open_share(ctx, "first", Some("pass 1"), "192.0.2.1");
+        assert_eq!(status(&response), "HTTP/1.1 429 Too Many Requests");
+        let response = open_share(ctx, "first", Some("pass 1"), "192.0.2.2");
+        assert_eq!(status(&response), "HTTP/1.1 429 Too Many Requests");
+
+        // Задержка привязана к ссылке share:{id}: другая ссылка с другого адреса открывается
+        let response = open_share(ctx, "second", Some("pass 1"), "192.0.2.3");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("top secret"));
+    }
+
+    #[test]
+    fn upload_metadata() {
+        let metadata = parse_upload_metadata("filename 0L7RgtGH0LXRgi50eHQ=,filetype dGV4dC9wbGFpbg==, is_confidential");
+        assert_eq!(metadata.get("filename").map(String::as_str), Some("отчет.txt"));
+        assert_eq!(metadata.get("filetype").map(String::as_str), Some("text/plain"));
+        // Ключ без значения допустим
+        assert_eq!(metadata.get("is_confidential").map(String::as_str), Some(""));
+        assert_eq!(metadata.len(), 3);
+
+        // Пара с неверным base64 или не-UTF-8 значением отбрасывается, остальные остаются
+        let metadata = parse_upload_metadata("filename !!!,dir ZG9jcw==,bad /w==");
+        assert!(!metadata.contains_key("filename"));
+        assert!(!metadata.contains_key("bad"));
+        assert_eq!(metadata.get("dir").map(String::as_str), Some("docs"));
+        assert!(parse_upload_metadata("").is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::files::{purge_expired_trash, StorageConfig};
+use crate::pool::Pool;
+use crate::scanner::{purge_stale_quarantine, QUARANTINE_MAX_AGE_SECS};
+use crate::uploads::purge_expired_uploads;
+use crate::utils::{get_formatted_time, get_timestamp, log_to_file};
+
+// Как часто проверяется корзина
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Фоновый поток: раз в JANITOR_INTERVAL окончательно удаляет файлы,
+// пролежавшие в корзине дольше storage.trash_retention_secs, брошенные загрузки
+// и файлы, оставшиеся в карантине после прерванной проверки
+pub fn start_janitor(pool: Arc<Pool>, storage: StorageConfig) {
+    thread::spawn(move || loop {
+        if let Err(e) = cleanup(&pool, &storage) {
+            let error_msg = format!("Janitor error: {}", e);
+            eprintln!("{}", error_msg);
+            let _ = log_to_file(&error_msg).map_err(|e| eprintln!("Log error: {}", e));
+        }
+        thread::sleep(JANITOR_INTERVAL);
+    });
+}
+
+fn cleanup(pool: &Pool, storage: &StorageConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = pool.get()?;
+    let now = get_timestamp();
+    let purged = purge_expired_trash(&conn, storage, now.saturating_sub(storage.trash_retention_secs))?;
+    if purged > 0 {
+        log_to_file(&format!("Janitor: purged {} files from trash at {}", purged, get_formatted_time()))?;
+    }
+    let abandoned = purge_expired_uploads(&conn, storage, now)?;
+    if abandoned > 0 {
+        log_to_file(&format!("Janitor: removed {} abandoned uploads at {}", abandoned, get_formatted_time()))?;
+    }
+    let stale = purge_stale_quarantine(storage, Duration::from_secs(QUARANTINE_MAX_AGE_SECS))?;
+    if stale > 0 {
+        log_to_file(&format!("Janitor: removed {} stale files from quarantine at {}", stale, get_formatted_time()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::utils::{get_formatted_time, get_timestamp, log_to_file};
+
+// Источник времени. Вынесен в трейт, чтобы в тестах можно было подставить фиктивные часы
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        get_timestamp()
+    }
+}
+
+// Параметры ограничения попыток входа
+pub struct LimiterConfig {
+    // Количество неудачных попыток до временной блокировки
+    pub max_failures: u32,
+    // Начальная задержка после неудачной попытки (секунды), удваивается с каждой ошибкой
+    pub base_delay_secs: u64,
+    // Максимальная задержка между попытками (секунды)
+    pub max_delay_secs: u64,
+    // Длительность блокировки после max_failures ошибок (секунды)
+    pub lockout_secs: u64,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        LimiterConfig {
+            max_failures: 5,
+            base_delay_secs: 1,
+            max_delay_secs: 60,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+// Устаревшие записи удаляются не чаще, чем раз в столько секунд
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+#[derive(Default)]
+struct Attempts {
+    failures: u32,
+    // Момент, раньше которого новая попытка отклоняется
+    blocked_until: u64,
+    locked: bool,
+}
+
+impl Attempts {
+    // Запись больше не влияет на вход: блокировка снята, а ошибки без блокировки
+    // забываются, если после задержки прошло lockout_secs без новых попыток
+    fn expired(&self, now: u64, config: &LimiterConfig) -> bool {
+        let forget_after = if self.locked { 0 } else { config.lockout_secs };
+        self.blocked_until.saturating_add(forget_after) <= now
+    }
+}
+
+// Учет неудачных попыток входа по IP-адресу и по имени пользователя
+pub struct LoginLimiter {
+    clock: Box<dyn Clock>,
+    config: LimiterConfig,
+    attempts: Mutex<HashMap<String, Attempts>>,
+    // Когда последний раз удаляли устаревшие записи
+    pruned_at: AtomicU64,
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+impl LoginLimiter {
+    pub fn new(config: LimiterConfig, clock: impl Clock + 'static) -> Self {
+        LoginLimiter {
+            clock: Box::new(clock),
+            config,
+            attempts: Mutex::new(HashMap::new()),
+            pruned_at: AtomicU64::new(0),
+        }
+    }
+
+    // Удаляет устаревшие записи, иначе таблица растет с каждым новым адресом и именем
+    fn prune(&self, attempts: &mut HashMap<String, Attempts>, now: u64) {
+        if now < self.pruned_at.load(Ordering::Relaxed).saturating_add(PRUNE_INTERVAL_SECS) {
+            return;
+        }
+        self.pruned_at.store(now, Ordering::Relaxed);
+        attempts.retain(|_, a| !a.expired(now, &self.config));
+    }
+
+    // Возвращает Err(секунды до следующей попытки), если вход сейчас запрещен
+    pub fn check(&self, ip: &str, username: &str) -> Result<(), u64> {
+        let now = self.clock.now();
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let retry_after = [ip_key(ip), user_key(username)]
+            .iter()
+            .filter_map(|key| attempts.get(key))
+            .map(|a| a.blocked_until.saturating_sub(now))
+            .max()
+            .unwrap_or(0);
+        if retry_after > 0 {
+            Err(retry_after)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Регистрирует неудачную попытку и назначает задержку или блокировку
+    pub fn record_failure(&self, ip: &str, username: &str) {
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        self.prune(&mut attempts, now);
+        for key in [ip_key(ip), user_key(username)] {
+            let entry = attempts.entry(key.clone()).or_default();
+            // После истечения записи счетчик начинается заново
+            if entry.expired(now, &self.config) {
+                *entry = Attempts::default();
+            }
+            entry.failures += 1;
+
+            if entry.failures >= self.config.max_failures {
+                entry.blocked_until = now + self.config.lockout_secs;
+                if !entry.locked {
+                    entry.locked = true;
+                    let log_entry = format!(
+                        "Lockout {} after {} failed logins for {} s at {}",
+                        key, entry.failures, self.config.lockout_secs, get_formatted_time()
+                    );
+                    let _ = log_to_file(&log_entry).map_err(|e| eprintln!("Log error: {}", e));
+                }
+            } else {
+                let shift = (entry.failures - 1).min(63);
+                let delay = self
+                    .config
+                    .base_delay_secs
+                    .saturating_mul(1u64 << shift)
+                    .min(self.config.max_delay_secs);
+                entry.blocked_until = now + delay;
+            }
+        }
+    }
+
+    // Успешный вход сбрасывает счетчик пользователя (счетчик IP не трогаем)
+    pub fn record_success(&self, username: &str) {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.remove(&user_key(username));
+    }
+
+    // Снятие блокировки администратором. key — "ip:<адрес>" или "user:<имя>"
+    pub fn unlock(&self, key: &str) -> bool {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.remove(key).is_some()
+    }
+
+    // Список активных блокировок: (ключ, число ошибок, секунд до снятия)
+    pub fn locked(&self) -> Vec<(String, u32, u64)> {
+        let now = self.clock.now();
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut locked: Vec<(String, u32, u64)> = attempts
+            .iter()
+            .filter(|(_, a)| a.locked && a.blocked_until > now)
+            .map(|(key, a)| (key.clone(), a.failures, a.blocked_until - now))
+            .collect();
+        locked.sort();
+        locked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Часы, которые тест переводит вручную
+    #[derive(Clone, Default)]
+    struct ManualClock(Arc<AtomicU64>);
+
+    impl ManualClock {
+        fn advance(&self, secs: u64) {
+            self.0.fetch_add(secs, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn limiter() -> (LoginLimiter, ManualClock) {
+        let clock = ManualClock::default();
+        clock.advance(1_000);
+        let config = LimiterConfig { max_failures: 3, base_delay_secs: 2, max_delay_secs: 5, lockout_secs: 100 };
+        (LoginLimiter::new(config, clock.clone()), clock)
+    }
+
+    fn entries(limiter: &LoginLimiter) -> usize {
+        limiter.attempts.lock().unwrap().len()
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let (limiter, clock) = limiter();
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Ok(()));
+        limiter.record_failure("1.1.1.1", "bob");
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(2));
+        clock.advance(2);
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Ok(()));
+        limiter.record_failure("1.1.1.1", "bob");
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(4));
+        // Другой адрес и другое имя не затронуты
+        assert_eq!(limiter.check("2.2.2.2", "alice"), Ok(()));
+        assert_eq!(limiter.check("2.2.2.2", "bob"), Err(4));
+    }
+
+    #[test]
+    fn lockout_after_max_failures_and_expiry() {
+        let (limiter, clock) = limiter();
+        for _ in 0..3 {
+            limiter.record_failure("1.1.1.1", "bob");
+        }
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(100));
+        let locked = limiter.locked();
+        assert_eq!(locked, vec![("ip:1.1.1.1".to_string(), 3, 100), ("user:bob".to_string(), 3, 100)]);
+
+        clock.advance(100);
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Ok(()));
+        assert!(limiter.locked().is_empty());
+        // После блокировки счет начинается заново: одна ошибка — обычная задержка
+        limiter.record_failure("1.1.1.1", "bob");
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(2));
+    }
+
+    #[test]
+    fn success_resets_user_but_not_ip() {
+        let (limiter, _clock) = limiter();
+        limiter.record_failure("1.1.1.1", "bob");
+        limiter.record_failure("1.1.1.1", "bob");
+        limiter.record_success("bob");
+        assert_eq!(limiter.check("3.3.3.3", "bob"), Ok(()));
+        assert_eq!(limiter.check("1.1.1.1", "alice"), Err(4));
+    }
+
+    #[test]
+    fn unlock_removes_lockout() {
+        let (limiter, _clock) = limiter();
+        for _ in 0..3 {
+            limiter.record_failure("1.1.1.1", "bob");
+        }
+        assert!(limiter.unlock("user:bob"));
+        assert!(!limiter.unlock("user:bob"));
+        assert_eq!(limiter.check("9.9.9.9", "bob"), Ok(()));
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(100));
+    }
+
+    #[test]
+    fn expired_entries_are_evicted() {
+        let (limiter, clock) = limiter();
+        for i in 0..10 {
+            limiter.record_failure(&format!("10.0.0.{}", i), &format!("user{}", i));
+        }
+        for _ in 0..3 {
+            limiter.record_failure("1.1.1.1", "bob");
+        }
+        assert_eq!(entries(&limiter), 22);
+
+        // Записи без блокировки забываются через lockout_secs после задержки, блокировки — по истечении
+        clock.advance(PRUNE_INTERVAL_SECS + 200);
+        limiter.record_failure("5.5.5.5", "carol");
+        assert_eq!(entries(&limiter), 2);
+    }
+
+    #[test]
+    fn failures_are_remembered_between_delays() {
+        let (limiter, clock) = limiter();
+        // Выждав задержку, нельзя обнулить счетчик: запись живет еще lockout_secs
+        for _ in 0..3 {
+            clock.advance(PRUNE_INTERVAL_SECS);
+            limiter.record_failure("1.1.1.1", "bob");
+        }
+        assert_eq!(limiter.check("1.1.1.1", "bob"), Err(100));
+    }
+}
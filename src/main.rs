@@ -1,22 +1,110 @@
 use std::error::Error;
 use std::net::TcpListener;
+use std::sync::Arc;
 
-use crate::db::init_db;
+use crate::content_policy::ContentPolicy;
+use crate::context::Context;
+use crate::db::{count_admins, init_db, DB_PATH};
+use crate::files::StorageConfig;
+use crate::janitor::start_janitor;
+use crate::limiter::{LimiterConfig, LoginLimiter, SystemClock};
+use crate::notifier::OutboxNotifier;
+use crate::policy::RegistrationPolicy;
+use crate::pool::Pool;
+use crate::scanner::{CommandScanner, NoopScanner, UploadScanner};
+use crate::store::SqliteUserStore;
 use crate::server::start_server;
 
+mod archive;
+mod auth;
+mod cli;
+mod content_policy;
+mod context;
 mod db;
+mod files;
 mod handlers;
+mod janitor;
+mod limiter;
+mod migrations;
+mod multipart;
+mod notifier;
+mod policy;
+mod preview;
+mod pool;
+mod scanner;
+mod search;
 mod server;
+mod session;
+mod shares;
+mod store;
+mod totp;
+mod uploads;
 mod utils;
+mod webdav;
 
 const HOST: &str = "127.0.0.1";
 const PORT: &str = "7878";
+// Максимум одновременно открытых соединений с базой
+const DB_POOL_SIZE: usize = 8;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] | ["serve"] => serve(),
+        ["user", "add", username] => cli::user_add(username, None),
+        ["user", "add", username, role] => cli::user_add(username, Some(role)),
+        ["user", "passwd", username] => cli::user_passwd(username),
+        ["user", "list"] => cli::user_list(),
+        ["user", "delete", username] => cli::user_delete(username),
+        ["user", "set-role", username, role] => cli::user_set_role(username, role),
+        ["user", "disable", username] => cli::user_set_disabled(username, true),
+        ["user", "enable", username] => cli::user_set_disabled(username, false),
+        ["user", "set-quota", username, quota] => cli::user_set_quota(username, quota),
+        ["db", "migrate"] => cli::db_migrate(false),
+        ["db", "migrate", "--dry-run"] => cli::db_migrate(true),
+        ["db", "backup", path] => cli::db_backup(path),
+        ["db", "check"] => cli::db_check(),
+        ["files", "reconcile"] => cli::files_reconcile(),
+        ["search", "reindex"] => cli::search_reindex(),
+        _ => {
+            eprintln!("{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn serve() -> Result<(), Box<dyn Error>> {
     init_db()?;
     let addr = format!("{}:{}", HOST, PORT);
     let listener = TcpListener::bind(&addr)?;
     println!("Server running on http://{}", addr);
-    start_server(listener)?;
+    let pool = Arc::new(Pool::new(DB_PATH, DB_POOL_SIZE)?);
+    if count_admins(&*pool.get()?)? == 0 {
+        println!("Администраторов нет; назначить: web_server_v2 user set-role <имя> admin");
+    }
+    let base_url = match std::env::var("BASE_URL") {
+        Ok(url) => url.trim_end_matches('/').to_string(),
+        Err(_) => format!("http://{}", addr),
+    };
+    let storage = StorageConfig::from_env();
+    start_janitor(Arc::clone(&pool), storage.clone());
+    // Без UPLOAD_SCAN_COMMAND загрузки не проверяются
+    let scanner: Box<dyn UploadScanner> = match CommandScanner::from_env() {
+        Some(scanner) => Box::new(scanner),
+        None => Box::new(NoopScanner),
+    };
+    let ctx = Context {
+        pool,
+        base_url,
+        users: Box::new(SqliteUserStore::open(DB_PATH, DB_POOL_SIZE)?),
+        limiter: LoginLimiter::new(LimiterConfig::default(), SystemClock),
+        notifier: Box::new(OutboxNotifier::new("outbox.txt")),
+        registration: RegistrationPolicy::from_env(),
+        storage,
+        content_policy: ContentPolicy::from_env(),
+        scanner,
+    };
+    start_server(listener, ctx)?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,448 @@
+use rusqlite::{Connection, Result};
+
+// Версия схемы хранится в PRAGMA user_version. Миграции применяются строго по порядку,
+// каждая в своей транзакции; уже выпущенные миграции не редактируются — только добавляются новые
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// IF NOT EXISTS в первых миграциях нужен для баз, созданных до появления версий схемы
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create users",
+        sql: "CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT NOT NULL
+        );",
+    },
+    Migration {
+        version: 2,
+        name: "sessions and two-factor authentication",
+        sql: "CREATE TABLE IF NOT EXISTS sessions (
+            token_hash TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            pending_2fa INTEGER NOT NULL DEFAULT 0,
+            expires_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS totp (
+            username TEXT PRIMARY KEY,
+            secret TEXT NOT NULL,
+            enabled INTEGER NOT NULL DEFAULT 0,
+            last_step INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            used INTEGER NOT NULL DEFAULT 0
+        );",
+    },
+    Migration {
+        version: 3,
+        name: "password reset tokens",
+        sql: "CREATE TABLE IF NOT EXISTS password_resets (
+            token_hash TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            used INTEGER NOT NULL DEFAULT 0
+        );",
+    },
+    Migration {
+        version: 4,
+        name: "api tokens",
+        sql: "CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER,
+            last_used_at INTEGER,
+            revoked INTEGER NOT NULL DEFAULT 0
+        );",
+    },
+    Migration {
+        version: 5,
+        name: "user roles",
+        sql: "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';",
+    },
+    Migration {
+        version: 6,
+        name: "user profile and activity",
+        // Время создания существующих пользователей неизвестно — считаем им момент миграции.
+        // Их пароли заданы до исправления разбора '+' в формах: флаг legacy_form_password
+        // снимается при первом успешном входе
+        sql: "ALTER TABLE users ADD COLUMN display_name TEXT NOT NULL DEFAULT '';
+        ALTER TABLE users ADD COLUMN email TEXT NOT NULL DEFAULT '';
+        ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN last_login_at INTEGER;
+        ALTER TABLE users ADD COLUMN last_login_ip TEXT;
+        ALTER TABLE users ADD COLUMN legacy_form_password INTEGER NOT NULL DEFAULT 0;
+        UPDATE users SET created_at = CAST(strftime('%s', 'now') AS INTEGER),
+                         updated_at = CAST(strftime('%s', 'now') AS INTEGER),
+                         legacy_form_password = 1;",
+    },
+    Migration {
+        version: 7,
+        name: "uploaded file metadata",
+        sql: "CREATE TABLE files (
+            id INTEGER PRIMARY KEY,
+            owner TEXT,
+            stored_path TEXT NOT NULL UNIQUE,
+            original_name TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            uploaded_at INTEGER NOT NULL
+        );
+        CREATE INDEX files_owner ON files (owner);",
+    },
+    Migration {
+        version: 8,
+        name: "per-user storage quota",
+        sql: "ALTER TABLE users ADD COLUMN quota_bytes INTEGER;",
+    },
+    Migration {
+        version: 9,
+        name: "content-addressed file storage",
+        // Таблица files пересоздается: stored_path больше не уникален (одинаковые файлы делят блоб),
+        // а уникальным становится имя файла в пространстве владельца. Уже загруженные личные файлы
+        // переносятся в хранилище блобов командой files reconcile
+        sql: "CREATE TABLE blobs (
+            sha256 TEXT PRIMARY KEY,
+            size INTEGER NOT NULL,
+            refcount INTEGER NOT NULL
+        );
+        CREATE TABLE files_new (
+            id INTEGER PRIMARY KEY,
+            owner TEXT,
+            path TEXT NOT NULL,
+            stored_path TEXT NOT NULL,
+            original_name TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            uploaded_at INTEGER NOT NULL,
+            UNIQUE (owner, path)
+        );
+        INSERT INTO files_new (id, owner, path, stored_path, original_name, size, sha256, content_type, uploaded_at)
+            SELECT id, owner, original_name, stored_path, original_name, size, sha256, content_type, uploaded_at
+            FROM files;
+        DROP TABLE files;
+        ALTER TABLE files_new RENAME TO files;
+        CREATE INDEX files_owner ON files (owner);
+        CREATE INDEX files_sha256 ON files (sha256);",
+    },
+    Migration {
+        version: 10,
+        name: "folders",
+        // Папки хранятся отдельно, чтобы пустая папка не исчезала
+        sql: "CREATE TABLE folders (
+            id INTEGER PRIMARY KEY,
+            owner TEXT NOT NULL,
+            path TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (owner, path)
+        );",
+    },
+    Migration {
+        version: 11,
+        name: "share links",
+        // Хранится только хеш токена ссылки, как и у токенов доступа
+        sql: "CREATE TABLE shares (
+            id INTEGER PRIMARY KEY,
+            file_id INTEGER NOT NULL,
+            owner TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            password_hash TEXT,
+            expires_at INTEGER,
+            max_downloads INTEGER,
+            downloads INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX shares_file ON shares (file_id);
+        CREATE INDEX shares_owner ON shares (owner);",
+    },
+    Migration {
+        version: 12,
+        name: "trash",
+        // Удаленный файл хранит прежний путь и время удаления; содержимое остается в хранилище
+        sql: "CREATE TABLE trash (
+            id INTEGER PRIMARY KEY,
+            owner TEXT NOT NULL,
+            path TEXT NOT NULL,
+            stored_path TEXT NOT NULL,
+            original_name TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            uploaded_at INTEGER NOT NULL,
+            deleted_at INTEGER NOT NULL
+        );
+        CREATE INDEX trash_owner ON trash (owner);
+        CREATE INDEX trash_deleted_at ON trash (deleted_at);",
+    },
+    Migration {
+        version: 13,
+        name: "resumable uploads",
+        // Незавершенные загрузки по протоколу tus; принятые данные лежат в STORAGE_DIR/.uploads
+        sql: "CREATE TABLE uploads (
+            id TEXT PRIMARY KEY,
+            owner TEXT NOT NULL,
+            path TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            length INTEGER NOT NULL,
+            offset INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL
+        );
+        CREATE INDEX uploads_owner ON uploads (owner);",
+    },
+    Migration {
+        version: 14,
+        name: "full-text search",
+        // Текст извлекается один раз на содержимое (search_texts по sha256), а документы индекса
+        // поддерживаются триггерами: любое добавление, переименование или удаление записи files
+        // сразу видно в поиске. Документ без file_id — заметка со страницы /save.
+        // Текст уже загруженных файлов добавляется командой search reindex
+        sql: "CREATE TABLE search_texts (
+            sha256 TEXT PRIMARY KEY,
+            body TEXT NOT NULL
+        );
+        CREATE TABLE search_documents (
+            id INTEGER PRIMARY KEY,
+            file_id INTEGER UNIQUE,
+            owner TEXT,
+            name TEXT NOT NULL,
+            body TEXT NOT NULL
+        );
+        CREATE VIRTUAL TABLE search_index USING fts5(
+            name, body, content = 'search_documents', content_rowid = 'id', tokenize = 'unicode61 remove_diacritics 2'
+        );
+        CREATE TRIGGER search_documents_insert AFTER INSERT ON search_documents BEGIN
+            INSERT INTO search_index (rowid, name, body) VALUES (new.id, new.name, new.body);
+        END;
+        CREATE TRIGGER search_documents_delete AFTER DELETE ON search_documents BEGIN
+            INSERT INTO search_index (search_index, rowid, name, body) VALUES ('delete', old.id, old.name, old.body);
+        END;
+        CREATE TRIGGER search_documents_update AFTER UPDATE ON search_documents BEGIN
+            INSERT INTO search_index (search_index, rowid, name, body) VALUES ('delete', old.id, old.name, old.body);
+            INSERT INTO search_index (rowid, name, body) VALUES (new.id, new.name, new.body);
+        END;
+        CREATE TRIGGER files_search_insert AFTER INSERT ON files BEGIN
+            INSERT INTO search_documents (file_id, owner, name, body)
+            VALUES (new.id, new.owner, new.path, COALESCE((SELECT body FROM search_texts WHERE sha256 = new.sha256), ''));
+        END;
+        CREATE TRIGGER files_search_update AFTER UPDATE OF owner, path, sha256 ON files BEGIN
+            UPDATE search_documents
+            SET owner = new.owner, name = new.path,
+                body = COALESCE((SELECT body FROM search_texts WHERE sha256 = new.sha256), '')
+            WHERE file_id = new.id;
+        END;
+        CREATE TRIGGER files_search_delete AFTER DELETE ON files BEGIN
+            DELETE FROM search_documents WHERE file_id = old.id;
+        END;
+        CREATE TRIGGER blobs_search_delete AFTER DELETE ON blobs BEGIN
+            DELETE FROM search_texts WHERE sha256 = old.sha256;
+        END;
+        INSERT INTO search_documents (file_id, owner, name, body) SELECT id, owner, path, '' FROM files;",
+    },
+];
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+// Миграции, которые еще не применены к базе
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = current_version(conn)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+// Применяет все ожидающие миграции и возвращает их список.
+// Ошибка в миграции откатывает только ее, ранее примененные остаются
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    let pending = pending(conn)?;
+    for migration in &pending {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(pending)
+}
+
+// Для db migrate: при dry_run только список ожидающих миграций, база не меняется
+pub fn run(conn: &mut Connection, dry_run: bool) -> Result<Vec<&'static Migration>> {
+    if dry_run {
+        pending(conn)
+    } else {
+        migrate(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Копия users.db из репозитория: база без версии схемы, созданная до миграций
+    struct FixtureCopy(PathBuf);
+
+    impl FixtureCopy {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("web_server_v2-{}-{}.db", name, std::process::id()));
+            std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/users.db"), &path).unwrap();
+            FixtureCopy(path)
+        }
+    }
+
+    impl Drop for FixtureCopy {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn usernames(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT username FROM users ORDER BY id").unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn fixture_migrates_to_latest_and_keeps_users() {
+        let fixture = FixtureCopy::new("migrate");
+        let mut conn = Connection::open(&fixture.0).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        let before = usernames(&conn);
+        assert!(!before.is_empty());
+
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(usernames(&conn), before);
+        assert_eq!(conn.query_row("PRAGMA integrity_check", [], |row| row.get::<_, String>(0)).unwrap(), "ok");
+
+        // Повторный запуск ничего не меняет
+        assert!(migrate(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn migration_grants_no_admin_by_name() {
+        let fixture = FixtureCopy::new("roles");
+        let mut conn = Connection::open(&fixture.0).unwrap();
+        migrate(&mut conn).unwrap();
+        assert!(usernames(&conn).iter().any(|name| name == "admin"));
+        let admins: i64 = conn
+            .query_row("SELECT COUNT(*) FROM users WHERE role != 'user'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(admins, 0);
+    }
+
+    fn schema(conn: &Connection, kind: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = ?1 AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .unwrap();
+        stmt.query_map([kind], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        stmt.query_map([], |row| row.get(1)).unwrap().collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn fixture_has_expected_schema() {
+        let fixture = FixtureCopy::new("schema");
+        let mut conn = Connection::open(&fixture.0).unwrap();
+        let hashes_before: Vec<(String, String)> = {
+            let mut stmt = conn.prepare("SELECT username, password_hash FROM users ORDER BY id").unwrap();
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().collect::<Result<_>>().unwrap()
+        };
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)).unwrap(), latest_version());
+        assert_eq!(
+            columns(&conn, "users"),
+            [
+                "id", "username", "password_hash", "role", "display_name", "email", "disabled", "created_at",
+                "updated_at", "last_login_at", "last_login_ip", "legacy_form_password", "quota_bytes",
+            ]
+        );
+        assert_eq!(
+            columns(&conn, "files"),
+            ["id", "owner", "path", "stored_path", "original_name", "size", "sha256", "content_type", "uploaded_at"]
+        );
+        for table in [
+            "users", "sessions", "totp", "recovery_codes", "password_resets", "api_tokens", "files", "blobs",
+            "folders", "shares", "trash", "uploads", "search_texts", "search_documents", "search_index",
+        ] {
+            assert!(schema(&conn, "table").iter().any(|name| name == table), "{}", table);
+        }
+        assert_eq!(
+            schema(&conn, "index"),
+            [
+                "files_owner", "files_sha256", "shares_file", "shares_owner", "trash_deleted_at", "trash_owner",
+                "uploads_owner",
+            ]
+        );
+
+        // Строки переносятся как есть: пароли не перехешируются, у старых учетных записей роль user
+        let rows: Vec<(String, String, String, bool)> = {
+            let mut stmt = conn
+                .prepare("SELECT username, password_hash, role, legacy_form_password FROM users ORDER BY id")
+                .unwrap();
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(rows.len(), hashes_before.len());
+        for ((username, hash, role, legacy), (old_username, old_hash)) in rows.iter().zip(&hashes_before) {
+            assert_eq!((username, hash), (old_username, old_hash));
+            assert_eq!(role, "user");
+            assert!(legacy);
+        }
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let fixture = FixtureCopy::new("dry-run");
+        let mut conn = Connection::open(&fixture.0).unwrap();
+        let planned: Vec<u32> = run(&mut conn, true).unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(planned.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert_eq!(schema(&conn, "table"), ["users"]);
+        assert_eq!(columns(&conn, "users"), ["id", "username", "password_hash"]);
+
+        // Затем применяется ровно то, что было показано
+        let applied: Vec<u32> = run(&mut conn, false).unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(applied, planned);
+        assert!(run(&mut conn, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn empty_database_migrates() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(usernames(&conn).is_empty());
+    }
+}
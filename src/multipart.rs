@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Наибольший размер заголовков одной части и значения обычного поля формы
+const PART_HEADERS_LIMIT: usize = 8 * 1024;
+const FIELD_LIMIT: usize = 64 * 1024;
+// Сколько байт тела multipart приходится на разделители и заголовки частей сверх самого файла
+pub const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+
+// Граница из заголовка Content-Type: multipart/form-data; boundary=...
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+enum State {
+    // До первого разделителя
+    Preamble,
+    // Сразу после разделителя: "\r\n" — дальше часть, "--" — конец тела
+    Delimiter,
+    Headers,
+    Body,
+    Done,
+}
+
+// Куда идет содержимое текущей части
+enum Target {
+    File,
+    Field(String, Vec<u8>),
+    Skip,
+}
+
+// Что осталось от формы после разбора: имя файла из поля file и остальные поля
+pub struct MultipartForm {
+    pub file_name: Option<String>,
+    pub file_size: u64,
+    pub fields: HashMap<String, String>,
+}
+
+// Разбирает тело multipart/form-data по мере поступления байт: содержимое поля file
+// пишется в file, остальные поля собираются в памяти. Тело — произвольные байты,
+// а не текст, поэтому файлы любого типа проходят без искажений
+pub struct MultipartWriter<F: Write> {
+    file: F,
+    // "\r\n--" + boundary; первый разделитель без "\r\n" — его добавляет new
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    target: Target,
+    form: MultipartForm,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Значение параметра Content-Disposition: name="dir"; filename="a.txt"
+fn disposition_param(headers: &str, name: &str) -> Option<String> {
+    let line = headers
+        .lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("content-disposition:"))?;
+    line.split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+impl<F: Write> MultipartWriter<F> {
+    pub fn new(boundary: &str, file: F) -> Self {
+        MultipartWriter {
+            file,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            target: Target::Skip,
+            form: MultipartForm { file_name: None, file_size: 0, fields: HashMap::new() },
+        }
+    }
+
+    // Результат разбора; тело должно закончиться закрывающим разделителем
+    pub fn finish(mut self) -> io::Result<(MultipartForm, F)> {
+        if !matches!(self.state, State::Done) {
+            return Err(invalid("incomplete multipart body"));
+        }
+        self.file.flush()?;
+        Ok((self.form, self.file))
+    }
+
+    fn emit(&mut self, len: usize) -> io::Result<()> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        match &mut self.target {
+            Target::File => {
+                self.file.write_all(&data)?;
+                self.form.file_size += data.len() as u64;
+            }
+            Target::Field(_, value) => {
+                if value.len() + data.len() > FIELD_LIMIT {
+                    return Err(invalid("form field is too long"));
+                }
+                value.extend_from_slice(&data);
+            }
+            Target::Skip => {}
+        }
+        Ok(())
+    }
+
+    fn end_part(&mut self) {
+        if let Target::Field(name, value) = std::mem::replace(&mut self.target, Target::Skip) {
+            self.form.fields.insert(name, String::from_utf8_lossy(&value).into_owned());
+        }
+    }
+
+    fn start_part(&mut self, headers: &str) {
+        let name = disposition_param(headers, "name").unwrap_or_default();
+        self.target = match disposition_param(headers, "filename") {
+            // Берется только первый файл формы
+            Some(file_name) if name == "file" && self.form.file_name.is_none() => {
+                self.form.file_name = Some(file_name);
+                Target::File
+            }
+            Some(_) => Target::Skip,
+            None => Target::Field(name, Vec::new()),
+        };
+    }
+
+    // Разбирает накопленное в buf, насколько это возможно без следующих байт
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buf, &self.delimiter) {
+                    Some(pos) => {
+                        self.buf.drain(..pos + self.delimiter.len());
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        let keep = self.buf.len().min(self.delimiter.len() - 1);
+                        self.buf.drain(..self.buf.len() - keep);
+                        return Ok(());
+                    }
+                },
+                State::Delimiter => {
+                    if self.buf.len() < 2 {
+                        return Ok(());
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.buf.clear();
+                        self.state = State::Done;
+                    } else if self.buf.starts_with(b"\r\n") {
+                        self.buf.drain(..2);
+                        self.state = State::Headers;
+                    } else {
+                        return Err(invalid("malformed multipart delimiter"));
+                    }
+                }
+                State::Headers => match find(&self.buf, b"\r\n\r\n") {
+                    Some(pos) => {
+                        let headers = String::from_utf8_lossy(&self.buf[..pos]).into_owned();
+                        self.buf.drain(..pos + 4);
+                        self.start_part(&headers);
+                        self.state = State::Body;
+                    }
+                    None if self.buf.len() > PART_HEADERS_LIMIT => return Err(invalid("part headers are too long")),
+                    None => return Ok(()),
+                },
+                State::Body => match find(&self.buf, &self.delimiter) {
+                    Some(pos) => {
+                        self.emit(pos)?;
+                        self.buf.drain(..self.delimiter.len());
+                        self.end_part();
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        // Хвост может оказаться началом разделителя — его оставляем до следующих байт
+                        let keep = self.buf.len().min(self.delimiter.len() - 1);
+                        self.emit(self.buf.len() - keep)?;
+                        return Ok(());
+                    }
+                },
+                // Эпилог после закрывающего разделителя не нужен
+                State::Done => {
+                    self.buf.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl<F: Write> Write for MultipartWriter<F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        self.process()?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(file: &[u8]) -> Vec<u8> {
+        let mut body = "--XyZ\r\nContent-Disposition: form-data; name=\"dir\"\r\n\r\nдокументы\r\n".as_bytes().to_vec();
+        body.extend_from_slice(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"отчет.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+                .as_bytes(),
+        );
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        body
+    }
+
+    fn parse(body: &[u8], chunk: usize) -> io::Result<(MultipartForm, Vec<u8>)> {
+        let mut writer = MultipartWriter::new("XyZ", Vec::new());
+        for piece in body.chunks(chunk) {
+            writer.write_all(piece)?;
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(multipart_boundary("multipart/form-data; boundary=abc").as_deref(), Some("abc"));
+        assert_eq!(multipart_boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(multipart_boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(multipart_boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn binary_file_survives_any_chunking() {
+        // Невалидный UTF-8, "\r\n" и почти-разделители внутри файла
+        let mut file: Vec<u8> = (0..=255u8).cycle().take(5000).collect();
+        file.extend_from_slice(b"\r\n--XyQ\r\n--Xy\xff\xfe\r\n");
+        let body = body(&file);
+        for chunk in [1, 2, 3, 7, 64, 1000, body.len()] {
+            let (form, content) = parse(&body, chunk).unwrap();
+            assert_eq!(content, file, "chunk {}", chunk);
+            assert_eq!(form.file_size, file.len() as u64);
+            assert_eq!(form.file_name.as_deref(), Some("отчет.bin"));
+            assert_eq!(form.fields.get("dir").map(String::as_str), Some("документы"));
+        }
+    }
+
+    #[test]
+    fn empty_file_and_preamble() {
+        let mut body = b"preamble\r\n".to_vec();
+        body.extend_from_slice(&self::body(b""));
+        let (form, content) = parse(&body, 5).unwrap();
+        assert!(content.is_empty());
+        assert_eq!(form.file_name.as_deref(), Some("отчет.bin"));
+    }
+
+    #[test]
+    fn truncated_body_is_rejected() {
+        let body = body(b"data");
+        assert!(parse(&body[..body.len() - 8], 16).is_err());
+        assert!(parse(b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue", 4).is_err());
+    }
+
+    #[test]
+    fn oversized_field_is_rejected() {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"dir\"\r\n\r\n".to_vec();
+        body.extend(std::iter::repeat_n(b'a', FIELD_LIMIT + 1));
+        body.extend_from_slice(b"\r\n--XyZ--");
+        assert_eq!(parse(&body, 4096).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
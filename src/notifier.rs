@@ -0,0 +1,61 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::utils::get_formatted_time;
+
+// Доставка служебных сообщений пользователю (ссылки сброса пароля и т.п.).
+// Почтового сервера нет, поэтому способ доставки подключается снаружи
+pub trait Notifier: Send + Sync {
+    fn notify(&self, username: &str, subject: &str, message: &str) -> Result<(), std::io::Error>;
+}
+
+// Складывает сообщения в файл-«почтовый ящик», откуда их забирает администратор или тест
+pub struct OutboxNotifier {
+    path: PathBuf,
+}
+
+impl OutboxNotifier {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OutboxNotifier { path: path.into() }
+    }
+}
+
+impl Notifier for OutboxNotifier {
+    fn notify(&self, username: &str, subject: &str, message: &str) -> Result<(), std::io::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "To: {}", username)?;
+        writeln!(file, "Date: {}", get_formatted_time())?;
+        writeln!(file, "Subject: {}", subject)?;
+        writeln!(file)?;
+        writeln!(file, "{}", message)?;
+        writeln!(file, "----")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outbox_receives_messages() {
+        let path = std::env::temp_dir().join(format!("web_server_v2-outbox-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let notifier = OutboxNotifier::new(&path);
+        notifier.notify("bg", "Сброс пароля", "http://localhost/reset/confirm?token=abc").unwrap();
+        notifier.notify("bg2", "Сброс пароля", "http://localhost/reset/confirm?token=def").unwrap();
+
+        let outbox = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let messages: Vec<&str> = outbox.split("----\n").filter(|m| !m.is_empty()).collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("To: bg\n"));
+        assert!(messages[0].contains("Subject: Сброс пароля\n"));
+        assert!(messages[0].contains("\n\nhttp://localhost/reset/confirm?token=abc\n"));
+        assert!(messages[1].starts_with("To: bg2\n"));
+    }
+}
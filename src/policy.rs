@@ -0,0 +1,276 @@
+// Правила регистрации: кто может регистрироваться и какие имена и пароли допустимы
+
+const DISPLAY_NAME_MAX_LEN: usize = 64;
+const EMAIL_MAX_LEN: usize = 254;
+
+pub enum RegistrationMode {
+    // Регистрироваться может любой
+    Open,
+    // Регистрация отключена, учетные записи создает администратор
+    Closed,
+    // Нужен код приглашения
+    Invite(String),
+}
+
+impl RegistrationMode {
+    // Режим задается переменными окружения REGISTRATION_MODE (open/closed/invite) и INVITE_CODE
+    pub fn from_env() -> Self {
+        match std::env::var("REGISTRATION_MODE").as_deref() {
+            Ok("closed") => RegistrationMode::Closed,
+            Ok("invite") => match std::env::var("INVITE_CODE") {
+                Ok(code) if !code.is_empty() => RegistrationMode::Invite(code),
+                // Без кода приглашения зарегистрироваться было бы невозможно
+                _ => RegistrationMode::Closed,
+            },
+            _ => RegistrationMode::Open,
+        }
+    }
+}
+
+pub struct RegistrationPolicy {
+    pub mode: RegistrationMode,
+    pub username_min_len: usize,
+    pub username_max_len: usize,
+    // Символы, разрешенные в имени помимо латинских букв и цифр
+    pub username_extra_chars: String,
+    // Имена, которые нельзя занять (сравниваются без учета регистра)
+    pub reserved_names: Vec<String>,
+    // Считать "Bob" и "bob" одним и тем же пользователем
+    pub case_insensitive_unique: bool,
+    pub password_min_len: usize,
+    pub password_require_letter: bool,
+    pub password_require_digit: bool,
+}
+
+impl Default for RegistrationPolicy {
+    fn default() -> Self {
+        RegistrationPolicy {
+            mode: RegistrationMode::Open,
+            username_min_len: 3,
+            username_max_len: 32,
+            username_extra_chars: "_-.".to_string(),
+            reserved_names: ["admin", "administrator", "root", "system", "support"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            case_insensitive_unique: true,
+            password_min_len: 8,
+            password_require_letter: true,
+            password_require_digit: true,
+        }
+    }
+}
+
+// Значение переменной окружения; если она не задана или не разбирается, None
+fn env_value<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok())
+}
+
+impl RegistrationPolicy {
+    // Правила из переменных окружения; незаданные берутся из Default:
+    // USERNAME_MIN_LEN, USERNAME_MAX_LEN, USERNAME_EXTRA_CHARS ("_-."),
+    // RESERVED_USERNAMES ("admin,root"; пустая строка — без зарезервированных имен),
+    // USERNAME_CASE_INSENSITIVE, PASSWORD_MIN_LEN, PASSWORD_REQUIRE_LETTER, PASSWORD_REQUIRE_DIGIT (true/false)
+    pub fn from_env() -> Self {
+        let defaults = RegistrationPolicy::default();
+        RegistrationPolicy {
+            mode: RegistrationMode::from_env(),
+            username_min_len: env_value("USERNAME_MIN_LEN").unwrap_or(defaults.username_min_len),
+            username_max_len: env_value("USERNAME_MAX_LEN").unwrap_or(defaults.username_max_len),
+            username_extra_chars: std::env::var("USERNAME_EXTRA_CHARS").unwrap_or(defaults.username_extra_chars),
+            reserved_names: match std::env::var("RESERVED_USERNAMES") {
+                Ok(names) => names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                Err(_) => defaults.reserved_names,
+            },
+            case_insensitive_unique: env_value("USERNAME_CASE_INSENSITIVE").unwrap_or(defaults.case_insensitive_unique),
+            password_min_len: env_value("PASSWORD_MIN_LEN").unwrap_or(defaults.password_min_len),
+            password_require_letter: env_value("PASSWORD_REQUIRE_LETTER").unwrap_or(defaults.password_require_letter),
+            password_require_digit: env_value("PASSWORD_REQUIRE_DIGIT").unwrap_or(defaults.password_require_digit),
+        }
+    }
+
+    // Проверка кода приглашения; в режиме Closed регистрация невозможна вовсе
+    pub fn check_mode(&self, invite_code: &str) -> Result<(), String> {
+        match &self.mode {
+            RegistrationMode::Open => Ok(()),
+            RegistrationMode::Closed => Err("Регистрация закрыта. Обратитесь к администратору.".to_string()),
+            RegistrationMode::Invite(code) if code == invite_code => Ok(()),
+            RegistrationMode::Invite(_) => Err("Неверный код приглашения.".to_string()),
+        }
+    }
+
+    pub fn requires_invite(&self) -> bool {
+        matches!(self.mode, RegistrationMode::Invite(_))
+    }
+
+    // Все нарушения правил для имени пользователя
+    pub fn validate_username(&self, username: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        let len = username.chars().count();
+        if len < self.username_min_len || len > self.username_max_len {
+            errors.push(format!(
+                "Имя пользователя должно содержать от {} до {} символов.",
+                self.username_min_len, self.username_max_len
+            ));
+        }
+        if !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || self.username_extra_chars.contains(c))
+        {
+            errors.push(format!(
+                "Имя пользователя может содержать только латинские буквы, цифры и символы {}",
+                self.username_extra_chars
+            ));
+        }
+        if self
+            .reserved_names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(username))
+        {
+            errors.push("Это имя зарезервировано.".to_string());
+        }
+        errors
+    }
+
+    // Все нарушения правил для пароля
+    pub fn validate_password(&self, username: &str, password: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if password.chars().count() < self.password_min_len {
+            errors.push(format!("Пароль должен содержать не менее {} символов.", self.password_min_len));
+        }
+        if self.password_require_letter && !password.chars().any(|c| c.is_alphabetic()) {
+            errors.push("Пароль должен содержать хотя бы одну букву.".to_string());
+        }
+        if self.password_require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push("Пароль должен содержать хотя бы одну цифру.".to_string());
+        }
+        if !username.is_empty() && password.eq_ignore_ascii_case(username) {
+            errors.push("Пароль не должен совпадать с именем пользователя.".to_string());
+        }
+        errors
+    }
+
+    // Необязательные поля профиля: отображаемое имя и адрес почты
+    pub fn validate_profile(&self, display_name: &str, email: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if display_name.chars().count() > DISPLAY_NAME_MAX_LEN {
+            errors.push(format!("Отображаемое имя не должно быть длиннее {} символов.", DISPLAY_NAME_MAX_LEN));
+        }
+        if display_name.chars().any(|c| c.is_control()) {
+            errors.push("Отображаемое имя содержит недопустимые символы.".to_string());
+        }
+        if !email.is_empty() {
+            let valid = email.len() <= EMAIL_MAX_LEN
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+                && matches!(email.split_once('@'), Some((local, domain))
+                    if !local.is_empty() && domain.contains('.') && !domain.contains('@'));
+            if !valid {
+                errors.push("Неверный адрес электронной почты.".to_string());
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_rules() {
+        let policy = RegistrationPolicy::default();
+        // (имя, число нарушений)
+        let cases = [
+            ("bob", 0),
+            ("bob_the-builder.2", 0),
+            ("bo", 1),
+            ("", 1),
+            (&"b".repeat(32), 0),
+            (&"b".repeat(33), 1),
+            ("bob smith", 1),
+            ("боб", 1),
+            ("bob/../x", 1),
+            ("Admin", 1),
+            ("ROOT", 1),
+            ("ад", 2),
+        ];
+        for (username, expected) in cases {
+            assert_eq!(policy.validate_username(username).len(), expected, "{:?}", username);
+        }
+
+        let relaxed = RegistrationPolicy { reserved_names: Vec::new(), username_extra_chars: String::new(), ..Default::default() };
+        assert!(relaxed.validate_username("admin").is_empty());
+        assert_eq!(relaxed.validate_username("bob_1").len(), 1);
+    }
+
+    #[test]
+    fn password_rules() {
+        let policy = RegistrationPolicy::default();
+        // (имя, пароль, число нарушений)
+        let cases = [
+            ("bob", "secret12", 0),
+            ("bob", "пароль123", 0),
+            ("bob", "secret1", 1),
+            ("bob", "", 3),
+            ("bob", "12345678", 1),
+            ("bob", "password", 1),
+            ("bob12345", "bob12345", 1),
+            ("bob12345", "BOB12345", 1),
+            // При сбросе имени может не быть — сравнение с ним пропускается
+            ("", "secret12", 0),
+        ];
+        for (username, password, expected) in cases {
+            assert_eq!(policy.validate_password(username, password).len(), expected, "{:?} {:?}", username, password);
+        }
+
+        let lenient = RegistrationPolicy {
+            password_min_len: 3,
+            password_require_letter: false,
+            password_require_digit: false,
+            ..Default::default()
+        };
+        assert!(lenient.validate_password("bob", "123").is_empty());
+        assert_eq!(lenient.validate_password("bob", "Bob").len(), 1);
+    }
+
+    #[test]
+    fn registration_modes() {
+        let open = RegistrationPolicy::default();
+        assert!(open.check_mode("").is_ok());
+        assert!(!open.requires_invite());
+
+        let closed = RegistrationPolicy { mode: RegistrationMode::Closed, ..Default::default() };
+        assert!(closed.check_mode("").is_err());
+        assert!(closed.check_mode("anything").is_err());
+
+        let invite = RegistrationPolicy { mode: RegistrationMode::Invite("welcome".to_string()), ..Default::default() };
+        assert!(invite.requires_invite());
+        assert!(invite.check_mode("welcome").is_ok());
+        assert!(invite.check_mode("Welcome").is_err());
+        assert!(invite.check_mode("").is_err());
+    }
+
+    #[test]
+    fn profile_rules() {
+        let policy = RegistrationPolicy::default();
+        let cases = [
+            ("", "", 0),
+            ("Боб Строитель", "bob@example.com", 0),
+            (&"я".repeat(64), "", 0),
+            (&"я".repeat(65), "", 1),
+            ("bob\n", "", 1),
+            ("", "bob@localhost", 1),
+            ("", "bob@@example.com", 1),
+            ("", "@example.com", 1),
+            ("", "bob @example.com", 1),
+        ];
+        for (display_name, email, expected) in cases {
+            assert_eq!(policy.validate_profile(display_name, email).len(), expected, "{:?} {:?}", display_name, email);
+        }
+    }
+}
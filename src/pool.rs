@@ -0,0 +1,100 @@
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use rusqlite::{Connection, Result};
+
+// Сколько ждать снятия блокировки другим писателем, прежде чем вернуть SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Сколько подготовленных запросов держать в кеше каждого соединения
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// Пул соединений с SQLite. Соединения создаются по требованию (не больше max_size)
+// и возвращаются в пул при уничтожении PooledConnection
+pub struct Pool {
+    path: String,
+    max_size: usize,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    // Всего открытых соединений: свободные + выданные
+    open: usize,
+}
+
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Pool {
+    pub fn new(path: &str, max_size: usize) -> Result<Self> {
+        let pool = Pool {
+            path: path.to_string(),
+            max_size: max_size.max(1),
+            state: Mutex::new(PoolState { idle: Vec::new(), open: 0 }),
+            available: Condvar::new(),
+        };
+        // Первое соединение открываем сразу, чтобы ошибки конфигурации всплыли при старте
+        let conn = pool.open()?;
+        let mut state = pool.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.idle.push(conn);
+        state.open = 1;
+        drop(state);
+        Ok(pool)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)?;
+        // WAL позволяет читать во время записи, а busy_timeout — дождаться писателя вместо SQLITE_BUSY
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(conn)
+    }
+
+    // Берет свободное соединение, при необходимости открывает новое или ждет освобождения
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(PooledConnection { pool: self, conn: Some(conn) });
+            }
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.open() {
+                    Ok(conn) => Ok(PooledConnection { pool: self, conn: Some(conn) }),
+                    Err(e) => {
+                        self.state.lock().unwrap_or_else(|e| e.into_inner()).open -= 1;
+                        self.available.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.available.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("соединение возвращается в пул только при drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut state = self.pool.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.idle.push(conn);
+            drop(state);
+            self.pool.available.notify_one();
+        }
+    }
+}
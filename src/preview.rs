@@ -0,0 +1,236 @@
+use std::fs;
+use std::io::{BufReader, Read};
+use std::time::UNIX_EPOCH;
+
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+use crate::content_policy::{content_type_for, is_risky_type, is_text_type};
+use crate::files::{FileRecord, StorageConfig};
+use crate::utils::{random_token, sha256_hex};
+
+// Миниатюра вписывается в квадрат с такой стороной
+pub const THUMB_SIZE: u32 = 160;
+// Из файлов больше этого миниатюры не делаются
+const MAX_THUMB_SOURCE_BYTES: u64 = 32 * 1024 * 1024;
+// Ограничения декодера: маленький файл может объявить огромную картинку
+const MAX_IMAGE_DIMENSION: u32 = 12_000;
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+// Сколько текста показывается на странице предпросмотра
+pub const PREVIEW_TEXT_BYTES: u64 = 256 * 1024;
+
+// Как файл показывается на странице предпросмотра
+#[derive(Clone, Copy, PartialEq)]
+pub enum PreviewKind {
+    Image,
+    Audio,
+    Video,
+    Markdown,
+    Text,
+    // Только скачивание
+    Unavailable,
+}
+
+impl PreviewKind {
+    pub fn of(content_type: &str) -> PreviewKind {
+        match content_type {
+            "text/markdown" => PreviewKind::Markdown,
+            // SVG и прочие опасные типы не встраиваются в страницу; текстовые показываются исходником
+            t if t.starts_with("image/") && !is_risky_type(t) => PreviewKind::Image,
+            t if t.starts_with("audio/") => PreviewKind::Audio,
+            t if t.starts_with("video/") => PreviewKind::Video,
+            t if is_text_type(t) => PreviewKind::Text,
+            _ => PreviewKind::Unavailable,
+        }
+    }
+
+    // Значок для плитки файла без миниатюры
+    pub fn icon(self) -> &'static str {
+        match self {
+            PreviewKind::Image => "🖼",
+            PreviewKind::Audio => "🎵",
+            PreviewKind::Video => "🎬",
+            PreviewKind::Markdown | PreviewKind::Text => "📄",
+            PreviewKind::Unavailable => "📦",
+        }
+    }
+}
+
+// Форматы, которые умеем декодировать для миниатюр
+fn thumbnail_source_format(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        _ => None,
+    }
+}
+
+pub fn has_thumbnail(file: &FileRecord) -> bool {
+    thumbnail_source_format(&file.content_type).is_some() && !file.sha256.is_empty() && file.size <= MAX_THUMB_SOURCE_BYTES
+}
+
+// Путь к миниатюре файла в кэше; при первом обращении она создается.
+// None — миниатюры нет: файл не изображение, слишком большой или не декодируется
+pub fn thumbnail(storage: &StorageConfig, file: &FileRecord) -> std::io::Result<Option<String>> {
+    match thumbnail_source_format(&file.content_type) {
+        Some(format) if has_thumbnail(file) => cached_thumbnail(&file.stored_path, format, storage.thumbnail_path(&file.sha256)),
+        _ => Ok(None),
+    }
+}
+
+// Миниатюра картинки из static/ (логотип, общие загрузки): путь в кэше и ключ для ETag.
+// Ключ зависит от пути, размера и времени изменения, поэтому замененная картинка получает новую миниатюру
+pub fn static_thumbnail(storage: &StorageConfig, path: &str) -> std::io::Result<Option<(String, String)>> {
+    let format = match thumbnail_source_format(content_type_for(path)) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+    let meta = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.len() <= MAX_THUMB_SOURCE_BYTES => meta,
+        _ => return Ok(None),
+    };
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let key = sha256_hex(format!("static:{}:{}:{}", path, meta.len(), modified.as_nanos()).as_bytes());
+    Ok(cached_thumbnail(path, format, storage.thumbnail_path(&key))?.map(|thumb| (thumb, key)))
+}
+
+// Общая часть: готовая миниатюра из кэша или новая, уменьшенная из source
+fn cached_thumbnail(source: &str, format: ImageFormat, path: String) -> std::io::Result<Option<String>> {
+    if fs::metadata(&path).is_ok() {
+        return Ok(Some(path));
+    }
+    let image = match decode_image(source, format) {
+        Ok(image) => image,
+        Err(_) => return Ok(None),
+    };
+    if let Some(dir) = std::path::Path::new(&path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Пишем рядом и переименовываем: параллельный запрос не увидит недописанную миниатюру
+    let temp = format!("{}.{}.tmp", path, random_token(8)?);
+    if let Err(e) = image.thumbnail(THUMB_SIZE, THUMB_SIZE).save_with_format(&temp, ImageFormat::Png) {
+        let _ = fs::remove_file(&temp);
+        return Err(std::io::Error::other(e));
+    }
+    fs::rename(&temp, &path)?;
+    Ok(Some(path))
+}
+
+fn decode_image(path: &str, format: ImageFormat) -> image::ImageResult<DynamicImage> {
+    let mut reader = ImageReader::with_format(BufReader::new(fs::File::open(path)?), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    reader.decode()
+}
+
+// Начало текстового файла для предпросмотра; true — файл длиннее показанного
+pub fn read_text_preview(path: &str) -> std::io::Result<(String, bool)> {
+    let mut content = Vec::new();
+    fs::File::open(path)?.take(PREVIEW_TEXT_BYTES + 1).read_to_end(&mut content)?;
+    let truncated = content.len() as u64 > PREVIEW_TEXT_BYTES;
+    content.truncate(PREVIEW_TEXT_BYTES as usize);
+    Ok((String::from_utf8_lossy(&content).into_owned(), truncated))
+}
+
+// Markdown в HTML для страницы предпросмотра. Встроенный HTML выводится как текст,
+// а ссылки со схемами вроде javascript: заменяются на "#", чтобы файл не выполнял код на нашем сайте
+pub fn render_markdown(source: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(source, options).map(|event| match event {
+        Event::Html(text) | Event::InlineHtml(text) => Event::Text(text),
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Link { link_type, dest_url: safe_url(dest_url), title, id })
+        }
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Image { link_type, dest_url: safe_url(dest_url), title, id })
+        }
+        event => event,
+    });
+    let mut output = String::new();
+    html::push_html(&mut output, events);
+    output
+}
+
+// Относительные ссылки и http, https, mailto остаются как есть
+fn safe_url(url: CowStr) -> CowStr {
+    match url.find([':', '/', '?', '#']) {
+        Some(end) if url[end..].starts_with(':') => {
+            let scheme = url[..end].to_ascii_lowercase();
+            if matches!(scheme.as_str(), "http" | "https" | "mailto") {
+                url
+            } else {
+                CowStr::Borrowed("#")
+            }
+        }
+        _ => url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::TempStorage;
+
+    fn save_png(storage: &TempStorage, name: &str, width: u32, height: u32) -> String {
+        let path = format!("{}/{}", storage.0.root, name);
+        image::GrayImage::new(width, height).save_with_format(&path, ImageFormat::Png).unwrap();
+        path
+    }
+
+    #[test]
+    fn markdown_html_is_escaped() {
+        let html = render_markdown("# Title\n\n<script>alert(1)</script>\n\ntext <img src=x onerror=alert(1)> **bold**");
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
+
+    #[test]
+    fn unsafe_links_are_replaced() {
+        let cases = [
+            ("[x](javascript:alert(1))", "#"),
+            ("[x](JavaScript:alert(1))", "#"),
+            ("[x](data:text/html;base64,PHNjcmlwdD4=)", "#"),
+            ("![x](data:image/png;base64,AAAA)", "#"),
+            ("[x](vbscript:msgbox)", "#"),
+            ("[x](https://example.com/a?b=c:d)", "https://example.com/a?b=c:d"),
+            ("[x](mailto:bob@example.com)", "mailto:bob@example.com"),
+            ("[x](docs/readme.md)", "docs/readme.md"),
+            ("[x](/files?dir=a:b)", "/files?dir=a:b"),
+            ("[x](#section)", "#section"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(safe_url(CowStr::Borrowed(source.split_once('(').unwrap().1.trim_end_matches(')'))).as_ref(), expected);
+            let html = render_markdown(source);
+            assert!(html.contains(&format!("=\"{}\"", expected.replace('&', "&amp;"))), "{}: {}", source, html);
+        }
+    }
+
+    #[test]
+    fn oversized_image_is_not_decoded() {
+        let storage = TempStorage::new("preview");
+        let small = save_png(&storage, "small.png", 300, 200);
+        let thumb = format!("{}/small.thumb.png", storage.0.root);
+        assert_eq!(cached_thumbnail(&small, ImageFormat::Png, thumb.clone()).unwrap(), Some(thumb.clone()));
+        let (width, height) = image::image_dimensions(&thumb).unwrap();
+        assert!(width <= THUMB_SIZE && height <= THUMB_SIZE);
+
+        // Картинка шире допустимого отвергается по заголовку, до выделения памяти под пиксели
+        let wide = save_png(&storage, "wide.png", MAX_IMAGE_DIMENSION + 1, 1);
+        assert!(matches!(decode_image(&wide, ImageFormat::Png), Err(image::ImageError::Limits(_))));
+        let wide_thumb = format!("{}/wide.thumb.png", storage.0.root);
+        assert_eq!(cached_thumbnail(&wide, ImageFormat::Png, wide_thumb.clone()).unwrap(), None);
+        assert!(fs::metadata(&wide_thumb).is_err());
+
+        // Содержимое, не похожее на картинку, тоже дает None, а не ошибку
+        let broken = format!("{}/broken.png", storage.0.root);
+        fs::write(&broken, b"<html>").unwrap();
+        assert_eq!(cached_thumbnail(&broken, ImageFormat::Png, format!("{}.thumb", broken)).unwrap(), None);
+    }
+}
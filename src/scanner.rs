@@ -0,0 +1,219 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::files::StorageConfig;
+use crate::utils::random_token;
+
+// Каталог карантина внутри хранилища: принятые файлы ждут здесь проверки
+const QUARANTINE_DIR: &str = ".quarantine";
+// Файл живет в карантине, только пока идет проверка; более старые остались от прерванных запросов
+pub const QUARANTINE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+// Сколько ждать сканер, если UPLOAD_SCAN_TIMEOUT_SECS не задан
+const DEFAULT_SCAN_TIMEOUT_SECS: u64 = 60;
+// Как часто проверять, не завершился ли сканер
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Вердикт сканера
+pub enum ScanVerdict {
+    Clean,
+    // Найдена угроза; строка — что сообщил сканер
+    Infected(String),
+    // Проверить не удалось: сканер не запустился, не уложился во время или завершился с ошибкой
+    Failed(String),
+}
+
+// Проверка загруженного файла до того, как он станет виден пользователям. Файл уже лежит
+// в карантине; сканер только читает его, а переносом в хранилище занимается вызывающий
+pub trait UploadScanner: Send + Sync {
+    // Чем проверяли — для журнала
+    fn name(&self) -> &str;
+    fn scan(&self, path: &Path) -> ScanVerdict;
+}
+
+// Сканер не настроен: все файлы считаются чистыми
+pub struct NoopScanner;
+
+impl UploadScanner for NoopScanner {
+    fn name(&self) -> &str {
+        "no scanner"
+    }
+
+    fn scan(&self, _path: &Path) -> ScanVerdict {
+        ScanVerdict::Clean
+    }
+}
+
+// Запускает локальную команду в духе clamscan: путь к файлу добавляется последним аргументом,
+// код выхода 0 — файл чистый, 1 — найдена угроза, остальное — ошибка проверки
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandScanner {
+    pub fn new(command: &str, timeout: Duration) -> Option<Self> {
+        let mut parts = command.split_whitespace().map(str::to_string);
+        let program = parts.next()?;
+        Some(CommandScanner { program, args: parts.collect(), timeout })
+    }
+
+    // Команда задается переменной UPLOAD_SCAN_COMMAND (например, "clamscan --no-summary"),
+    // время ожидания в секундах — UPLOAD_SCAN_TIMEOUT_SECS. None — сканер не настроен
+    pub fn from_env() -> Option<Self> {
+        let command = std::env::var("UPLOAD_SCAN_COMMAND").ok()?;
+        let timeout = std::env::var("UPLOAD_SCAN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_SCAN_TIMEOUT_SECS);
+        CommandScanner::new(&command, Duration::from_secs(timeout))
+    }
+}
+
+impl UploadScanner for CommandScanner {
+    fn name(&self) -> &str {
+        &self.program
+    }
+
+    fn scan(&self, path: &Path) -> ScanVerdict {
+        let mut child = match Command::new(&self.program)
+            .args(&self.args)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => return ScanVerdict::Failed(format!("cannot start {}: {}", self.program, e)),
+        };
+        // Вывод читается в отдельном потоке, иначе сканер может встать на заполненном канале
+        let reader = child.stdout.take().map(|mut stdout| {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = stdout.read_to_string(&mut output);
+                output
+            })
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(SCAN_POLL_INTERVAL),
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return ScanVerdict::Failed(format!("timed out after {} s", self.timeout.as_secs()));
+                }
+                Err(e) => return ScanVerdict::Failed(e.to_string()),
+            }
+        };
+        let output = reader.and_then(|reader| reader.join().ok()).unwrap_or_default();
+        match status.code() {
+            Some(0) => ScanVerdict::Clean,
+            Some(1) => {
+                // clamscan пишет "<путь>: <сигнатура> FOUND"; в журнал попадает первая строка вывода
+                let found = output.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or("threat found");
+                ScanVerdict::Infected(found.chars().take(200).collect())
+            }
+            Some(code) => ScanVerdict::Failed(format!("exit code {}", code)),
+            None => ScanVerdict::Failed("terminated by signal".to_string()),
+        }
+    }
+}
+
+fn quarantine_path(storage: &StorageConfig) -> std::io::Result<String> {
+    fs::create_dir_all(format!("{}/{}", storage.root, QUARANTINE_DIR))?;
+    Ok(format!("{}/{}/{}", storage.root, QUARANTINE_DIR, random_token(16)?))
+}
+
+// Переносит принятый файл (в пределах хранилища) в карантин; возвращает его новый путь
+pub fn quarantine_file(storage: &StorageConfig, source: &str) -> std::io::Result<String> {
+    let path = quarantine_path(storage)?;
+    fs::rename(source, &path)?;
+    Ok(path)
+}
+
+// Удаляет из карантина файлы, пролежавшие там дольше max_age; возвращает, сколько удалено
+pub fn purge_stale_quarantine(storage: &StorageConfig, max_age: Duration) -> std::io::Result<usize> {
+    let entries = match fs::read_dir(format!("{}/{}", storage.root, QUARANTINE_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let stale = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age > max_age);
+        if stale && fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::TempStorage;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Команда через sh -c: путь к файлу, добавленный последним аргументом, становится $0
+    fn shell(script: &str, timeout: Duration) -> CommandScanner {
+        CommandScanner { program: "sh".to_string(), args: vec!["-c".to_string(), script.to_string()], timeout }
+    }
+
+    #[test]
+    fn exit_codes_map_to_verdicts() {
+        let path = Path::new("upload.bin");
+        assert!(matches!(CommandScanner::new("true", TIMEOUT).unwrap().scan(path), ScanVerdict::Clean));
+        assert!(matches!(
+            CommandScanner::new("false", TIMEOUT).unwrap().scan(path),
+            ScanVerdict::Infected(found) if found == "threat found"
+        ));
+        assert!(matches!(
+            shell("echo \"$0: Eicar-Test-Signature FOUND\"; exit 1", TIMEOUT).scan(path),
+            ScanVerdict::Infected(found) if found == "upload.bin: Eicar-Test-Signature FOUND"
+        ));
+        assert!(matches!(shell("exit 2", TIMEOUT).scan(path), ScanVerdict::Failed(reason) if reason == "exit code 2"));
+        assert!(matches!(
+            CommandScanner::new("/nonexistent/scanner", TIMEOUT).unwrap().scan(path),
+            ScanVerdict::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn slow_scanner_is_killed() {
+        let started = Instant::now();
+        let verdict = shell("sleep 5", Duration::from_millis(200)).scan(Path::new("upload.bin"));
+        assert!(matches!(verdict, ScanVerdict::Failed(reason) if reason.starts_with("timed out")));
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn stale_quarantine_is_purged_by_mtime() {
+        let storage = TempStorage::new("quarantine");
+        assert_eq!(purge_stale_quarantine(&storage.0, Duration::from_secs(60)).unwrap(), 0);
+
+        let stale = quarantine_path(&storage.0).unwrap();
+        let fresh = quarantine_path(&storage.0).unwrap();
+        fs::write(&stale, b"old").unwrap();
+        fs::write(&fresh, b"new").unwrap();
+        let hour_ago = std::time::SystemTime::now() - Duration::from_secs(60 * 60);
+        fs::File::options().write(true).open(&stale).unwrap().set_modified(hour_ago).unwrap();
+
+        assert_eq!(purge_stale_quarantine(&storage.0, Duration::from_secs(60)).unwrap(), 1);
+        assert!(!Path::new(&stale).exists());
+        assert!(Path::new(&fresh).exists());
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io::Read;
+
+use rusqlite::{params, Connection, Result};
+
+use crate::content_policy::is_text_type;
+use crate::files::{list_file_records, FileError};
+
+// Из файла индексируется только начало такой длины
+const MAX_INDEXED_BYTES: usize = 1024 * 1024;
+// Сколько слов запроса учитывается
+const MAX_QUERY_TERMS: usize = 10;
+// Больше результатов не показываем
+pub const SEARCH_LIMIT: usize = 50;
+// Файл с заметкой, которую сохраняет /save
+pub const NOTE_FILE: &str = "user_content.txt";
+// Как заметка называется в индексе и результатах
+pub const NOTE_NAME: &str = "Заметка";
+// Границы найденных слов в имени и фрагменте; после экранирования заменяются на <mark>
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+// Найденный документ. name и snippet содержат метки MATCH_START/MATCH_END вокруг совпадений
+pub struct SearchHit {
+    // None — заметка
+    pub file_id: Option<i64>,
+    pub owner: Option<String>,
+    pub name: String,
+    pub snippet: String,
+    // Чем больше, тем лучше совпадение
+    pub score: f64,
+}
+
+// Текст содержимого для индекса; None — файл не текстовый
+pub fn extract_text(content_type: &str, content: &[u8]) -> Option<String> {
+    if !is_text_type(content_type) {
+        return None;
+    }
+    let content = &content[..content.len().min(MAX_INDEXED_BYTES)];
+    // Двоичные данные под текстовым расширением в индекс не попадают
+    if content.contains(&0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(content).into_owned())
+}
+
+// То же для файла на диске: читается только индексируемое начало
+pub fn extract_file_text(content_type: &str, path: &str) -> std::io::Result<Option<String>> {
+    if !is_text_type(content_type) {
+        return Ok(None);
+    }
+    let mut content = Vec::new();
+    fs::File::open(path)?.take(MAX_INDEXED_BYTES as u64).read_to_end(&mut content)?;
+    Ok(extract_text(content_type, &content))
+}
+
+// Запоминает текст содержимого; записи files с этим sha256 получают его триггерами.
+// Вызывается до сохранения записи, в той же транзакции
+pub fn index_text(conn: &Connection, sha256: &str, text: &str) -> Result<()> {
+    conn.prepare_cached("INSERT OR REPLACE INTO search_texts (sha256, body) VALUES (?1, ?2)")?
+        .execute(params![sha256, text])?;
+    Ok(())
+}
+
+// Заметка — единственный документ индекса без файла; новая заменяет прежнюю
+pub fn index_note(conn: &Connection, text: &str) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM search_documents WHERE file_id IS NULL", [])?;
+    tx.execute(
+        "INSERT INTO search_documents (file_id, owner, name, body) VALUES (NULL, NULL, ?1, ?2)",
+        params![NOTE_NAME, text],
+    )?;
+    tx.commit()
+}
+
+// Запрос FTS5 из слов пользователя: каждое слово ищется как начало слова, нужны все слова.
+// Слова берутся в кавычки, так что операторы FTS5 в запросе не действуют; None — слов нет
+pub fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .take(MAX_QUERY_TERMS)
+        .map(|term| format!("\"{}\"*", term))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+// Поиск по именам и тексту. owner — файлы этого пользователя, общие файлы и заметка;
+// None — все документы (для администратора). Совпадение в имени весит больше, чем в тексте
+pub fn search(conn: &Connection, query: &str, owner: Option<&str>, limit: usize) -> Result<Vec<SearchHit>> {
+    let fts = match fts_query(query) {
+        Some(fts) => fts,
+        None => return Ok(Vec::new()),
+    };
+    let mut stmt = conn.prepare_cached(
+        "SELECT d.file_id, d.owner,
+                highlight(search_index, 0, ?3, ?4),
+                snippet(search_index, 1, ?3, ?4, '…', 24),
+                bm25(search_index, 10.0, 1.0) AS score
+         FROM search_index JOIN search_documents d ON d.id = search_index.rowid
+         WHERE search_index MATCH ?1 AND (?2 IS NULL OR d.owner = ?2 OR d.owner IS NULL)
+         ORDER BY score
+         LIMIT ?5",
+    )?;
+    let hits = stmt.query_map(
+        params![fts, owner, MATCH_START.to_string(), MATCH_END.to_string(), limit as i64],
+        |row| {
+            Ok(SearchHit {
+                file_id: row.get(0)?,
+                owner: row.get(1)?,
+                name: row.get(2)?,
+                snippet: row.get(3)?,
+                // bm25 тем меньше, чем лучше совпадение
+                score: -row.get::<_, f64>(4)?,
+            })
+        },
+    )?;
+    hits.collect()
+}
+
+// search reindex: заново извлекает текст всех файлов и заметки и пересобирает документы индекса.
+// Возвращает число файлов, текст которых попал в индекс
+pub fn rebuild_search_index(conn: &Connection) -> std::result::Result<usize, FileError> {
+    let mut indexed = 0;
+    for file in list_file_records(conn)? {
+        // Файл, пропавший с диска, просто остается без текста
+        if let Ok(Some(text)) = extract_file_text(&file.content_type, &file.stored_path) {
+            index_text(conn, &file.sha256, &text)?;
+            indexed += 1;
+        }
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(
+        "DELETE FROM search_texts WHERE sha256 NOT IN (SELECT sha256 FROM files UNION SELECT sha256 FROM trash);
+         DELETE FROM search_documents WHERE file_id IS NOT NULL;
+         INSERT INTO search_documents (file_id, owner, name, body)
+             SELECT f.id, f.owner, f.path, COALESCE(t.body, '')
+             FROM files f LEFT JOIN search_texts t ON t.sha256 = f.sha256;",
+    )?;
+    tx.commit()?;
+    if let Ok(note) = fs::read(NOTE_FILE) {
+        index_note(conn, &String::from_utf8_lossy(&note))?;
+    }
+    Ok(indexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+    use crate::files::{store_user_file, TempStorage};
+
+    fn owners(hits: &[SearchHit]) -> Vec<Option<&str>> {
+        let mut owners: Vec<Option<&str>> = hits.iter().map(|hit| hit.owner.as_deref()).collect();
+        owners.sort();
+        owners
+    }
+
+    #[test]
+    fn query_terms_are_quoted() {
+        let cases = [
+            ("отчет", Some(r#""отчет"*"#)),
+            ("alpha beta", Some(r#""alpha"* "beta"*"#)),
+            // Операторы и синтаксис FTS5 становятся обычными словами
+            (r#"alpha" OR "beta"#, Some(r#""alpha"* "OR"* "beta"*"#)),
+            ("NOT alpha*", Some(r#""NOT"* "alpha"*"#)),
+            ("name:secret NEAR(a b)", Some(r#""name"* "secret"* "NEAR"* "a"* "b"*"#)),
+            ("^-+()\"*", None),
+            ("", None),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(fts_query(query).as_deref(), expected, "{:?}", query);
+        }
+        assert_eq!(fts_query(&"a ".repeat(20)).unwrap().matches('*').count(), MAX_QUERY_TERMS);
+    }
+
+    #[test]
+    fn hits_are_filtered_by_owner() {
+        let db = TempDb::new("search-owner");
+        let storage = TempStorage::new("search-owner");
+        let conn = Connection::open(&db.0).unwrap();
+        store_user_file(&conn, &storage.0, "bg", "plan.txt", "квартальный план alpha".as_bytes(), "text/plain").unwrap();
+        store_user_file(&conn, &storage.0, "bg2", "secret.txt", b"alpha beta", "text/plain").unwrap();
+        store_user_file(&conn, &storage.0, "bg2", "alpha.png", b"\x89PNG", "image/png").unwrap();
+
+        assert_eq!(owners(&search(&conn, "alpha", Some("bg"), 10).unwrap()), [Some("bg")]);
+        assert_eq!(owners(&search(&conn, "alpha", Some("bg2"), 10).unwrap()), [Some("bg2"), Some("bg2")]);
+        assert_eq!(search(&conn, "alpha", None, 10).unwrap().len(), 3);
+        assert!(search(&conn, "beta", Some("bg"), 10).unwrap().is_empty());
+        // Совпадение в имени весит больше, чем в тексте
+        let hits = search(&conn, "alpha", Some("bg2"), 10).unwrap();
+        assert_eq!(hits[0].name, format!("{}alpha{}.png", MATCH_START, MATCH_END));
+        // Кавычки и операторы во вводе не ломают запрос: OR ищется как слово
+        assert_eq!(search(&conn, r#"beta" "alpha"#, Some("bg2"), 10).unwrap().len(), 1);
+        assert!(search(&conn, r#"alpha" OR "beta"#, Some("bg2"), 10).unwrap().is_empty());
+        assert!(search(&conn, r#"""#, None, 10).unwrap().is_empty());
+        assert_eq!(search(&conn, "КВАРТАЛ", Some("bg"), 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn index_is_rebuilt_from_files() {
+        let db = TempDb::new("search-rebuild");
+        let storage = TempStorage::new("search-rebuild");
+        let conn = Connection::open(&db.0).unwrap();
+        store_user_file(&conn, &storage.0, "bg", "a.txt", b"gamma", "text/plain").unwrap();
+        store_user_file(&conn, &storage.0, "bg", "copy.txt", b"gamma", "text/plain").unwrap();
+        let lost = store_user_file(&conn, &storage.0, "bg", "lost.txt", b"delta", "text/plain").unwrap();
+        fs::remove_file(&lost.stored_path).unwrap();
+        conn.execute_batch("DELETE FROM search_texts; DELETE FROM search_documents;").unwrap();
+        assert!(search(&conn, "gamma", None, 10).unwrap().is_empty());
+
+        assert_eq!(rebuild_search_index(&conn).unwrap(), 2);
+        assert_eq!(search(&conn, "gamma", Some("bg"), 10).unwrap().len(), 2);
+        // Файл, пропавший с диска, находится только по имени
+        assert!(search(&conn, "delta", None, 10).unwrap().is_empty());
+        assert_eq!(search(&conn, "lost", None, 10).unwrap().len(), 1);
+    }
+}
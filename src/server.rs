@@ -1,15 +1,20 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
+use std::sync::Arc;
 use std::thread;
 
+use crate::context::Context;
 use crate::handlers::handle_connection;
 use crate::utils::log_to_file;
 
-pub fn start_server(listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+pub fn start_server(listener: TcpListener, ctx: Context) -> Result<(), Box<dyn std::error::Error>> {
+    // Контекст (счетчики попыток входа и т.д.) общий для всех потоков
+    let ctx = Arc::new(ctx);
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                let ctx = Arc::clone(&ctx);
                 thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream) {
+                    if let Err(e) = handle_connection(stream, &ctx) {
                         let error_msg = format!("Connection error: {}", e);
                         eprintln!("{}", error_msg);
                         let _ = log_to_file(&error_msg).map_err(|e| eprintln!("Log error: {}", e));
@@ -0,0 +1,72 @@
+use rusqlite::Connection;
+
+use crate::db::{activate_session, create_session, delete_other_sessions, delete_session, find_session};
+use crate::handlers::HttpError;
+use crate::utils::{get_cookie, get_timestamp, hash_password, random_token};
+
+const SESSION_COOKIE: &str = "session";
+// Время жизни сессии после полного входа
+const SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+// Сколько ждем ввода второго фактора после верного пароля
+const PENDING_TTL_SECS: u64 = 5 * 60;
+
+pub struct Session {
+    pub username: String,
+    pub pending_2fa: bool,
+    token_hash: String,
+}
+
+// Создает сессию и возвращает значение заголовка Set-Cookie.
+// В базе хранится только хеш токена, сам токен знает лишь браузер
+pub fn start_session(conn: &Connection, username: &str, pending_2fa: bool) -> Result<String, HttpError> {
+    let token = random_token(32)?;
+    let ttl = if pending_2fa { PENDING_TTL_SECS } else { SESSION_TTL_SECS };
+    create_session(conn, &hash_password(&token), username, pending_2fa, get_timestamp() + ttl)?;
+    Ok(format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE, token, ttl
+    ))
+}
+
+// Сессия из cookie запроса, включая ожидающие второго фактора
+pub fn current_session(conn: &Connection, request: &str) -> Result<Option<Session>, HttpError> {
+    let token = match get_cookie(request, SESSION_COOKIE) {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let token_hash = hash_password(token);
+    Ok(find_session(conn, &token_hash, get_timestamp())?.map(|(username, pending_2fa)| Session {
+        username,
+        pending_2fa,
+        token_hash,
+    }))
+}
+
+// Имя пользователя, если он полностью вошел в систему
+pub fn current_user(conn: &Connection, request: &str) -> Result<Option<String>, HttpError> {
+    Ok(current_session(conn, request)?
+        .filter(|session| !session.pending_2fa)
+        .map(|session| session.username))
+}
+
+// Второй фактор подтвержден — сессия становится полноценной
+pub fn complete_2fa(conn: &Connection, session: &Session) -> Result<(), HttpError> {
+    activate_session(conn, &session.token_hash, get_timestamp() + SESSION_TTL_SECS)?;
+    Ok(())
+}
+
+// Завершает остальные сессии пользователя (после смены пароля). Сессия, из которой пришел
+// запрос, остается, если принадлежит ему же
+pub fn end_other_sessions(conn: &Connection, request: &str, username: &str) -> Result<(), HttpError> {
+    let keep = current_session(conn, request)?.map(|session| session.token_hash).unwrap_or_default();
+    delete_other_sessions(conn, username, &keep)?;
+    Ok(())
+}
+
+// Удаляет сессию и возвращает Set-Cookie, стирающий cookie в браузере
+pub fn end_session(conn: &Connection, request: &str) -> Result<String, HttpError> {
+    if let Some(session) = current_session(conn, request)? {
+        delete_session(conn, &session.token_hash)?;
+    }
+    Ok(format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", SESSION_COOKIE))
+}
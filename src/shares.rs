@@ -0,0 +1,95 @@
+use rusqlite::{params, Connection, Result, Row};
+
+// Длина случайной части ссылки /s/<token> в байтах
+pub const SHARE_TOKEN_BYTES: usize = 24;
+
+// Публичная ссылка на файл. Сам токен не хранится — его видно только при создании
+pub struct Share {
+    pub id: i64,
+    pub file_id: i64,
+    pub owner: String,
+    pub password_hash: Option<String>,
+    pub expires_at: Option<u64>,
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+    pub created_at: u64,
+}
+
+impl Share {
+    // Ссылка еще работает: не истекла и лимит скачиваний не исчерпан
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|at| at > now) && self.max_downloads.is_none_or(|max| self.downloads < max)
+    }
+}
+
+const SHARE_COLUMNS: &str = "id, file_id, owner, password_hash, expires_at, max_downloads, downloads, created_at";
+
+fn share_from_row(row: &Row) -> Result<Share> {
+    Ok(Share {
+        id: row.get(0)?,
+        file_id: row.get(1)?,
+        owner: row.get(2)?,
+        password_hash: row.get(3)?,
+        expires_at: row.get::<_, Option<i64>>(4)?.map(|t| t as u64),
+        max_downloads: row.get::<_, Option<i64>>(5)?.map(|n| n as u64),
+        downloads: row.get::<_, i64>(6)? as u64,
+        created_at: row.get::<_, i64>(7)? as u64,
+    })
+}
+
+// Сохраняет новую ссылку (поля id и downloads не используются) и возвращает ее id
+pub fn create_share(conn: &Connection, share: &Share, token_hash: &str) -> Result<i64> {
+    conn.prepare_cached(
+        "INSERT INTO shares (file_id, owner, token_hash, password_hash, expires_at, max_downloads, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?
+    .execute(params![
+        share.file_id,
+        share.owner,
+        token_hash,
+        share.password_hash,
+        share.expires_at.map(|t| t as i64),
+        share.max_downloads.map(|n| n as i64),
+        share.created_at as i64
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Ссылка по хешу токена, в том числе истекшая: ее состояние проверяет вызывающий
+pub fn find_share(conn: &Connection, token_hash: &str) -> Result<Option<Share>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM shares WHERE token_hash = ?1", SHARE_COLUMNS))?;
+    let mut rows = stmt.query_map(params![token_hash], share_from_row)?;
+    rows.next().transpose()
+}
+
+// Все ссылки пользователя (для файлового менеджера)
+pub fn list_shares(conn: &Connection, owner: &str) -> Result<Vec<Share>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM shares WHERE owner = ?1 ORDER BY created_at DESC, id DESC",
+        SHARE_COLUMNS
+    ))?;
+    let shares = stmt.query_map(params![owner], share_from_row)?;
+    shares.collect()
+}
+
+// Засчитывает скачивание, если лимит еще не исчерпан; false — ссылка уже израсходована
+pub fn record_share_download(conn: &Connection, id: i64) -> Result<bool> {
+    let updated = conn.prepare_cached(
+        "UPDATE shares SET downloads = downloads + 1
+         WHERE id = ?1 AND (max_downloads IS NULL OR downloads < max_downloads)",
+    )?
+    .execute(params![id])?;
+    Ok(updated > 0)
+}
+
+// Отзыв ссылки; владелец проверяется, чтобы нельзя было отозвать чужую
+pub fn revoke_share(conn: &Connection, owner: &str, id: i64) -> Result<bool> {
+    let deleted = conn.prepare_cached("DELETE FROM shares WHERE id = ?1 AND owner = ?2")?.execute(params![id, owner])?;
+    Ok(deleted > 0)
+}
+
+// Ссылки удаляются вместе с файлом
+pub fn delete_file_shares(conn: &Connection, file_id: i64) -> Result<()> {
+    conn.prepare_cached("DELETE FROM shares WHERE file_id = ?1")?.execute(params![file_id])?;
+    Ok(())
+}
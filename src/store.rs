@@ -0,0 +1,481 @@
+#[cfg(test)]
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, ErrorCode, Row};
+
+use crate::pool::Pool;
+use crate::utils::{get_timestamp, hash_password};
+
+// Роли пользователей
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLES: &[&str] = &[ROLE_USER, ROLE_ADMIN];
+
+#[derive(Clone)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub password_hash: String,
+    pub role: String,
+    pub display_name: String,
+    pub email: String,
+    // Отключенный пользователь не может войти, но его данные сохраняются
+    pub disabled: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub last_login_at: Option<u64>,
+    pub last_login_ip: Option<String>,
+    // Индивидуальная квота на файлы; None — квота по умолчанию
+    pub quota_bytes: Option<u64>,
+    // Пароль задан до исправления разбора '+' в формах (см. миграцию 6)
+    pub legacy_form_password: bool,
+}
+
+impl User {
+    // Права администратора дает только сохраненная роль, а не имя учетной записи.
+    // Отключенный администратор теряет их вместе со входом
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN && !self.disabled
+    }
+
+    // Новый пароль хешируется из уже раскодированного значения, поэтому старая форма больше не нужна
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = hash_password(password);
+        self.legacy_form_password = false;
+    }
+}
+
+// Поле сортировки списка пользователей
+#[derive(Clone, Copy, PartialEq)]
+pub enum UserSort {
+    Id,
+    Username,
+    CreatedAt,
+    LastLogin,
+}
+
+impl UserSort {
+    pub const ALL: &'static [UserSort] = &[UserSort::Id, UserSort::Username, UserSort::CreatedAt, UserSort::LastLogin];
+
+    // Значение параметра ?sort= в админ-панели
+    pub fn param(self) -> &'static str {
+        match self {
+            UserSort::Id => "id",
+            UserSort::Username => "username",
+            UserSort::CreatedAt => "created",
+            UserSort::LastLogin => "login",
+        }
+    }
+
+    pub fn from_param(value: &str) -> Option<UserSort> {
+        UserSort::ALL.iter().copied().find(|sort| sort.param() == value)
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            UserSort::Id => "id",
+            UserSort::Username => "username",
+            UserSort::CreatedAt => "created_at",
+            // Никогда не входившие пользователи оказываются в начале по возрастанию
+            UserSort::LastLogin => "COALESCE(last_login_at, 0)",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    // Пользователь с таким именем уже есть
+    AlreadyExists,
+    NotFound,
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        match &err {
+            rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation => {
+                StoreError::AlreadyExists
+            }
+            _ => StoreError::Sqlite(err),
+        }
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+            StoreError::AlreadyExists => write!(f, "user already exists"),
+            StoreError::NotFound => write!(f, "user not found"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+// Хранилище учетных записей. Обработчики работают только через этот трейт,
+// поэтому SQLite можно заменить другой реализацией (например, MemoryUserStore).
+// Каждая реализация сама владеет тем, где хранит данные: трейт не знает о соединениях SQLite
+pub trait UserStore: Send + Sync {
+    fn create(&self, username: &str, password_hash: &str) -> Result<User, StoreError>;
+    fn find(&self, username: &str) -> Result<Option<User>, StoreError>;
+    // Занято ли имя; без учета регистра "Bob" и "bob" считаются одним именем
+    fn username_taken(&self, username: &str, case_insensitive: bool) -> Result<bool, StoreError>;
+    fn authenticate(&self, username: &str, password_hash: &str) -> Result<Option<User>, StoreError>;
+    // Сохраняет изменяемые поля пользователя с user.id и обновляет updated_at
+    fn update(&self, user: &User) -> Result<(), StoreError>;
+    // Запоминает время и адрес успешного входа
+    fn record_login(&self, id: i64, ip: &str) -> Result<(), StoreError>;
+    fn delete(&self, username: &str) -> Result<bool, StoreError>;
+    // Страница пользователей в заданном порядке; при равенстве — по id
+    fn list(&self, offset: usize, limit: usize, sort: UserSort, descending: bool) -> Result<Vec<User>, StoreError>;
+    fn count(&self) -> Result<usize, StoreError>;
+}
+
+// Учетные записи в SQLite. У хранилища свой пул соединений: обработчик, который уже держит
+// соединение из общего пула, не ждет второго из того же пула. Методы берут одно соединение
+// и не вызывают друг друга, пока его держат
+pub struct SqliteUserStore {
+    pool: Pool,
+}
+
+impl SqliteUserStore {
+    pub fn open(path: &str, max_size: usize) -> rusqlite::Result<Self> {
+        Ok(SqliteUserStore { pool: Pool::new(path, max_size)? })
+    }
+}
+
+const USER_COLUMNS: &str = "id, username, password_hash, role, display_name, email, disabled, \
+    created_at, updated_at, last_login_at, last_login_ip, quota_bytes, legacy_form_password";
+
+fn find_user(conn: &Connection, username: &str) -> Result<Option<User>, StoreError> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM users WHERE username = ?1", USER_COLUMNS))?;
+    let mut rows = stmt.query(params![username])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(user_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        password_hash: row.get(2)?,
+        role: row.get(3)?,
+        display_name: row.get(4)?,
+        email: row.get(5)?,
+        disabled: row.get(6)?,
+        created_at: row.get::<_, i64>(7)? as u64,
+        updated_at: row.get::<_, i64>(8)? as u64,
+        last_login_at: row.get::<_, Option<i64>>(9)?.map(|t| t as u64),
+        last_login_ip: row.get(10)?,
+        quota_bytes: row.get::<_, Option<i64>>(11)?.map(|q| q as u64),
+        legacy_form_password: row.get(12)?,
+    })
+}
+
+impl UserStore for SqliteUserStore {
+    fn create(&self, username: &str, password_hash: &str) -> Result<User, StoreError> {
+        let conn = self.pool.get()?;
+        let now = get_timestamp() as i64;
+        conn.prepare_cached(
+            "INSERT INTO users (username, password_hash, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+        )?
+        .execute(params![username, password_hash, now])?;
+        find_user(&conn, username)?.ok_or(StoreError::NotFound)
+    }
+
+    fn find(&self, username: &str) -> Result<Option<User>, StoreError> {
+        find_user(&*self.pool.get()?, username)
+    }
+
+    fn username_taken(&self, username: &str, case_insensitive: bool) -> Result<bool, StoreError> {
+        let sql = if case_insensitive {
+            "SELECT COUNT(*) FROM users WHERE username = ?1 COLLATE NOCASE"
+        } else {
+            "SELECT COUNT(*) FROM users WHERE username = ?1"
+        };
+        let conn = self.pool.get()?;
+        let count: i64 = conn.prepare_cached(sql)?.query_row(params![username], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    fn authenticate(&self, username: &str, password_hash: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.find(username)?.filter(|user| user.password_hash == password_hash))
+    }
+
+    fn update(&self, user: &User) -> Result<(), StoreError> {
+        let conn = self.pool.get()?;
+        let updated = conn
+            .prepare_cached(
+                "UPDATE users SET password_hash = ?2, role = ?3, display_name = ?4, email = ?5, disabled = ?6,
+                 quota_bytes = ?7, legacy_form_password = ?8, updated_at = ?9 WHERE id = ?1",
+            )?
+            .execute(params![
+                user.id,
+                user.password_hash,
+                user.role,
+                user.display_name,
+                user.email,
+                user.disabled,
+                user.quota_bytes.map(|q| q as i64),
+                user.legacy_form_password,
+                get_timestamp() as i64
+            ])?;
+        if updated == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn record_login(&self, id: i64, ip: &str) -> Result<(), StoreError> {
+        let conn = self.pool.get()?;
+        conn.prepare_cached("UPDATE users SET last_login_at = ?2, last_login_ip = ?3 WHERE id = ?1")?
+            .execute(params![id, get_timestamp() as i64, ip])?;
+        Ok(())
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, StoreError> {
+        let conn = self.pool.get()?;
+        let deleted = conn
+            .prepare_cached("DELETE FROM users WHERE username = ?1")?
+            .execute(params![username])?;
+        Ok(deleted > 0)
+    }
+
+    fn list(&self, offset: usize, limit: usize, sort: UserSort, descending: bool) -> Result<Vec<User>, StoreError> {
+        // Столбец и направление берутся из перечисления, а не из запроса, поэтому подстановка безопасна
+        let direction = if descending { "DESC" } else { "ASC" };
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM users ORDER BY {} {}, id {} LIMIT ?1 OFFSET ?2",
+            USER_COLUMNS,
+            sort.column(),
+            direction,
+            direction
+        ))?;
+        let users = stmt.query_map(params![limit as i64, offset as i64], user_from_row)?;
+        Ok(users.collect::<rusqlite::Result<Vec<User>>>()?)
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        let conn = self.pool.get()?;
+        let count: i64 = conn.prepare_cached("SELECT COUNT(*) FROM users")?.query_row([], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+// Хранилище в памяти для тестов: кода, работающего через UserStore, не нужен users.db на диске
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: Mutex<Vec<User>>,
+}
+
+#[cfg(test)]
+impl UserStore for MemoryUserStore {
+    fn create(&self, username: &str, password_hash: &str) -> Result<User, StoreError> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        if users.iter().any(|u| u.username == username) {
+            return Err(StoreError::AlreadyExists);
+        }
+        let now = get_timestamp();
+        let user = User {
+            id: users.last().map(|u| u.id + 1).unwrap_or(1),
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            role: ROLE_USER.to_string(),
+            display_name: String::new(),
+            email: String::new(),
+            disabled: false,
+            created_at: now,
+            updated_at: now,
+            last_login_at: None,
+            last_login_ip: None,
+            quota_bytes: None,
+            legacy_form_password: false,
+        };
+        users.push(user.clone());
+        Ok(user)
+    }
+
+    fn find(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(users.iter().find(|u| u.username == username).cloned())
+    }
+
+    fn username_taken(&self, username: &str, case_insensitive: bool) -> Result<bool, StoreError> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(users.iter().any(|u| {
+            if case_insensitive {
+                u.username.eq_ignore_ascii_case(username)
+            } else {
+                u.username == username
+            }
+        }))
+    }
+
+    fn authenticate(&self, username: &str, password_hash: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.find(username)?.filter(|user| user.password_hash == password_hash))
+    }
+
+    fn update(&self, user: &User) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let existing = users.iter_mut().find(|u| u.id == user.id).ok_or(StoreError::NotFound)?;
+        existing.password_hash = user.password_hash.clone();
+        existing.role = user.role.clone();
+        existing.display_name = user.display_name.clone();
+        existing.email = user.email.clone();
+        existing.disabled = user.disabled;
+        existing.quota_bytes = user.quota_bytes;
+        existing.legacy_form_password = user.legacy_form_password;
+        existing.updated_at = get_timestamp();
+        Ok(())
+    }
+
+    fn record_login(&self, id: i64, ip: &str) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let existing = users.iter_mut().find(|u| u.id == id).ok_or(StoreError::NotFound)?;
+        existing.last_login_at = Some(get_timestamp());
+        existing.last_login_ip = Some(ip.to_string());
+        Ok(())
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, StoreError> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let before = users.len();
+        users.retain(|u| u.username != username);
+        Ok(users.len() < before)
+    }
+
+    fn list(&self, offset: usize, limit: usize, sort: UserSort, descending: bool) -> Result<Vec<User>, StoreError> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        users.sort_by(|a, b| {
+            let order = match sort {
+                UserSort::Id => a.id.cmp(&b.id),
+                UserSort::Username => a.username.cmp(&b.username),
+                UserSort::CreatedAt => a.created_at.cmp(&b.created_at),
+                UserSort::LastLogin => a.last_login_at.unwrap_or(0).cmp(&b.last_login_at.unwrap_or(0)),
+            }
+            .then(a.id.cmp(&b.id));
+            if descending { order.reverse() } else { order }
+        });
+        Ok(users.into_iter().skip(offset).take(limit).collect())
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        Ok(self.users.lock().unwrap_or_else(|e| e.into_inner()).len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+
+    // Обе реализации проверяются одними и теми же тестами; пул SQLite из одного соединения
+    // заодно проверяет, что методы не берут второе, пока держат первое
+    fn stores(db: &TempDb) -> Vec<Box<dyn UserStore>> {
+        vec![Box::new(MemoryUserStore::default()), Box::new(SqliteUserStore::open(&db.0, 1).unwrap())]
+    }
+
+    #[test]
+    fn create_find_and_authenticate() {
+        let db = TempDb::new("store");
+        for store in stores(&db) {
+            let user = store.create("alice", "hash1").unwrap();
+            assert_eq!(user.username, "alice");
+            assert_eq!(user.role, ROLE_USER);
+            assert!(!user.disabled);
+            assert!(matches!(store.create("alice", "other"), Err(StoreError::AlreadyExists)));
+
+            assert_eq!(store.find("alice").unwrap().unwrap().id, user.id);
+            assert!(store.find("bob").unwrap().is_none());
+            assert!(store.authenticate("alice", "hash1").unwrap().is_some());
+            assert!(store.authenticate("alice", "wrong").unwrap().is_none());
+            assert!(store.authenticate("bob", "hash1").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn username_taken_respects_case_mode() {
+        let db = TempDb::new("store");
+        for store in stores(&db) {
+            store.create("Bob", "hash").unwrap();
+            assert!(store.username_taken("Bob", false).unwrap());
+            assert!(!store.username_taken("bob", false).unwrap());
+            assert!(store.username_taken("bob", true).unwrap());
+            assert!(!store.username_taken("carol", true).unwrap());
+        }
+    }
+
+    #[test]
+    fn update_and_record_login() {
+        let db = TempDb::new("store");
+        for store in stores(&db) {
+            let mut user = store.create("alice", "hash").unwrap();
+            user.password_hash = "new".to_string();
+            user.display_name = "Alice".to_string();
+            user.email = "alice@example.org".to_string();
+            user.disabled = true;
+            user.quota_bytes = Some(1024);
+            store.update(&user).unwrap();
+            store.record_login(user.id, "10.0.0.1").unwrap();
+
+            let stored = store.find("alice").unwrap().unwrap();
+            assert_eq!(stored.password_hash, "new");
+            assert_eq!(stored.display_name, "Alice");
+            assert_eq!(stored.email, "alice@example.org");
+            assert!(stored.disabled);
+            assert_eq!(stored.quota_bytes, Some(1024));
+            assert!(stored.last_login_at.is_some());
+            assert_eq!(stored.last_login_ip.as_deref(), Some("10.0.0.1"));
+
+            user.id += 100;
+            assert!(matches!(store.update(&user), Err(StoreError::NotFound)));
+        }
+    }
+
+    #[test]
+    fn list_pages_in_order_and_delete() {
+        let db = TempDb::new("store");
+        for store in stores(&db) {
+            for name in ["carol", "alice", "bob"] {
+                store.create(name, "hash").unwrap();
+            }
+            assert_eq!(store.count().unwrap(), 3);
+            let names = |users: Vec<User>| users.into_iter().map(|u| u.username).collect::<Vec<_>>();
+            assert_eq!(names(store.list(0, 10, UserSort::Id, false).unwrap()), ["carol", "alice", "bob"]);
+            assert_eq!(names(store.list(0, 2, UserSort::Username, false).unwrap()), ["alice", "bob"]);
+            assert_eq!(names(store.list(2, 2, UserSort::Username, false).unwrap()), ["carol"]);
+            assert_eq!(names(store.list(0, 1, UserSort::Username, true).unwrap()), ["carol"]);
+
+            assert!(store.delete("alice").unwrap());
+            assert!(!store.delete("alice").unwrap());
+            assert_eq!(store.count().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn admin_rights_come_from_role_not_name() {
+        let db = TempDb::new("store");
+        for store in stores(&db) {
+            let mut named_admin = store.create("admin", "hash").unwrap();
+            assert!(!named_admin.is_admin());
+
+            let mut operator = store.create("operator", "hash").unwrap();
+            operator.role = ROLE_ADMIN.to_string();
+            store.update(&operator).unwrap();
+            assert!(store.find("operator").unwrap().unwrap().is_admin());
+
+            operator.disabled = true;
+            store.update(&operator).unwrap();
+            assert!(!store.find("operator").unwrap().unwrap().is_admin());
+
+            named_admin.role = ROLE_USER.to_string();
+            store.update(&named_admin).unwrap();
+            assert!(!store.find("admin").unwrap().unwrap().is_admin());
+        }
+    }
+}
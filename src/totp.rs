@@ -0,0 +1,158 @@
+use hmac::{Hmac, Mac};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use sha1::Sha1;
+
+use crate::utils::random_bytes;
+
+// Параметры RFC 6238, которые понимают все приложения-аутентификаторы
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// Допустимое расхождение часов клиента и сервера (в шагах)
+const SKEW_STEPS: u64 = 1;
+const ISSUER: &str = "WebServer";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+pub fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Новый секрет: 20 случайных байт (160 бит, как рекомендует RFC 4226) в base32
+pub fn generate_secret() -> Result<String, std::io::Error> {
+    Ok(base32_encode(&random_bytes(20)?))
+}
+
+// HOTP-код (RFC 4226) для заданного счетчика
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC принимает ключ любой длины");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    binary % 10u32.pow(DIGITS)
+}
+
+// Проверяет код и возвращает номер шага, на котором он совпал.
+// Шаги не новее last_step отвергаются, чтобы один код нельзя было использовать дважды
+pub fn verify_code(secret: &str, code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32_decode(secret)?;
+    let current = now / STEP_SECS;
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .filter(|&step| step > last_step)
+        .find(|&step| hotp(&key, step) == code)
+}
+
+// URI для приложений-аутентификаторов (формат Key Uri Format от Google Authenticator)
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        user = urlencoding::encode(username),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECS
+    )
+}
+
+// QR-код с URI в виде встроенного SVG
+pub fn qr_svg(data: &str) -> Option<String> {
+    let code = QrCode::new(data.as_bytes()).ok()?;
+    Some(
+        code.render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ключ из RFC 6238 (приложение B) для SHA-1 и его запись в base32
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        // В RFC коды 8-значные; у нас 6 цифр — те же младшие разряды
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, expected) in vectors {
+            let code = expected % 1_000_000;
+            assert_eq!(hotp(RFC_KEY, time / STEP_SECS), code, "time {}", time);
+            assert_eq!(verify_code(RFC_SECRET, &format!("{:06}", code), time, 0), Some(time / STEP_SECS));
+        }
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(RFC_KEY), RFC_SECRET);
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw6ytboi======").as_deref(), Some(&b"foobar"[..]));
+        assert_eq!(base32_decode("MZXW 6YTB OI").as_deref(), Some(&b"foobar"[..]));
+        assert_eq!(base32_decode("MZXW1"), None);
+        for len in 0..=20 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(base32_decode(&base32_encode(&data)), Some(data));
+        }
+    }
+
+    #[test]
+    fn used_step_is_rejected() {
+        let now = 1111111111;
+        let step = now / STEP_SECS;
+        let code = format!("{:06}", hotp(RFC_KEY, step));
+        assert_eq!(verify_code(RFC_SECRET, &code, now, step - 1), Some(step));
+        assert_eq!(verify_code(RFC_SECRET, &code, now, step), None);
+        // Соседний шаг в пределах расхождения часов принимается, но не уже использованный
+        let previous = format!("{:06}", hotp(RFC_KEY, step - 1));
+        assert_eq!(verify_code(RFC_SECRET, &previous, now, step - 2), Some(step - 1));
+        assert_eq!(verify_code(RFC_SECRET, &previous, now, step - 1), None);
+        assert_eq!(verify_code(RFC_SECRET, "12345", now, 0), None);
+        assert_eq!(verify_code(RFC_SECRET, "abcdef", now, 0), None);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, Result, Row};
+
+use crate::files::{FileError, StorageConfig};
+use crate::utils::random_token;
+
+// Сколько живет незавершенная загрузка после последнего полученного куска
+pub const UPLOAD_TTL_SECS: u64 = 24 * 60 * 60;
+// Длина идентификатора загрузки в байтах
+pub const UPLOAD_ID_BYTES: usize = 16;
+// Каталог с принятыми частями внутри хранилища
+const UPLOAD_DIR: &str = ".uploads";
+
+// Загрузки, в которые прямо сейчас пишет какой-то запрос
+static ACTIVE: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+// Незавершенная загрузка (tus): сколько байт ожидается и сколько уже принято
+pub struct Upload {
+    pub id: String,
+    pub owner: String,
+    // Куда файл попадет в пространстве владельца после завершения
+    pub path: String,
+    pub content_type: String,
+    pub length: u64,
+    pub offset: u64,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Пока значение живо, другие запросы не могут писать в ту же загрузку
+pub struct UploadLock {
+    id: String,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(active) = active.as_mut() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+// None — в загрузку уже пишет другой запрос
+pub fn lock_upload(id: &str) -> Option<UploadLock> {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    if active.get_or_insert_with(HashSet::new).insert(id.to_string()) {
+        Some(UploadLock { id: id.to_string() })
+    } else {
+        None
+    }
+}
+
+// Файл с уже принятыми данными загрузки
+pub fn part_path(storage: &StorageConfig, id: &str) -> String {
+    format!("{}/{}/{}.part", storage.root, UPLOAD_DIR, id)
+}
+
+// Временный файл для тела запроса, которое целиком приходит одним запросом (PUT)
+pub fn temp_upload_path(storage: &StorageConfig) -> std::io::Result<String> {
+    fs::create_dir_all(format!("{}/{}", storage.root, UPLOAD_DIR))?;
+    Ok(format!("{}/{}/put-{}.tmp", storage.root, UPLOAD_DIR, random_token(UPLOAD_ID_BYTES)?))
+}
+
+const UPLOAD_COLUMNS: &str = "id, owner, path, content_type, length, offset, created_at, expires_at";
+
+fn upload_from_row(row: &Row) -> Result<Upload> {
+    Ok(Upload {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        path: row.get(2)?,
+        content_type: row.get(3)?,
+        length: row.get::<_, i64>(4)? as u64,
+        offset: row.get::<_, i64>(5)? as u64,
+        created_at: row.get::<_, i64>(6)? as u64,
+        expires_at: row.get::<_, i64>(7)? as u64,
+    })
+}
+
+// Регистрирует загрузку и создает пустой файл для ее данных
+pub fn create_upload(conn: &Connection, storage: &StorageConfig, upload: &Upload) -> std::result::Result<(), FileError> {
+    fs::create_dir_all(format!("{}/{}", storage.root, UPLOAD_DIR))?;
+    fs::File::create(part_path(storage, &upload.id))?;
+    conn.prepare_cached(
+        "INSERT INTO uploads (id, owner, path, content_type, length, offset, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        upload.id,
+        upload.owner,
+        upload.path,
+        upload.content_type,
+        upload.length as i64,
+        upload.offset as i64,
+        upload.created_at as i64,
+        upload.expires_at as i64
+    ])?;
+    Ok(())
+}
+
+pub fn find_upload(conn: &Connection, id: &str) -> Result<Option<Upload>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM uploads WHERE id = ?1", UPLOAD_COLUMNS))?;
+    let mut rows = stmt.query_map(params![id], upload_from_row)?;
+    rows.next().transpose()
+}
+
+// Сколько байт принято; каждый полученный кусок продлевает жизнь загрузки
+pub fn set_upload_offset(conn: &Connection, id: &str, offset: u64, expires_at: u64) -> Result<()> {
+    conn.prepare_cached("UPDATE uploads SET offset = ?2, expires_at = ?3 WHERE id = ?1")?
+        .execute(params![id, offset as i64, expires_at as i64])?;
+    Ok(())
+}
+
+// Удаляет загрузку вместе с принятыми данными (если они еще не перенесены в хранилище)
+pub fn delete_upload(conn: &Connection, storage: &StorageConfig, id: &str) -> std::result::Result<(), FileError> {
+    conn.prepare_cached("DELETE FROM uploads WHERE id = ?1")?.execute(params![id])?;
+    match fs::remove_file(part_path(storage, id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(FileError::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+fn delete_uploads_where(
+    conn: &Connection,
+    storage: &StorageConfig,
+    condition: &str,
+    value: &dyn rusqlite::ToSql,
+) -> std::result::Result<usize, FileError> {
+    let ids: Vec<String> = {
+        let mut stmt = conn.prepare_cached(&format!("SELECT id FROM uploads WHERE {}", condition))?;
+        let ids = stmt.query_map([value], |row| row.get(0))?;
+        ids.collect::<Result<_>>()?
+    };
+    for id in &ids {
+        delete_upload(conn, storage, id)?;
+    }
+    Ok(ids.len())
+}
+
+// Брошенные загрузки, срок которых истек к моменту now; возвращает их число
+pub fn purge_expired_uploads(conn: &Connection, storage: &StorageConfig, now: u64) -> std::result::Result<usize, FileError> {
+    delete_uploads_where(conn, storage, "expires_at <= ?1", &(now as i64))
+}
+
+// Незавершенные загрузки пользователя удаляются вместе с ним
+pub fn delete_user_uploads(conn: &Connection, storage: &StorageConfig, owner: &str) -> std::result::Result<usize, FileError> {
+    delete_uploads_where(conn, storage, "owner = ?1", &owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TempDb;
+    use crate::files::TempStorage;
+
+    fn upload(id: &str, owner: &str, expires_at: u64) -> Upload {
+        Upload {
+            id: id.to_string(),
+            owner: owner.to_string(),
+            path: format!("{}.bin", id),
+            content_type: "application/octet-stream".to_string(),
+            length: 10,
+            offset: 0,
+            created_at: 0,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let first = lock_upload("lock-test").unwrap();
+        assert!(lock_upload("lock-test").is_none());
+        // Другие загрузки не блокируются
+        assert!(lock_upload("lock-test-other").is_some());
+        drop(first);
+        assert!(lock_upload("lock-test").is_some());
+    }
+
+    #[test]
+    fn expired_uploads_are_purged() {
+        let db = TempDb::new("uploads");
+        let storage = TempStorage::new("uploads");
+        let conn = Connection::open(&db.0).unwrap();
+        create_upload(&conn, &storage.0, &upload("old", "bg", 100)).unwrap();
+        create_upload(&conn, &storage.0, &upload("fresh", "bg", 200)).unwrap();
+        create_upload(&conn, &storage.0, &upload("other", "bg2", 300)).unwrap();
+        fs::write(part_path(&storage.0, "old"), b"12345").unwrap();
+        set_upload_offset(&conn, "old", 5, 100).unwrap();
+        assert_eq!(find_upload(&conn, "old").unwrap().map(|u| u.offset), Some(5));
+
+        // Граница включительно: срок истек ровно в now
+        assert_eq!(purge_expired_uploads(&conn, &storage.0, 100).unwrap(), 1);
+        assert!(find_upload(&conn, "old").unwrap().is_none());
+        assert!(fs::metadata(part_path(&storage.0, "old")).is_err());
+        assert!(find_upload(&conn, "fresh").unwrap().is_some());
+        assert!(fs::metadata(part_path(&storage.0, "fresh")).is_ok());
+        assert_eq!(purge_expired_uploads(&conn, &storage.0, 100).unwrap(), 0);
+
+        assert_eq!(delete_user_uploads(&conn, &storage.0, "bg").unwrap(), 1);
+        assert!(find_upload(&conn, "fresh").unwrap().is_none());
+        assert!(find_upload(&conn, "other").unwrap().is_some());
+    }
+}
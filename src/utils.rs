@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use libc::{time_t, tm};
@@ -12,6 +12,10 @@ extern "C" {
 }
 
 pub fn log_to_file(message: &str) -> Result<(), std::io::Error> {
+    // Тесты не пишут в журнал сервера
+    if cfg!(test) {
+        return Ok(());
+    }
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
@@ -21,12 +25,20 @@ pub fn log_to_file(message: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-pub fn get_formatted_time() -> String {
-    let now = SystemTime::now()
+pub fn get_timestamp() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
-        .as_secs();
-    let t: time_t = now as time_t;
+        .as_secs()
+}
+
+pub fn get_formatted_time() -> String {
+    format_timestamp(get_timestamp())
+}
+
+// Форматирует UNIX-время в локальное "ГГГГ-ММ-ДД чч:мм:сс"
+pub fn format_timestamp(secs: u64) -> String {
+    let t: time_t = secs as time_t;
 
     unsafe {
         let tm_ptr: *mut tm = localtime(&t);
@@ -46,21 +58,234 @@ pub fn get_formatted_time() -> String {
     }
 }
 
+// Дата для HTTP-заголовков (RFC 7231): "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let t: time_t = secs as time_t;
+    let mut tm: tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::gmtime_r(&t, &mut tm) }.is_null() {
+        return String::new();
+    }
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[tm.tm_wday as usize % 7],
+        tm.tm_mday,
+        MONTHS[tm.tm_mon as usize % 12],
+        tm.tm_year + 1900,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// Дата в формате RFC 3339 (UTC): "1994-11-06T08:49:37Z"
+pub fn rfc3339_date(secs: u64) -> String {
+    let t: time_t = secs as time_t;
+    let mut tm: tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::gmtime_r(&t, &mut tm) }.is_null() {
+        return String::new();
+    }
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
 pub fn parse_form_data(body: &str) -> HashMap<String, String> {
     let mut data = HashMap::new();
     for pair in body.split('&') {
-        let mut split = pair.splitn(2, '=');
-        if let (Some(k), Some(v)) = (split.next(), split.next()) {
-            let key = decode(k).unwrap_or_default().to_string();
-            let value = decode(v).unwrap_or_default().to_string();
+        if let Some((k, v)) = pair.split_once('=') {
+            // В application/x-www-form-urlencoded пробел кодируется как '+'. Пароли, сохраненные
+            // до этого исправления, проверяет auth::check_password (миграция 6)
+            let key = decode(&k.replace('+', " ")).unwrap_or_default().to_string();
+            let value = decode(&v.replace('+', " ")).unwrap_or_default().to_string();
             data.insert(key, value);
         }
     }
     data
 }
 
+// Все значения повторяющегося поля формы (например, отмеченные флажки id=1&id=2)
+pub fn parse_form_list(body: &str, name: &str) -> Vec<String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(k, _)| decode(&k.replace('+', " ")).map(|k| k == name).unwrap_or(false))
+        .map(|(_, v)| decode(&v.replace('+', " ")).unwrap_or_default().to_string())
+        .collect()
+}
+
+// SHA-256 содержимого файла в виде hex-строки
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Размер в байтах в удобном для чтения виде: "512 Б", "1.5 МБ"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["Б", "КБ", "МБ", "ГБ", "ТБ"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// MIME-тип по расширению имени файла
+pub fn get_content_type(path: &str) -> &str {
+    match path.rsplit('.').next() {
+        Some("html") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
 pub fn hash_password(password: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(password.as_bytes());
     format!("{:x}", hasher.finalize())
-}
\ No newline at end of file
+}
+// Число итераций PBKDF2 для паролей, которые хранятся вне таблицы users
+const PBKDF2_ROUNDS: u32 = 100_000;
+const PBKDF2_PREFIX: &str = "pbkdf2-sha256";
+
+// Медленный хеш с солью: "pbkdf2-sha256$<итерации>$<соль hex>$<хеш hex>"
+pub fn hash_password_salted(password: &str) -> Result<String, std::io::Error> {
+    let salt = random_bytes(16)?;
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, PBKDF2_ROUNDS, &mut key);
+    Ok(format!("{}${}${}${}", PBKDF2_PREFIX, PBKDF2_ROUNDS, hex(&salt), hex(&key)))
+}
+
+// Проверка пароля по hash_password_salted; значения в другом формате не подходят ни к какому паролю
+pub fn verify_password_salted(password: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    let (rounds, salt, expected) = match parts.as_slice() {
+        [PBKDF2_PREFIX, rounds, salt, expected] => match (rounds.parse::<u32>(), from_hex(salt)) {
+            (Ok(rounds), Some(salt)) => (rounds, salt, *expected),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut key);
+    constant_time_eq(hex(&key).as_bytes(), expected.as_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Сравнение без раннего выхода: время не зависит от того, где строки расходятся
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Криптографически стойкие случайные байты из /dev/urandom
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut buf = vec![0u8; len];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Случайный токен в виде hex-строки (для сессий, ссылок и т.п.)
+pub fn random_token(len: usize) -> Result<String, std::io::Error> {
+    Ok(hex(&random_bytes(len)?))
+}
+
+// Значение заголовка запроса (имя без учета регистра)
+pub fn get_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()
+        .unwrap_or("")
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// Значение cookie из заголовка Cookie
+pub fn get_cookie<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    get_header(request, "Cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Экранирование пользовательских данных перед вставкой в HTML
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Экранирование строки для вставки в JSON
+pub fn json_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salted_hash_round_trip() {
+        let first = hash_password_salted("секрет 1").unwrap();
+        let second = hash_password_salted("секрет 1").unwrap();
+        assert!(first.starts_with("pbkdf2-sha256$"));
+        // Соль разная — одинаковые пароли дают разные хеши
+        assert_ne!(first, second);
+        assert!(verify_password_salted("секрет 1", &first));
+        assert!(!verify_password_salted("секрет 2", &first));
+        assert!(!verify_password_salted("секрет 1", "pbkdf2-sha256$1$zz$00"));
+    }
+
+    #[test]
+    fn unsalted_hashes_are_rejected() {
+        // Пароли ссылок всегда хешировались с солью, одиночный SHA-256 не принимается
+        let unsalted = hash_password("secret");
+        assert!(!verify_password_salted("secret", &unsalted));
+        assert!(!verify_password_salted("", ""));
+    }
+}
@@ -0,0 +1,654 @@
+use std::sync::{Mutex, MutexGuard};
+
+use crate::files::{base_name, normalize_dir, FileRecord};
+use crate::utils::{get_timestamp, html_escape, http_date, random_bytes, rfc3339_date};
+
+// Адрес корня WebDAV: /dav/ — корневая папка пространства пользователя
+pub const DAV_PREFIX: &str = "/dav";
+// Методы, которые понимает обработчик (заголовок Allow)
+pub const DAV_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+// Наибольший срок блокировки; клиенты продлевают ее повторным LOCK
+pub const DAV_LOCK_TIMEOUT_SECS: u64 = 60 * 60;
+const DAV_NS: &str = "DAV:";
+
+// Свойства, которые сервер вычисляет сам; других свойств (dead properties) не хранится
+const LIVE_PROPS: &[&str] = &[
+    "resourcetype",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getlastmodified",
+    "creationdate",
+    "getetag",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+// Действующие блокировки. Они живут только в памяти: после перезапуска клиенты берут их заново
+static LOCKS: Mutex<Vec<DavLock>> = Mutex::new(Vec::new());
+
+// Путь в пространстве пользователя из адреса /dav/<path> (корень — пустая строка)
+pub fn dav_path(route: &str) -> Result<String, String> {
+    let encoded = route.strip_prefix(DAV_PREFIX).unwrap_or(route);
+    let decoded = urlencoding::decode(encoded).map_err(|_| "invalid path encoding".to_string())?;
+    normalize_dir(&decoded)
+}
+
+// Адрес ресурса в ответах: компоненты пути кодируются, у папок в конце "/"
+pub fn dav_href(path: &str, folder: bool) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect();
+    let mut href = format!("{}/{}", DAV_PREFIX, segments.join("/"));
+    if folder && !path.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+// Путь из заголовка Destination (полный URL или абсолютный путь); None — адрес вне /dav/
+// или на другом сервере: хост полного URL должен совпадать с заголовком Host запроса
+pub fn destination_path(value: &str, host: Option<&str>) -> Option<Result<String, String>> {
+    let value = value.trim();
+    let path = match value.find("://") {
+        Some(i) => {
+            let rest = &value[i + 3..];
+            let split = rest.find('/').unwrap_or(rest.len());
+            if !host.is_some_and(|host| rest[..split].eq_ignore_ascii_case(host.trim())) {
+                return None;
+            }
+            &rest[split..]
+        }
+        None => value,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    if path != DAV_PREFIX && !path.starts_with(&format!("{}/", DAV_PREFIX)) {
+        return None;
+    }
+    Some(dav_path(path))
+}
+
+// Лежит ли path внутри папки dir (на любой глубине)
+fn is_inside(path: &str, dir: &str) -> bool {
+    if dir.is_empty() {
+        !path.is_empty()
+    } else {
+        path.len() > dir.len() + 1 && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+    }
+}
+
+// Начальный тег из тела запроса: пространство имен, локальное имя, глубина вложенности,
+// конец самого тега и начало закрывающего тега (для пустого элемента они совпадают)
+struct XmlElement {
+    ns: String,
+    name: String,
+    depth: usize,
+    end: usize,
+    close: usize,
+}
+
+impl XmlElement {
+    fn is(&self, name: &str) -> bool {
+        self.ns == DAV_NS && self.name == name
+    }
+}
+
+// Атрибуты name="value" начального тега (без имени самого тега)
+fn xml_attributes(mut rest: &str) -> Vec<(&str, &str)> {
+    let mut attributes = Vec::new();
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            break;
+        };
+        let Some(end) = value[1..].find(quote) else {
+            break;
+        };
+        attributes.push((name, &value[1..1 + end]));
+        rest = &value[end + 2..];
+    }
+    attributes
+}
+
+// Разбор XML-тел WebDAV без внешних зависимостей: нужны только элементы и их пространства имен,
+// поэтому текст, комментарии и инструкции обработки пропускаются
+fn xml_elements(body: &str) -> Result<Vec<XmlElement>, String> {
+    let mut elements: Vec<XmlElement> = Vec::new();
+    // Открытые элементы: индекс в elements и объявленные в них префиксы
+    let mut open: Vec<(usize, Vec<(String, String)>)> = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = body[pos..].find('<') {
+        let start = pos + found;
+        let tail = &body[start..];
+        let (terminator, skip) = if tail.starts_with("<?") {
+            ("?>", true)
+        } else if tail.starts_with("<!--") {
+            ("-->", true)
+        } else if tail.starts_with("<![CDATA[") {
+            ("]]>", true)
+        } else if tail.starts_with("<!") {
+            (">", true)
+        } else {
+            (">", false)
+        };
+        let close = tail.find(terminator).ok_or("unterminated XML tag")?;
+        pos = start + close + terminator.len();
+        if skip {
+            continue;
+        }
+
+        let tag = &tail[1..close];
+        if tag.starts_with('/') {
+            let (index, _) = open.pop().ok_or("unbalanced XML end tag")?;
+            elements[index].close = start;
+            continue;
+        }
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let qname = tag.split_whitespace().next().ok_or("empty XML tag")?;
+        let declared: Vec<(String, String)> = xml_attributes(&tag[qname.len()..])
+            .into_iter()
+            .filter_map(|(name, value)| match name {
+                "xmlns" => Some((String::new(), value.to_string())),
+                _ => name.strip_prefix("xmlns:").map(|prefix| (prefix.to_string(), value.to_string())),
+            })
+            .collect();
+        let (prefix, name) = qname.split_once(':').unwrap_or(("", qname));
+        let ns = declared
+            .iter()
+            .rev()
+            .chain(open.iter().rev().flat_map(|(_, declared)| declared.iter().rev()))
+            .find(|(declared_prefix, _)| declared_prefix == prefix)
+            .map(|(_, uri)| uri.clone())
+            .unwrap_or_default();
+        elements.push(XmlElement { ns, name: name.to_string(), depth: open.len(), end: pos, close: pos });
+        if !empty {
+            open.push((elements.len() - 1, declared));
+        }
+    }
+    if !open.is_empty() {
+        return Err("unclosed XML element".to_string());
+    }
+    Ok(elements)
+}
+
+// Непосредственные потомки элемента elements[index]
+fn xml_children(elements: &[XmlElement], index: usize) -> impl Iterator<Item = &XmlElement> {
+    let depth = elements[index].depth;
+    elements[index + 1..]
+        .iter()
+        .take_while(move |element| element.depth > depth)
+        .filter(move |element| element.depth == depth + 1)
+}
+
+fn xml_root(body: &str, name: &str) -> Result<Vec<XmlElement>, String> {
+    let elements = xml_elements(body)?;
+    if !elements.first().is_some_and(|root| root.is(name)) {
+        return Err(format!("expected DAV:{} element", name));
+    }
+    Ok(elements)
+}
+
+// Какие свойства запрошены в PROPFIND: все, только имена или перечисленные (пространство имен, имя)
+pub enum PropRequest {
+    All,
+    Names,
+    Props(Vec<(String, String)>),
+}
+
+// Пустое тело PROPFIND означает allprop
+pub fn parse_propfind(body: &str) -> Result<PropRequest, String> {
+    if body.trim().is_empty() {
+        return Ok(PropRequest::All);
+    }
+    let elements = xml_root(body, "propfind")?;
+    if elements.iter().any(|element| element.is("propname")) {
+        return Ok(PropRequest::Names);
+    }
+    if let Some(index) = elements.iter().position(|element| element.depth == 1 && element.is("prop")) {
+        let names = xml_children(&elements, index)
+            .map(|element| (element.ns.clone(), element.name.clone()))
+            .collect();
+        return Ok(PropRequest::Props(names));
+    }
+    Ok(PropRequest::All)
+}
+
+// Имена свойств, которые PROPPATCH просит установить или удалить
+pub fn parse_proppatch(body: &str) -> Result<Vec<(String, String)>, String> {
+    let elements = xml_root(body, "propertyupdate")?;
+    let mut names = Vec::new();
+    for (index, _) in elements.iter().enumerate().filter(|(_, element)| element.depth == 2 && element.is("prop")) {
+        names.extend(xml_children(&elements, index).map(|element| (element.ns.clone(), element.name.clone())));
+    }
+    Ok(names)
+}
+
+// Тело LOCK: вид блокировки и описание того, кто ее берет
+pub struct LockRequest {
+    pub shared: bool,
+    pub holder: String,
+}
+
+pub fn parse_lockinfo(body: &str) -> Result<LockRequest, String> {
+    let elements = xml_root(body, "lockinfo")?;
+    let shared = elements.iter().any(|element| element.is("shared"));
+    let holder = match elements.iter().position(|element| element.is("owner")) {
+        Some(index) => {
+            let owner = &elements[index];
+            // Содержимое <owner> возвращается клиенту как есть, но без чужих префиксов пространств имен
+            let text = xml_text(&body[owner.end..owner.close]);
+            if xml_children(&elements, index).any(|element| element.is("href")) {
+                format!("<D:owner><D:href>{}</D:href></D:owner>", text)
+            } else {
+                format!("<D:owner>{}</D:owner>", text)
+            }
+        }
+        None => String::new(),
+    };
+    Ok(LockRequest { shared, holder })
+}
+
+// Текст фрагмента XML без тегов (сущности остаются экранированными)
+fn xml_text(fragment: &str) -> String {
+    let mut text = String::new();
+    let mut rest = fragment;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = rest[start..].find('>').map(|end| &rest[start + end + 1..]).unwrap_or("");
+    }
+    text.push_str(rest);
+    text.trim().to_string()
+}
+
+// Ресурс в ответе PROPFIND: файл или папка пространства пользователя
+pub struct DavResource {
+    pub path: String,
+    pub folder: bool,
+    pub size: u64,
+    pub content_type: String,
+    pub modified: u64,
+    // Пустой у папок
+    pub etag: String,
+}
+
+impl DavResource {
+    pub fn file(file: &FileRecord) -> DavResource {
+        DavResource {
+            path: file.path.clone(),
+            folder: false,
+            size: file.size,
+            content_type: file.content_type.clone(),
+            modified: file.uploaded_at,
+            etag: file_etag(file),
+        }
+    }
+
+    pub fn folder(path: &str, created_at: u64) -> DavResource {
+        DavResource {
+            path: path.to_string(),
+            folder: true,
+            size: 0,
+            content_type: String::new(),
+            modified: created_at,
+            etag: String::new(),
+        }
+    }
+
+    // Значение свойства DAV:name; None — у такого ресурса этого свойства нет
+    fn prop(&self, name: &str, locks: &[DavLock]) -> Option<String> {
+        let now = get_timestamp();
+        match name {
+            "resourcetype" => Some(if self.folder { "<D:collection/>".to_string() } else { String::new() }),
+            "displayname" if !self.path.is_empty() => Some(html_escape(base_name(&self.path))),
+            "getcontentlength" if !self.folder => Some(self.size.to_string()),
+            "getcontenttype" if !self.folder => Some(html_escape(&self.content_type)),
+            "getlastmodified" => Some(http_date(self.modified)),
+            "creationdate" => Some(rfc3339_date(self.modified)),
+            "getetag" if !self.folder => Some(html_escape(&self.etag)),
+            "supportedlock" => Some(
+                "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+                 <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>"
+                    .to_string(),
+            ),
+            "lockdiscovery" => Some(locks.iter().map(|lock| active_lock(lock, now)).collect()),
+            _ => None,
+        }
+    }
+
+    // Элемент <D:response> для multistatus; неизвестные свойства попадают в propstat с 404
+    pub fn prop_response(&self, request: &PropRequest, locks: &[DavLock]) -> String {
+        let mut found = String::new();
+        let mut missing = String::new();
+        match request {
+            PropRequest::All => {
+                for name in LIVE_PROPS {
+                    if let Some(value) = self.prop(name, locks) {
+                        found.push_str(&format!("<D:{0}>{1}</D:{0}>", name, value));
+                    }
+                }
+            }
+            PropRequest::Names => {
+                for name in LIVE_PROPS.iter().filter(|name| self.prop(name, locks).is_some()) {
+                    found.push_str(&format!("<D:{}/>", name));
+                }
+            }
+            PropRequest::Props(names) => {
+                for (ns, name) in names {
+                    match self.prop(name, locks).filter(|_| ns == DAV_NS) {
+                        Some(value) => found.push_str(&format!("<D:{0}>{1}</D:{0}>", name, value)),
+                        None => missing.push_str(&prop_name(ns, name)),
+                    }
+                }
+            }
+        }
+        let mut propstats = String::new();
+        if !found.is_empty() || missing.is_empty() {
+            propstats.push_str(&propstat(&found, "200 OK"));
+        }
+        if !missing.is_empty() {
+            propstats.push_str(&propstat(&missing, "404 Not Found"));
+        }
+        dav_response(&dav_href(&self.path, self.folder), &propstats)
+    }
+}
+
+// Сильный ETag файла: содержимое и время записи
+pub fn file_etag(file: &FileRecord) -> String {
+    format!("\"{}-{}\"", &file.sha256[..file.sha256.len().min(16)], file.uploaded_at)
+}
+
+// Пустой элемент свойства с его пространством имен
+pub fn prop_name(ns: &str, name: &str) -> String {
+    if ns == DAV_NS {
+        format!("<D:{}/>", name)
+    } else {
+        format!("<{} xmlns=\"{}\"/>", name, html_escape(ns))
+    }
+}
+
+pub fn propstat(props: &str, status: &str) -> String {
+    format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>", props, status)
+}
+
+pub fn dav_response(href: &str, propstats: &str) -> String {
+    format!("<D:response><D:href>{}</D:href>{}</D:response>", html_escape(href), propstats)
+}
+
+pub fn multistatus(responses: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses
+    )
+}
+
+// Блокировка записи (RFC 4918, раздел 6)
+#[derive(Clone)]
+pub struct DavLock {
+    pub token: String,
+    // Чье пространство и какой ресурс в нем заблокирован
+    pub owner: String,
+    pub path: String,
+    // Depth: infinity — блокировка распространяется на все содержимое папки
+    pub deep: bool,
+    pub shared: bool,
+    // Кто взял блокировку: элемент <D:owner> из запроса, возвращается в lockdiscovery
+    pub holder: String,
+    pub expires_at: u64,
+}
+
+impl DavLock {
+    // Действует ли блокировка на ресурс path
+    fn covers(&self, owner: &str, path: &str) -> bool {
+        self.owner == owner && (self.path == path || (self.deep && is_inside(path, &self.path)))
+    }
+}
+
+// Описание блокировки для lockdiscovery
+fn active_lock(lock: &DavLock, now: u64) -> String {
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+         <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.shared { "shared" } else { "exclusive" },
+        if lock.deep { "infinity" } else { "0" },
+        lock.holder,
+        lock.expires_at.saturating_sub(now),
+        lock.token,
+        html_escape(&dav_href(&lock.path, false))
+    )
+}
+
+// Тело ответа на LOCK
+pub fn lock_response(lock: &DavLock) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        active_lock(lock, get_timestamp())
+    )
+}
+
+// Новый токен блокировки в виде opaquelocktoken:<UUID>
+pub fn new_lock_token() -> std::io::Result<String> {
+    let bytes = random_bytes(16)?;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!(
+        "opaquelocktoken:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+// Срок блокировки из заголовка Timeout ("Second-600", "Infinite"), не больше DAV_LOCK_TIMEOUT_SECS
+pub fn lock_timeout(header: Option<&str>) -> u64 {
+    header
+        .and_then(|value| value.split(',').map(str::trim).find_map(|item| item.strip_prefix("Second-")))
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map(|secs| secs.clamp(1, DAV_LOCK_TIMEOUT_SECS))
+        .unwrap_or(DAV_LOCK_TIMEOUT_SECS)
+}
+
+// Токены блокировок, которые клиент предъявил в заголовке If (или Lock-Token)
+pub fn submitted_tokens(header: Option<&str>) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut rest = header.unwrap_or("");
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let token = &rest[start + 1..start + end];
+        if token.starts_with("opaquelocktoken:") {
+            tokens.push(token.to_string());
+        }
+        rest = &rest[start + end + 1..];
+    }
+    tokens
+}
+
+// Таблица блокировок без истекших
+fn active_locks() -> MutexGuard<'static, Vec<DavLock>> {
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    let now = get_timestamp();
+    locks.retain(|lock| lock.expires_at > now);
+    locks
+}
+
+// Блокировки, действующие на ресурс (для lockdiscovery)
+pub fn locks_on(owner: &str, path: &str) -> Vec<DavLock> {
+    active_locks().iter().filter(|lock| lock.covers(owner, path)).cloned().collect()
+}
+
+// Ставит блокировку, если она не конфликтует с действующими: исключительная несовместима ни с какой другой
+pub fn acquire_lock(lock: DavLock) -> Option<DavLock> {
+    let mut locks = active_locks();
+    let conflict = locks.iter().any(|other| {
+        (other.covers(&lock.owner, &lock.path) || lock.covers(&other.owner, &other.path)) && !(other.shared && lock.shared)
+    });
+    if conflict {
+        return None;
+    }
+    locks.push(lock.clone());
+    Some(lock)
+}
+
+// Продление блокировки, токен которой предъявлен и которая действует на path
+pub fn refresh_lock(owner: &str, path: &str, tokens: &[String], timeout: u64) -> Option<DavLock> {
+    let mut locks = active_locks();
+    let lock = locks.iter_mut().find(|lock| lock.covers(owner, path) && tokens.contains(&lock.token))?;
+    lock.expires_at = get_timestamp() + timeout;
+    Some(lock.clone())
+}
+
+// Снимает блокировку с токеном token, если она действует на path
+pub fn release_lock(owner: &str, path: &str, token: &str) -> bool {
+    let mut locks = active_locks();
+    let before = locks.len();
+    locks.retain(|lock| !(lock.token == token && lock.covers(owner, path)));
+    locks.len() != before
+}
+
+// Можно ли менять path (при deep — и все внутри него): на каждую исключительную блокировку
+// нужен ее токен, а из общих блокировок достаточно одной
+pub fn may_modify(owner: &str, path: &str, deep: bool, tokens: &[String]) -> bool {
+    let locks = active_locks();
+    let relevant: Vec<&DavLock> = locks
+        .iter()
+        .filter(|lock| lock.covers(owner, path) || (deep && lock.owner == owner && is_inside(&lock.path, path)))
+        .collect();
+    let submitted = |lock: &&DavLock| tokens.contains(&lock.token);
+    relevant.iter().filter(|lock| !lock.shared).all(submitted)
+        && (relevant.iter().all(|lock| !lock.shared) || relevant.iter().filter(|lock| lock.shared).any(submitted))
+}
+
+// Удаленный или перенесенный ресурс теряет свои блокировки
+pub fn drop_locks(owner: &str, path: &str) {
+    active_locks().retain(|lock| !(lock.owner == owner && (lock.path == path || is_inside(&lock.path, path))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Блокировки общие для процесса, поэтому у каждого теста свой владелец пространства
+    fn lock(owner: &str, path: &str, deep: bool, shared: bool) -> DavLock {
+        DavLock {
+            token: new_lock_token().unwrap(),
+            owner: owner.to_string(),
+            path: path.to_string(),
+            deep,
+            shared,
+            holder: String::new(),
+            expires_at: get_timestamp() + 60,
+        }
+    }
+
+    #[test]
+    fn propfind_body_variants() {
+        assert!(matches!(parse_propfind("").unwrap(), PropRequest::All));
+        let allprop = r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#;
+        assert!(matches!(parse_propfind(allprop).unwrap(), PropRequest::All));
+        let names = r#"<propfind xmlns="DAV:"><propname/></propfind>"#;
+        assert!(matches!(parse_propfind(names).unwrap(), PropRequest::Names));
+        let props = r#"<D:propfind xmlns:D="DAV:" xmlns:Z="urn:z"><D:prop><D:getetag/><Z:color/></D:prop></D:propfind>"#;
+        match parse_propfind(props).unwrap() {
+            PropRequest::Props(names) => assert_eq!(
+                names,
+                vec![("DAV:".to_string(), "getetag".to_string()), ("urn:z".to_string(), "color".to_string())]
+            ),
+            _ => panic!("expected a list of properties"),
+        }
+        assert!(parse_propfind(r#"<D:lockinfo xmlns:D="DAV:"/>"#).is_err());
+    }
+
+    #[test]
+    fn lockinfo_scope_and_owner() {
+        let body = r#"<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype><D:owner><D:href>mailto:bg@example.com</D:href></D:owner></D:lockinfo>"#;
+        let request = parse_lockinfo(body).unwrap();
+        assert!(!request.shared);
+        assert_eq!(request.holder, "<D:owner><D:href>mailto:bg@example.com</D:href></D:owner>");
+        let shared = r#"<lockinfo xmlns="DAV:"><lockscope><shared/></lockscope><locktype><write/></locktype></lockinfo>"#;
+        let request = parse_lockinfo(shared).unwrap();
+        assert!(request.shared);
+        assert!(request.holder.is_empty());
+    }
+
+    #[test]
+    fn if_header_tokens() {
+        let header = "(<opaquelocktoken:1111-2222> [\"etag\"]) </dav/a.txt> (Not <DAV:no-lock>) (<opaquelocktoken:3333>)";
+        assert_eq!(submitted_tokens(Some(header)), vec!["opaquelocktoken:1111-2222", "opaquelocktoken:3333"]);
+        assert_eq!(submitted_tokens(Some("<opaquelocktoken:4444>")), vec!["opaquelocktoken:4444"]);
+        assert!(submitted_tokens(Some("(<opaquelocktoken:broken")).is_empty());
+        assert!(submitted_tokens(None).is_empty());
+    }
+
+    #[test]
+    fn lock_timeout_is_clamped() {
+        assert_eq!(lock_timeout(Some("Second-600")), 600);
+        assert_eq!(lock_timeout(Some("Infinite, Second-30")), 30);
+        assert_eq!(lock_timeout(Some("Second-99999999")), DAV_LOCK_TIMEOUT_SECS);
+        assert_eq!(lock_timeout(Some("Infinite")), DAV_LOCK_TIMEOUT_SECS);
+        assert_eq!(lock_timeout(None), DAV_LOCK_TIMEOUT_SECS);
+    }
+
+    #[test]
+    fn exclusive_lock_lifecycle() {
+        let owner = "test-exclusive";
+        let held = acquire_lock(lock(owner, "docs", true, false)).unwrap();
+        let tokens = vec![held.token.clone()];
+        // Вторая блокировка на ту же папку или ресурс внутри нее не ставится
+        assert!(acquire_lock(lock(owner, "docs/a.txt", false, false)).is_none());
+        assert!(acquire_lock(lock(owner, "docs", false, true)).is_none());
+        // Менять содержимое можно только с токеном
+        assert!(!may_modify(owner, "docs/a.txt", false, &[]));
+        assert!(may_modify(owner, "docs/a.txt", false, &tokens));
+        assert!(may_modify(owner, "other.txt", false, &[]));
+        assert!(!may_modify(owner, "", true, &[]));
+        assert_eq!(locks_on(owner, "docs/a.txt").len(), 1);
+        // Блокировки другого пользователя на тот же путь не действуют
+        assert!(may_modify("test-exclusive-other", "docs/a.txt", false, &[]));
+
+        let refreshed = refresh_lock(owner, "docs/a.txt", &tokens, 600).unwrap();
+        assert_eq!(refreshed.token, held.token);
+        assert!(refresh_lock(owner, "docs", &["opaquelocktoken:wrong".to_string()], 600).is_none());
+
+        assert!(!release_lock(owner, "other.txt", &held.token));
+        assert!(release_lock(owner, "docs", &held.token));
+        assert!(!release_lock(owner, "docs", &held.token));
+        assert!(may_modify(owner, "docs/a.txt", false, &[]));
+    }
+
+    #[test]
+    fn shared_locks_need_one_token() {
+        let owner = "test-shared";
+        let first = acquire_lock(lock(owner, "a.txt", false, true)).unwrap();
+        let second = acquire_lock(lock(owner, "a.txt", false, true)).unwrap();
+        assert!(acquire_lock(lock(owner, "a.txt", false, false)).is_none());
+        assert!(!may_modify(owner, "a.txt", false, &[]));
+        assert!(may_modify(owner, "a.txt", false, std::slice::from_ref(&second.token)));
+        drop_locks(owner, "a.txt");
+        assert!(locks_on(owner, "a.txt").is_empty());
+        assert!(!release_lock(owner, "a.txt", &first.token));
+    }
+
+    #[test]
+    fn destination_must_be_on_this_server() {
+        let host = Some("files.example.com:8080");
+        assert_eq!(destination_path("/dav/a/b.txt", host), Some(Ok("a/b.txt".to_string())));
+        assert_eq!(destination_path("/dav/%D0%B0.txt", None), Some(Ok("а.txt".to_string())));
+        assert_eq!(
+            destination_path("http://FILES.example.com:8080/dav/new%20name.txt?x=1", host),
+            Some(Ok("new name.txt".to_string()))
+        );
+        assert_eq!(destination_path("http://evil.example.com/dav/a.txt", host), None);
+        assert_eq!(destination_path("http://files.example.com/dav/a.txt", host), None);
+        assert_eq!(destination_path("http://files.example.com:8080/dav/a.txt", None), None);
+        assert_eq!(destination_path("/other/a.txt", host), None);
+        assert_eq!(destination_path("/dav", host), Some(Ok(String::new())));
+        assert!(matches!(destination_path("/dav/../x", host), Some(Err(_))));
+    }
+}